@@ -0,0 +1,496 @@
+use std::{cmp, io};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// A disk made up of fixed size blocks, what the disk writers write to.
+///
+/// Accesses are whole blocks. Byte level users like filesystems go through [`BlockIo`].
+pub trait BlockDevice {
+	fn block_size(&self) -> u32;
+	
+	fn block_count(&self) -> u64;
+	
+	/// Reads the blocks starting at `lba` into `buf`, a whole number of blocks long.
+	fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()>;
+	
+	/// Writes `buf`, a whole number of blocks long, to the blocks starting at `lba`.
+	fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> io::Result<()>;
+	
+	fn flush(&mut self) -> io::Result<()>;
+	
+	fn size_bytes(&self) -> u64 {
+		self.block_count() * self.block_size() as u64
+	}
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &mut D {
+	fn block_size(&self) -> u32 {
+		(**self).block_size()
+	}
+	
+	fn block_count(&self) -> u64 {
+		(**self).block_count()
+	}
+	
+	fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+		(**self).read_blocks(lba, buf)
+	}
+	
+	fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> io::Result<()> {
+		(**self).write_blocks(lba, buf)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		(**self).flush()
+	}
+}
+
+/// Checks an access of `len` bytes at `lba` stays within `block_count` whole blocks.
+fn check_access(block_size: u32, block_count: u64, lba: u64, len: usize) -> io::Result<()> {
+	if !len.is_multiple_of(block_size as usize) {
+		let msg = format!("access of {} bytes isn't a whole number of {} byte blocks", len, block_size);
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+	}
+	
+	let blocks = (len / block_size as usize) as u64;
+	if lba.checked_add(blocks).is_none_or(|end| end > block_count) {
+		let msg = format!("access of {} blocks at lba {} is beyond the end of the device ({} blocks)", blocks, lba, block_count);
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+	}
+	Ok(())
+}
+
+/// Reads until the buffer is full or the end of the stream, returns the number of bytes read.
+pub fn read_up_to(reader: &mut (impl Read + ?Sized), buf: &mut [u8]) -> io::Result<usize> {
+	let mut read = 0;
+	while read < buf.len() {
+		match reader.read(&mut buf[read..]) {
+			Ok(0) => break,
+			Ok(n) => read += n,
+			Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(read)
+}
+
+/// A stream as a block device, e.g. an image [`File`], a [`MemDisk`](crate::memdisk::MemDisk)
+/// or a [`SparseFile`](crate::sparse::SparseFile).
+pub struct IoDevice<T> {
+	inner: T,
+	block_size: u32,
+	block_count: u64,
+}
+
+impl<T: Read + Write + Seek> IoDevice<T> {
+	/// Uses the whole stream, a partial block at the end is left out.
+	pub fn new(mut inner: T, block_size: u32) -> io::Result<Self> {
+		let len = inner.seek(SeekFrom::End(0))?;
+		
+		Ok(IoDevice {
+			inner,
+			block_size,
+			block_count: len / block_size as u64,
+		})
+	}
+	
+	pub fn get_ref(&self) -> &T {
+		&self.inner
+	}
+	
+	pub fn get_mut(&mut self) -> &mut T {
+		&mut self.inner
+	}
+	
+	pub fn into_inner(self) -> T {
+		self.inner
+	}
+}
+
+impl IoDevice<File> {
+	/// Sets the length of the file to `block_count` blocks first, new space reads as zero.
+	pub fn with_block_count(file: File, block_size: u32, block_count: u64) -> io::Result<Self> {
+		file.set_len(block_count * block_size as u64)?;
+		Self::new(file, block_size)
+	}
+}
+
+impl<T: Read + Write + Seek> BlockDevice for IoDevice<T> {
+	fn block_size(&self) -> u32 {
+		self.block_size
+	}
+	
+	fn block_count(&self) -> u64 {
+		self.block_count
+	}
+	
+	fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+		check_access(self.block_size, self.block_count, lba, buf.len())?;
+		
+		self.inner.seek(SeekFrom::Start(lba * self.block_size as u64))?;
+		self.inner.read_exact(buf)
+	}
+	
+	fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> io::Result<()> {
+		check_access(self.block_size, self.block_count, lba, buf.len())?;
+		
+		self.inner.seek(SeekFrom::Start(lba * self.block_size as u64))?;
+		self.inner.write_all(buf)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// The blocks of one partition, borrowed from the device of the whole disk.
+pub struct PartitionDevice<'a, D: ?Sized> {
+	disk: &'a mut D,
+	start_lba: u64,
+	size_in_lba: u64,
+}
+
+impl<'a, D: BlockDevice + ?Sized> PartitionDevice<'a, D> {
+	pub fn new(disk: &'a mut D, start_lba: u64, size_in_lba: u64) -> io::Result<Self> {
+		if start_lba.checked_add(size_in_lba).is_none_or(|end| end > disk.block_count()) {
+			let msg = format!("partition at lba {} with {} blocks is beyond the end of the disk ({} blocks)", start_lba, size_in_lba, disk.block_count());
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+		}
+		
+		Ok(PartitionDevice {
+			disk,
+			start_lba,
+			size_in_lba,
+		})
+	}
+	
+	pub fn start_lba(&self) -> u64 {
+		self.start_lba
+	}
+}
+
+impl<'a, D: BlockDevice + ?Sized> BlockDevice for PartitionDevice<'a, D> {
+	fn block_size(&self) -> u32 {
+		self.disk.block_size()
+	}
+	
+	fn block_count(&self) -> u64 {
+		self.size_in_lba
+	}
+	
+	fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+		check_access(self.block_size(), self.size_in_lba, lba, buf.len())?;
+		self.disk.read_blocks(self.start_lba + lba, buf)
+	}
+	
+	fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> io::Result<()> {
+		check_access(self.block_size(), self.size_in_lba, lba, buf.len())?;
+		self.disk.write_blocks(self.start_lba + lba, buf)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		self.disk.flush()
+	}
+}
+
+/// Skips the first `offset_lba` blocks of a device and optionally stops after `limit_lba` blocks,
+/// e.g. for a disk image embedded in a bigger file.
+pub struct OffsetDevice<D> {
+	inner: D,
+	offset_lba: u64,
+	block_count: u64,
+}
+
+impl<D: BlockDevice> OffsetDevice<D> {
+	pub fn new(inner: D, offset_lba: u64, limit_lba: Option<u64>) -> io::Result<Self> {
+		if offset_lba > inner.block_count() {
+			let msg = format!("offset of {} blocks is beyond the end of the device ({} blocks)", offset_lba, inner.block_count());
+			return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+		}
+		
+		let remaining = inner.block_count() - offset_lba;
+		Ok(OffsetDevice {
+			block_count: limit_lba.map_or(remaining, |limit| cmp::min(limit, remaining)),
+			inner,
+			offset_lba,
+		})
+	}
+	
+	pub fn into_inner(self) -> D {
+		self.inner
+	}
+}
+
+impl<D: BlockDevice> BlockDevice for OffsetDevice<D> {
+	fn block_size(&self) -> u32 {
+		self.inner.block_size()
+	}
+	
+	fn block_count(&self) -> u64 {
+		self.block_count
+	}
+	
+	fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> io::Result<()> {
+		check_access(self.block_size(), self.block_count, lba, buf.len())?;
+		self.inner.read_blocks(self.offset_lba + lba, buf)
+	}
+	
+	fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> io::Result<()> {
+		check_access(self.block_size(), self.block_count, lba, buf.len())?;
+		self.inner.write_blocks(self.offset_lba + lba, buf)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Byte level access to a block device, for filesystem code like fatfs.
+///
+/// Partial blocks are read, modified and written back, the last one touched is kept around
+/// so runs of small accesses don't go to the device each time.
+/// Changes to it only reach the device on [`Write::flush`].
+pub struct BlockIo<D> {
+	device: D,
+	cursor: u64,
+	cache: Vec<u8>,
+	cache_lba: Option<u64>,
+	dirty: bool,
+}
+
+impl<D: BlockDevice> BlockIo<D> {
+	pub fn new(device: D) -> Self {
+		BlockIo {
+			cache: vec![0; device.block_size() as usize],
+			device,
+			cursor: 0,
+			cache_lba: None,
+			dirty: false,
+		}
+	}
+	
+	/// Flushes and returns the device.
+	pub fn into_inner(mut self) -> io::Result<D> {
+		self.flush()?;
+		Ok(self.device)
+	}
+	
+	fn size(&self) -> u64 {
+		self.device.size_bytes()
+	}
+	
+	fn write_back(&mut self) -> io::Result<()> {
+		if let (Some(lba), true) = (self.cache_lba, self.dirty) {
+			self.device.write_blocks(lba, &self.cache)?;
+			self.dirty = false;
+		}
+		Ok(())
+	}
+	
+	fn load(&mut self, lba: u64) -> io::Result<()> {
+		if self.cache_lba != Some(lba) {
+			self.write_back()?;
+			self.cache_lba = None;
+			self.device.read_blocks(lba, &mut self.cache)?;
+			self.cache_lba = Some(lba);
+		}
+		Ok(())
+	}
+	
+	/// The whole blocks an access of `len` bytes at the cursor covers, if it starts at a block boundary.
+	fn whole_blocks(&self, len: usize) -> Option<(u64, usize)> {
+		let block_size = self.cache.len();
+		match self.cursor.is_multiple_of(block_size as u64) && len >= block_size {
+			true => Some((self.cursor / block_size as u64, len / block_size)),
+			false => None,
+		}
+	}
+	
+	fn cache_within(&self, lba: u64, blocks: usize) -> bool {
+		self.cache_lba.is_some_and(|c| c >= lba && c < lba + blocks as u64)
+	}
+}
+
+impl<D: BlockDevice> Read for BlockIo<D> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = cmp::min(buf.len() as u64, self.size().saturating_sub(self.cursor)) as usize;
+		if len == 0 {
+			return Ok(0);
+		}
+		let block_size = self.cache.len();
+		
+		// Whole blocks straight from the device, which has to be up to date
+		if let Some((lba, blocks)) = self.whole_blocks(len) {
+			if self.cache_within(lba, blocks) {
+				self.write_back()?;
+			}
+			self.device.read_blocks(lba, &mut buf[..blocks * block_size])?;
+			self.cursor += (blocks * block_size) as u64;
+			return Ok(blocks * block_size);
+		}
+		
+		// Part of a block through the cache
+		let offset = (self.cursor % block_size as u64) as usize;
+		let len = cmp::min(len, block_size - offset);
+		self.load(self.cursor / block_size as u64)?;
+		buf[..len].copy_from_slice(&self.cache[offset..offset + len]);
+		self.cursor += len as u64;
+		
+		Ok(len)
+	}
+}
+
+impl<D: BlockDevice> Write for BlockIo<D> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+		let len = cmp::min(buf.len() as u64, self.size().saturating_sub(self.cursor)) as usize;
+		if len == 0 {
+			return Err(io::Error::new(io::ErrorKind::StorageFull, "write beyond the end of the block device"));
+		}
+		let block_size = self.cache.len();
+		
+		// Whole blocks straight to the device, replacing a cached copy
+		if let Some((lba, blocks)) = self.whole_blocks(len) {
+			if self.cache_within(lba, blocks) {
+				self.cache_lba = None;
+				self.dirty = false;
+			}
+			self.device.write_blocks(lba, &buf[..blocks * block_size])?;
+			self.cursor += (blocks * block_size) as u64;
+			return Ok(blocks * block_size);
+		}
+		
+		// Part of a block through the cache
+		let offset = (self.cursor % block_size as u64) as usize;
+		let len = cmp::min(len, block_size - offset);
+		self.load(self.cursor / block_size as u64)?;
+		self.cache[offset..offset + len].copy_from_slice(&buf[..len]);
+		self.dirty = true;
+		self.cursor += len as u64;
+		
+		Ok(len)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		self.write_back()?;
+		self.device.flush()
+	}
+}
+
+impl<D: BlockDevice> Seek for BlockIo<D> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_cursor = match pos {
+			SeekFrom::Start(n) => Some(n),
+			SeekFrom::Current(n) => self.cursor.checked_add_signed(n),
+			SeekFrom::End(n) => self.size().checked_add_signed(n),
+		};
+		
+		match new_cursor {
+			Some(cursor) if cursor <= self.size() => {
+				self.cursor = cursor;
+				Ok(cursor)
+			}
+			_ => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek outside of the block device")),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use crate::memdisk::MemDisk;
+	
+	fn mem_device(block_size: u32, block_count: u64) -> IoDevice<MemDisk> {
+		IoDevice::new(MemDisk::new_fixed_size((block_size as u64 * block_count) as usize), block_size).unwrap()
+	}
+	
+	#[test]
+	pub fn io_device_bounds() {
+		let mut dev = mem_device(512, 8);
+		assert_eq!(dev.size_bytes(), 4096);
+		
+		dev.write_blocks(6, &[0xAA; 1024]).unwrap();
+		assert_eq!(dev.write_blocks(7, &[0; 1024]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+		assert_eq!(dev.write_blocks(0, &[0; 100]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+		assert_eq!(dev.read_blocks(u64::MAX, &mut [0; 512]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+		
+		let data = dev.into_inner().into_inner();
+		assert!(data[..3072].iter().all(|&b| b == 0));
+		assert!(data[3072..].iter().all(|&b| b == 0xAA));
+	}
+	
+	#[test]
+	pub fn partition_and_offset_views() {
+		let mut dev = mem_device(512, 16);
+		{
+			let mut part = PartitionDevice::new(&mut dev, 4, 4).unwrap();
+			assert_eq!(part.block_count(), 4);
+			part.write_blocks(3, &[1; 512]).unwrap();
+			assert!(part.write_blocks(4, &[1; 512]).is_err());
+		}
+		assert!(PartitionDevice::new(&mut dev, 10, 7).is_err());
+		
+		let mut offset = OffsetDevice::new(&mut dev, 6, Some(100)).unwrap();
+		assert_eq!(offset.block_count(), 10);
+		let mut block = [0; 512];
+		offset.read_blocks(1, &mut block).unwrap();
+		assert_eq!(block, [1; 512]);
+		assert!(OffsetDevice::new(&mut dev, 17, None).is_err());
+	}
+	
+	#[test]
+	pub fn block_io_round_trip() {
+		let mut io = BlockIo::new(mem_device(512, 8));
+		
+		// Unaligned writes, some crossing block boundaries, and whole blocks
+		let expected = (0..4096).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+		io.seek(SeekFrom::Start(0)).unwrap();
+		for chunk in expected.chunks(300) {
+			io.write_all(chunk).unwrap();
+		}
+		io.seek(SeekFrom::Start(1024)).unwrap();
+		io.write_all(&expected[1024..2048]).unwrap();
+		
+		// Reads see cached writes
+		io.seek(SeekFrom::Start(100)).unwrap();
+		let mut buf = vec![0; 3996];
+		io.read_exact(&mut buf).unwrap();
+		assert_eq!(buf, &expected[100..]);
+		
+		assert_eq!(io.write(&[0]).unwrap_err().kind(), io::ErrorKind::StorageFull);
+		assert!(io.seek(SeekFrom::Current(1)).is_err());
+		
+		let data = io.into_inner().unwrap().into_inner().into_inner();
+		assert_eq!(data, expected);
+	}
+	
+	#[test]
+	pub fn fat_on_a_partition() {
+		let mut dev = mem_device(512, 4096);
+		{
+			let mut io = BlockIo::new(PartitionDevice::new(&mut dev, 2048, 2048).unwrap());
+			crate::fat::format_volume(&mut io, 2048 * 512, 512, 0).unwrap();
+			
+			let fs = fatfs::FileSystem::new(&mut io, fatfs::FsOptions::new()).unwrap();
+			fs.root_dir().create_file("hello.txt").unwrap().write_all(b"hello").unwrap();
+			fs.unmount().unwrap();
+			io.flush().unwrap();
+		}
+		
+		// Read back through another view of the same blocks
+		{
+			let io = BlockIo::new(OffsetDevice::new(&mut dev, 2048, None).unwrap());
+			let fs = fatfs::FileSystem::new(io, fatfs::FsOptions::new()).unwrap();
+			let mut contents = String::new();
+			fs.root_dir().open_file("hello.txt").unwrap().read_to_string(&mut contents).unwrap();
+			assert_eq!(contents, "hello");
+		}
+		
+		let data = dev.into_inner().into_inner();
+		assert!(data[..2048 * 512].iter().all(|&b| b == 0));
+		assert_eq!(&data[2048 * 512 + 510..2048 * 512 + 512], &[0x55, 0xAA]);
+	}
+}
@@ -0,0 +1,167 @@
+use std::{error, fmt, io};
+use std::path::PathBuf;
+
+use crate::fat::SizingError;
+use crate::gpt::{IntegrityIssue, PartitionError, PartitionNameError};
+use crate::manifest::ManifestError;
+use crate::mbr::{HybridMbrError, MbrError};
+
+/// Everything that can go wrong building an image.
+///
+/// Errors about a partition name it once known, see [`Error::in_partition`].
+#[derive(Debug)]
+pub enum Error {
+	/// Reading or writing failed, `context` says what was being done.
+	Io {context: String, error: io::Error},
+	/// The disk or a partition can't be laid out as described.
+	InvalidLayout {partition: Option<String>, reason: String},
+	/// The content doesn't fit into its partition or the disk.
+	ContentTooLarge {partition: Option<String>, reason: String},
+	/// An input file or directory can't be read.
+	MissingInput {path: PathBuf, error: io::Error},
+	/// A filesystem can't be created or filled.
+	FilesystemFormat {partition: Option<String>, error: io::Error},
+	/// The written image doesn't check out.
+	Verification {path: PathBuf, issues: Vec<IntegrityIssue>},
+}
+
+impl Error {
+	pub fn io(context: impl Into<String>, error: io::Error) -> Self {
+		Error::Io {
+			context: context.into(),
+			error,
+		}
+	}
+	
+	pub fn invalid_layout(reason: impl fmt::Display) -> Self {
+		Error::InvalidLayout {
+			partition: None,
+			reason: reason.to_string(),
+		}
+	}
+	
+	pub fn content_too_large(reason: impl fmt::Display) -> Self {
+		Error::ContentTooLarge {
+			partition: None,
+			reason: reason.to_string(),
+		}
+	}
+	
+	pub fn missing_input(path: impl Into<PathBuf>, error: io::Error) -> Self {
+		Error::MissingInput {
+			path: path.into(),
+			error,
+		}
+	}
+	
+	/// Names the partition the error is about, unless it already names one.
+	pub fn in_partition(self, name: &str) -> Self {
+		match self {
+			Error::Io {context, error} => Error::Io {context: format!("partition \"{}\": {}", name, context), error},
+			Error::InvalidLayout {partition: None, reason} => Error::InvalidLayout {partition: Some(name.to_owned()), reason},
+			Error::ContentTooLarge {partition: None, reason} => Error::ContentTooLarge {partition: Some(name.to_owned()), reason},
+			Error::FilesystemFormat {partition: None, error} => Error::FilesystemFormat {partition: Some(name.to_owned()), error},
+			other => other,
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let partition_prefix = |partition: &Option<String>| match partition {
+			Some(name) => format!("partition \"{}\": ", name),
+			None => String::new(),
+		};
+		
+		match self {
+			Error::Io {context, error} => write!(f, "{}: {}", context, error),
+			Error::InvalidLayout {partition, reason} => write!(f, "{}invalid layout: {}", partition_prefix(partition), reason),
+			Error::ContentTooLarge {partition, reason} => write!(f, "{}content doesn't fit: {}", partition_prefix(partition), reason),
+			Error::MissingInput {path, error} => write!(f, "can't read input {}: {}", path.display(), error),
+			Error::FilesystemFormat {partition, error} => write!(f, "{}can't build the filesystem: {}", partition_prefix(partition), error),
+			Error::Verification {path, issues} => {
+				write!(f, "{} failed verification", path.display())?;
+				for issue in issues.iter() {
+					write!(f, "\n  {}", issue)?;
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+		match self {
+			Error::Io {error, ..} | Error::MissingInput {error, ..} | Error::FilesystemFormat {error, ..} => Some(error),
+			_ => None,
+		}
+	}
+}
+
+impl From<PartitionError> for Error {
+	fn from(e: PartitionError) -> Self {
+		match e {
+			PartitionError::NoSpace {..} => Error::content_too_large(e),
+			_ => Error::invalid_layout(e),
+		}
+	}
+}
+
+impl From<PartitionNameError> for Error {
+	fn from(e: PartitionNameError) -> Self {
+		Error::invalid_layout(e)
+	}
+}
+
+impl From<MbrError> for Error {
+	fn from(e: MbrError) -> Self {
+		match e {
+			MbrError::NoSpace {..} => Error::content_too_large(e),
+			_ => Error::invalid_layout(e),
+		}
+	}
+}
+
+impl From<HybridMbrError> for Error {
+	fn from(e: HybridMbrError) -> Self {
+		Error::invalid_layout(e)
+	}
+}
+
+impl From<SizingError> for Error {
+	fn from(e: SizingError) -> Self {
+		Error::content_too_large(e)
+	}
+}
+
+impl From<ManifestError> for Error {
+	fn from(e: ManifestError) -> Self {
+		match e {
+			ManifestError::Io {path, error} => Error::missing_input(path, error),
+			_ => Error::invalid_layout(e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	pub fn partition_context() {
+		let e = Error::from(PartitionError::NoSpace {size_in_lba: 10}).in_partition("Nell Boot");
+		assert!(matches!(&e, Error::ContentTooLarge {partition: Some(name), ..} if name == "Nell Boot"));
+		assert!(e.to_string().starts_with("partition \"Nell Boot\": content doesn't fit: "), "{}", e);
+		
+		// The innermost partition wins
+		let e = e.in_partition("Other");
+		assert!(e.to_string().starts_with("partition \"Nell Boot\""), "{}", e);
+		
+		let e = Error::io("writing the mbr", io::Error::other("broken")).in_partition("ESP");
+		assert_eq!(e.to_string(), "partition \"ESP\": writing the mbr: broken");
+		
+		let e = Error::Verification {path: PathBuf::from("boot.img"), issues: vec![IntegrityIssue::DiskTooSmall {disk_size_lba: 2}]};
+		assert_eq!(e.to_string().lines().count(), 2);
+	}
+}
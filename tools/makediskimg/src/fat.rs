@@ -0,0 +1,814 @@
+use std::{cmp, fmt, io};
+use std::convert::TryFrom;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fatfs::{FatType, FileSystem, ReadWriteSeek};
+use glob::{MatchOptions, Pattern};
+
+use crate::Error;
+use crate::manifest::FileSpec;
+
+/// Root directory size of fat12/16 volumes, fatfs' default
+const ROOT_DIR_ENTRIES: u64 = 512;
+const DIR_ENTRY_SIZE: u64 = 32;
+/// Utf-16 code units per long file name entry
+const LFN_CHARS_PER_ENTRY: u64 = 13;
+/// Longest file name fatfs accepts, in utf-8 bytes
+const MAX_LFN_LEN: usize = 255;
+
+const FAT16_MIN_CLUSTERS: u64 = 4085;
+const FAT32_MIN_CLUSTERS: u64 = 65525;
+/// Fat32 allows 0x0FFF_FFF4, fatfs can't mount fats of 512MiB and more (it counts their bits in a u32)
+const FAT32_MAX_CLUSTERS: u64 = (1 << 27) - 4096;
+/// Largest cluster size all fat implementations support
+const MAX_CLUSTER_SIZE: u64 = 32 * 1024;
+
+/// What fatfs says when it runs out of clusters, it has no error kind for it
+const FATFS_NO_SPACE_MESSAGE: &str = "No space left on device";
+
+/// Something to put into a fat volume.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImportEntry {
+	Dir {vfs_path: String},
+	File {source: PathBuf, vfs_path: String, size: u64, created: Option<SystemTime>, modified: Option<SystemTime>},
+}
+
+/// Expands the file specs into the files and directories to import.
+///
+/// Host directories are imported recursively, keeping the relative paths.
+/// Their include/exclude patterns are matched against the file name, or the path relative to the directory if the pattern has a `/`.
+/// With include patterns only matching files and the directories leading to them are imported.
+pub fn collect_imports(files: &[FileSpec]) -> Result<Vec<ImportEntry>, Error> {
+	let mut imports = Vec::new();
+	for file in files.iter() {
+		check_vfs_path(&file.target).map_err(Error::invalid_layout)?;
+		
+		let filter = ImportFilter {
+			include: &file.include,
+			exclude: &file.exclude,
+		};
+		collect_imports_rec(&file.source, "", &file.target, &filter, &mut imports)?;
+	}
+	Ok(imports)
+}
+
+struct ImportFilter<'a> {
+	include: &'a [Pattern],
+	exclude: &'a [Pattern],
+}
+
+impl<'a> ImportFilter<'a> {
+	fn matches(patterns: &[Pattern], rel_path: &str) -> bool {
+		let options = MatchOptions {
+			require_literal_separator: true,
+			..MatchOptions::new()
+		};
+		let file_name = rel_path.rsplit('/').next().unwrap();
+		
+		patterns.iter().any(|p| match p.as_str().contains('/') {
+			true => p.matches_with(rel_path, options),
+			false => p.matches_with(file_name, options),
+		})
+	}
+	
+	fn excludes(&self, rel_path: &str) -> bool {
+		!rel_path.is_empty() && Self::matches(self.exclude, rel_path)
+	}
+	
+	fn includes_file(&self, rel_path: &str) -> bool {
+		self.include.is_empty() || rel_path.is_empty() || Self::matches(self.include, rel_path)
+	}
+}
+
+/// Collects `source`, found at `rel_path` within the imported tree, into `vfs_path`.
+fn collect_imports_rec(source: &Path, rel_path: &str, vfs_path: &str, filter: &ImportFilter, imports: &mut Vec<ImportEntry>) -> Result<(), Error> {
+	let metadata = fs::metadata(source).map_err(|e| Error::missing_input(source, e))?;
+	if !metadata.is_dir() {
+		if filter.includes_file(rel_path) {
+			imports.push(ImportEntry::File {
+				source: source.to_owned(),
+				vfs_path: vfs_path.to_owned(),
+				size: metadata.len(),
+				created: metadata.created().ok(),
+				modified: metadata.modified().ok(),
+			});
+		}
+		return Ok(());
+	}
+	
+	// Sorted, so the image doesn't depend on the host's directory order
+	let mut entries = fs::read_dir(source)
+		.and_then(|dir| dir.collect::<io::Result<Vec<_>>>())
+		.map_err(|e| Error::missing_input(source, e))?;
+	entries.sort_by_key(|e| e.file_name());
+	
+	let mut children = Vec::new();
+	let mut names = BTreeMap::new();
+	for entry in entries {
+		let name = entry.file_name().into_string()
+			.map_err(|name| Error::invalid_layout(format!("{}: file name isn't valid unicode", Path::new(&name).display())))?;
+		
+		let child_rel_path = match rel_path.is_empty() {
+			true => name.clone(),
+			false => format!("{}/{}", rel_path, name),
+		};
+		if filter.excludes(&child_rel_path) {
+			continue;
+		}
+		
+		// Fat names are case insensitive
+		check_vfs_name(&name)
+			.map_err(|e| Error::invalid_layout(format!("{}: {}", entry.path().display(), e)))?;
+		if let Some(other) = names.insert(name.to_lowercase(), name.clone()) {
+			let msg = format!("{}: \"{}\" and \"{}\" only differ in case", source.display(), other, name);
+			return Err(Error::invalid_layout(msg));
+		}
+		
+		let child_vfs_path = format!("{}/{}", vfs_path.trim_end_matches('/'), name);
+		collect_imports_rec(&entry.path(), &child_rel_path, &child_vfs_path, filter, &mut children)?;
+	}
+	
+	// Skip directories without anything included, unless everything is
+	if filter.include.is_empty() || !children.is_empty() || rel_path.is_empty() {
+		imports.push(ImportEntry::Dir {vfs_path: vfs_path.to_owned()});
+	}
+	imports.append(&mut children);
+	Ok(())
+}
+
+/// Checks the path is absolute and made up of valid long file names.
+fn check_vfs_path(vfs_path: &str) -> Result<(), String> {
+	if !vfs_path.starts_with('/') {
+		return Err(format!("\"{}\" isn't an absolute path", vfs_path));
+	}
+	vfs_path.split('/')
+		.filter(|s| !s.is_empty())
+		.try_for_each(check_vfs_name)
+		.map_err(|e| format!("\"{}\": {}", vfs_path, e))
+}
+
+/// Checks the name is a valid long file name, same rules as fatfs.
+fn check_vfs_name(name: &str) -> Result<(), String> {
+	if name.is_empty() || name == "." || name == ".." {
+		return Err(format!("\"{}\" isn't a valid file name", name));
+	}
+	if name.len() > MAX_LFN_LEN {
+		return Err(format!("\"{}\" is longer than {} bytes", name, MAX_LFN_LEN));
+	}
+	
+	let valid_char = |c: char| c.is_ascii_alphanumeric() || ('\u{80}'..='\u{FFFF}').contains(&c) || "$%'-_@~`!(){}. +,;=[]^#&".contains(c);
+	match name.chars().find(|&c| !valid_char(c)) {
+		Some(c) => Err(format!("\"{}\" contains the unsupported character {:?}", name, c)),
+		None => Ok(()),
+	}
+}
+
+/// Converts to a fat timestamp, clamped to the representable years 1980 to 2107.
+/// Fat has no time zones, utc is used.
+pub fn fat_date_time(time: SystemTime) -> fatfs::DateTime {
+	let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let secs = since_epoch.as_secs();
+	let (year, month, day) = civil_from_days((secs / 86400) as i64);
+	
+	let (date, time) = match year {
+		y if y < 1980 => (fatfs::Date {year: 1980, month: 1, day: 1}, fatfs::Time {hour: 0, min: 0, sec: 0, millis: 0}),
+		y if y > 2107 => (fatfs::Date {year: 2107, month: 12, day: 31}, fatfs::Time {hour: 23, min: 59, sec: 59, millis: 999}),
+		_ => {
+			let secs_of_day = secs % 86400;
+			(
+				fatfs::Date {year: year as u16, month, day},
+				fatfs::Time {
+					hour: (secs_of_day / 3600) as u16,
+					min: (secs_of_day / 60 % 60) as u16,
+					sec: (secs_of_day % 60) as u16,
+					millis: since_epoch.subsec_millis() as u16,
+				},
+			)
+		}
+	};
+	fatfs::DateTime {date, time}
+}
+
+/// Converts days since 1970-01-01 to year, month and day of the proleptic gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u16, u16) {
+	// From Howard Hinnant's date algorithms
+	let z = days + 719468;
+	let era = z.div_euclid(146097);
+	let doe = z.rem_euclid(146097);
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 {mp + 3} else {mp - 9};
+	let year = yoe + era * 400 + if month <= 2 {1} else {0};
+	(year, month as u16, day as u16)
+}
+
+/// Copies the imports into the filesystem, creating parent directories as needed.
+/// Existing files are overwritten, files keep their host timestamps.
+/// Fails without writing anything if the file data alone doesn't fit into the free space.
+pub fn import<T: ReadWriteSeek>(fs: &mut FileSystem<T>, imports: &[ImportEntry]) -> Result<(), Error> {
+	check_free_space(fs, imports)?;
+	
+	for entry in imports.iter() {
+		match entry {
+			ImportEntry::Dir {vfs_path} => {
+				create_dirs(fs, vfs_path.split('/'))
+					.map_err(|e| import_error(vfs_path, e))?;
+			}
+			ImportEntry::File {source, vfs_path, created, modified, ..} => {
+				let mut src_file = File::open(source)
+					.map_err(|e| Error::missing_input(source, e))?;
+				
+				let (target_dir, file_name) = {
+					let segs = vfs_path.split('/').collect::<Vec<_>>();
+					(create_dirs(fs, segs[..segs.len()-1].iter().copied()).map_err(|e| import_error(vfs_path, e))?, *segs.last().unwrap())
+				};
+				
+				// Create file
+				let mut vfs_file = target_dir
+					.create_file(file_name)
+					.and_then(|mut f| f.truncate().map(|_| f))
+					.map_err(|e| import_error(vfs_path, e))?;
+				
+				io::copy(&mut src_file, &mut vfs_file)
+					.map_err(|e| import_error(vfs_path, e))?;
+				
+				// Deprecated in favour of a TimeProvider, which can't give each file its own time.
+				// Has to come after writing, which sets the modification time
+				#[allow(deprecated)]
+				{
+					if let Some(modified) = modified {
+						let modified = fat_date_time(*modified);
+						vfs_file.set_modified(modified);
+						vfs_file.set_accessed(modified.date);
+						vfs_file.set_created(created.map_or(modified, fat_date_time));
+					}
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Compares the clusters the imported files need, less those of the files they replace, to the free ones.
+/// Directories aren't counted, running out of space for them is still caught by [`import_error`].
+fn check_free_space<T: ReadWriteSeek>(fs: &FileSystem<T>, imports: &[ImportEntry]) -> Result<(), Error> {
+	let format_error = |error| Error::FilesystemFormat {partition: None, error};
+	let cluster_size = fs.cluster_size() as u64;
+	let free_clusters = fs.stats().map_err(format_error)?.free_clusters() as u64;
+	
+	// Later imports to the same path replace earlier ones
+	let sizes = imports.iter()
+		.filter_map(|entry| match entry {
+			ImportEntry::File {vfs_path, size, ..} => Some((vfs_path.as_str(), *size)),
+			ImportEntry::Dir {..} => None,
+		})
+		.collect::<BTreeMap<_, _>>();
+	
+	let mut needed_clusters = 0i64;
+	for (vfs_path, size) in sizes {
+		let replaced_size = match fs.root_dir().open_file(vfs_path.trim_start_matches('/')) {
+			Ok(mut file) => file.seek(SeekFrom::End(0)).map_err(format_error)?,
+			Err(_) => 0,
+		};
+		needed_clusters += size.div_ceil(cluster_size) as i64 - replaced_size.div_ceil(cluster_size) as i64;
+	}
+	
+	if needed_clusters > free_clusters as i64 {
+		return Err(Error::content_too_large(format!("the files need {} more clusters of {} bytes, only {} are free", needed_clusters, cluster_size, free_clusters)));
+	}
+	Ok(())
+}
+
+/// Errors of fatfs while importing to `vfs_path`, a full volume means the content is too large.
+fn import_error(vfs_path: &str, e: io::Error) -> Error {
+	if e.kind() == io::ErrorKind::Other && e.to_string() == FATFS_NO_SPACE_MESSAGE {
+		return Error::content_too_large(format!("no space left for {}", vfs_path));
+	}
+	Error::FilesystemFormat {
+		partition: None,
+		error: io::Error::new(e.kind(), format!("{}: {}", vfs_path, e)),
+	}
+}
+
+/// Creates (or opens) the directory made up of the given path segments, empty segments are skipped.
+fn create_dirs<'a, T: ReadWriteSeek>(fs: &'a FileSystem<T>, segs: impl Iterator<Item = &'a str>) -> io::Result<fatfs::Dir<'a, T>> {
+	let mut dir = fs.root_dir();
+	for s in segs.filter(|s| !s.is_empty()) {
+		dir = dir.create_dir(s)?;
+	}
+	Ok(dir)
+}
+
+/// Formats a fat volume at the start of the storage, e.g. a partition slice.
+/// The size is rounded down to whole sectors, the sector size should match the disk's block size.
+/// Clusters are one sector, see [`sectors_per_cluster`] for volumes too large for that.
+/// `volume_id` is the serial number in the boot sector.
+pub fn format_volume<T: ReadWriteSeek>(storage: &mut T, size_bytes: u64, sector_size: u32, volume_id: u32) -> io::Result<()> {
+	let total_sectors = u32::try_from(size_bytes / sector_size as u64).map_err(|_| {
+		let msg = format!("a volume of {} bytes has more than 2^32 sectors of {} bytes", size_bytes, sector_size);
+		io::Error::new(io::ErrorKind::InvalidInput, msg)
+	})?;
+	let sectors_per_cluster = sectors_per_cluster(total_sectors as u64, sector_size);
+	if clusters_of(total_sectors as u64, sector_size, sectors_per_cluster, FatType::Fat32) > FAT32_MAX_CLUSTERS {
+		let msg = format!("a volume of {} bytes has too many clusters for fat32, even with {} byte clusters", size_bytes, MAX_CLUSTER_SIZE);
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+	}
+	
+	// Fat32 is only a hint here, fatfs picks the fat type by the number of clusters
+	let format_opts = fatfs::FormatVolumeOptions::new()
+		.fat_type(FatType::Fat32)
+		.bytes_per_sector(sector_size as u16)
+		.total_sectors(total_sectors)
+		.bytes_per_cluster((sectors_per_cluster * sector_size as u64) as u32)
+		.volume_id(volume_id);
+	
+	fatfs::format_volume(&mut *storage, format_opts)?;
+	
+	storage.seek(SeekFrom::Start(0))?;
+	Ok(())
+}
+
+/// Size and type of the smallest fat volume that holds some content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VolumeSize {
+	pub fat_type: FatType,
+	pub size_bytes: u64,
+}
+
+/// Computes the smallest volume (as formatted by [`format_volume`]) that holds the imports plus `headroom_bytes` of free space.
+/// Picks the smallest fat type the content fits in unless `fat_type` forces one.
+pub fn min_volume_size(imports: &[ImportEntry], sector_size: u32, headroom_bytes: u64, fat_type: Option<FatType>) -> Result<VolumeSize, SizingError> {
+	let content = ContentTally::new(imports);
+	let clusters = |cluster_size: u64, with_root: bool| content.data_clusters(cluster_size, with_root) + headroom_bytes.div_ceil(cluster_size);
+	let root_fits = content.root_entries() < ROOT_DIR_ENTRIES;
+	
+	let fat_type = match fat_type {
+		Some(fat_type) => fat_type,
+		// Fat12/16 have a fixed size root directory, fat32 stores it in clusters
+		None if !root_fits || clusters(sector_size as u64, false) >= FAT32_MIN_CLUSTERS => FatType::Fat32,
+		None if clusters(sector_size as u64, false) >= FAT16_MIN_CLUSTERS => FatType::Fat16,
+		None => FatType::Fat12,
+	};
+	if fat_type != FatType::Fat32 && !root_fits {
+		return Err(SizingError::RootDirFull {entries: content.root_entries()});
+	}
+	
+	// Bigger clusters only for fat32 content that doesn't fit otherwise, same as format_volume
+	let mut sectors_per_cluster = 1;
+	let needed_clusters = loop {
+		let cluster_size = sectors_per_cluster * sector_size as u64;
+		let needed_clusters = clusters(cluster_size, fat_type == FatType::Fat32);
+		if needed_clusters <= max_clusters(fat_type) {
+			break needed_clusters;
+		}
+		if fat_type != FatType::Fat32 || cluster_size >= MAX_CLUSTER_SIZE {
+			return Err(SizingError::TooLarge {fat_type, clusters: needed_clusters});
+		}
+		sectors_per_cluster *= 2;
+	};
+	
+	let clusters = cmp::max(needed_clusters, min_clusters(fat_type));
+	let mut total_sectors = min_total_sectors(clusters, sector_size, sectors_per_cluster, fat_type);
+	if sectors_per_cluster > 1 {
+		// Too large for half the cluster size, or format_volume would pick that
+		total_sectors = cmp::max(total_sectors, min_total_sectors(FAT32_MAX_CLUSTERS + 1, sector_size, sectors_per_cluster / 2, fat_type));
+	}
+	// Fat counts sectors in 32 bits
+	if total_sectors > u32::MAX as u64 {
+		return Err(SizingError::TooLarge {fat_type, clusters: needed_clusters});
+	}
+	
+	Ok(VolumeSize {
+		fat_type,
+		size_bytes: total_sectors * sector_size as u64,
+	})
+}
+
+/// Sectors per cluster [`format_volume`] uses: one, doubled up to [`MAX_CLUSTER_SIZE`] while there are more than [`FAT32_MAX_CLUSTERS`].
+/// Like mkfs.fat only large volumes get bigger clusters, but they stay as small as possible.
+fn sectors_per_cluster(total_sectors: u64, sector_size: u32) -> u64 {
+	let mut sectors_per_cluster = 1;
+	while clusters_of(total_sectors, sector_size, sectors_per_cluster, FatType::Fat32) > FAT32_MAX_CLUSTERS
+		&& sectors_per_cluster * (sector_size as u64) < MAX_CLUSTER_SIZE {
+		sectors_per_cluster *= 2;
+	}
+	sectors_per_cluster
+}
+
+/// Number of clusters of a volume of the given type, mirrors fatfs' geometry calculation.
+fn clusters_of(total_sectors: u64, sector_size: u32, sectors_per_cluster: u64, fat_type: FatType) -> u64 {
+	let data_and_fat_sectors = total_sectors.saturating_sub(overhead_sectors(sector_size, fat_type));
+	let t1 = data_and_fat_sectors + 2 * sectors_per_cluster;
+	let sectors_per_fat = t1.div_ceil(sectors_per_cluster * sector_size as u64 * 8 / fat_bits(fat_type) + 2);
+	data_and_fat_sectors.saturating_sub(2 * sectors_per_fat) / sectors_per_cluster
+}
+
+/// Reserved and root directory sectors.
+fn overhead_sectors(sector_size: u32, fat_type: FatType) -> u64 {
+	match fat_type {
+		FatType::Fat32 => 8,
+		_ => 1 + (ROOT_DIR_ENTRIES * DIR_ENTRY_SIZE).div_ceil(sector_size as u64),
+	}
+}
+
+/// Smallest volume with at least `clusters` clusters of the given type.
+fn min_total_sectors(clusters: u64, sector_size: u32, sectors_per_cluster: u64, fat_type: FatType) -> u64 {
+	let overhead = overhead_sectors(sector_size, fat_type);
+	
+	// Binary search, the cluster count only grows with the volume size
+	let mut low = overhead + 9;
+	let mut high = overhead + 9 + 2 * clusters * sectors_per_cluster;
+	while low < high {
+		let mid = low + (high - low) / 2;
+		match clusters_of(mid, sector_size, sectors_per_cluster, fat_type) >= clusters {
+			true => high = mid,
+			false => low = mid + 1,
+		}
+	}
+	low
+}
+
+fn fat_bits(fat_type: FatType) -> u64 {
+	match fat_type {
+		FatType::Fat12 => 12,
+		FatType::Fat16 => 16,
+		FatType::Fat32 => 32,
+	}
+}
+
+fn min_clusters(fat_type: FatType) -> u64 {
+	match fat_type {
+		FatType::Fat12 => 1,
+		FatType::Fat16 => FAT16_MIN_CLUSTERS,
+		FatType::Fat32 => FAT32_MIN_CLUSTERS,
+	}
+}
+
+fn max_clusters(fat_type: FatType) -> u64 {
+	match fat_type {
+		FatType::Fat12 => FAT16_MIN_CLUSTERS - 1,
+		FatType::Fat16 => FAT32_MIN_CLUSTERS - 1,
+		FatType::Fat32 => FAT32_MAX_CLUSTERS,
+	}
+}
+
+/// The directory tree and file sizes of a volume's content.
+struct ContentTally {
+	/// Names in each directory, keyed by the directory's path (root is "")
+	dirs: BTreeMap<String, BTreeSet<String>>,
+	files: BTreeMap<String, u64>,
+}
+
+impl ContentTally {
+	fn new(imports: &[ImportEntry]) -> ContentTally {
+		let mut tally = ContentTally {
+			dirs: BTreeMap::new(),
+			files: BTreeMap::new(),
+		};
+		tally.dirs.insert(String::new(), BTreeSet::new());
+		
+		for entry in imports.iter() {
+			match entry {
+				ImportEntry::Dir {vfs_path} => {
+					tally.add_dir(&normalize(vfs_path));
+				}
+				ImportEntry::File {vfs_path, size, ..} => {
+					let path = normalize(vfs_path);
+					let (parent, name) = split_parent(&path);
+					tally.add_dir(parent);
+					tally.dirs.get_mut(parent).unwrap().insert(name.to_owned());
+					tally.files.insert(path, *size);
+				}
+			}
+		}
+		tally
+	}
+	
+	fn add_dir(&mut self, path: &str) {
+		if path.is_empty() || self.dirs.contains_key(path) {
+			return;
+		}
+		
+		let (parent, name) = split_parent(path);
+		self.add_dir(parent);
+		self.dirs.get_mut(parent).unwrap().insert(name.to_owned());
+		self.dirs.insert(path.to_owned(), BTreeSet::new());
+	}
+	
+	fn root_entries(&self) -> u64 {
+		dir_entries(&self.dirs[""])
+	}
+	
+	/// Clusters taken by files and directories, optionally including the root directory.
+	fn data_clusters(&self, cluster_size: u64, with_root: bool) -> u64 {
+		let files = self.files.values()
+			.map(|size| size.div_ceil(cluster_size))
+			.sum::<u64>();
+		let dirs = self.dirs.iter()
+			.filter(|(path, _)| with_root || !path.is_empty())
+			.map(|(path, names)| {
+				// Subdirectories start with "." and ".."
+				let dots = if path.is_empty() {0} else {2};
+				((dots + dir_entries(names)) * DIR_ENTRY_SIZE).div_ceil(cluster_size)
+			})
+			.sum::<u64>();
+		files + dirs
+	}
+}
+
+/// Directory entries taken by the names, assuming each needs a long file name.
+/// Adds one spare entry, since fatfs grows a directory once its last entry is used.
+fn dir_entries(names: &BTreeSet<String>) -> u64 {
+	let entries = names.iter()
+		.map(|name| 1 + (name.encode_utf16().count() as u64).div_ceil(LFN_CHARS_PER_ENTRY))
+		.sum::<u64>();
+	entries + 1
+}
+
+fn normalize(vfs_path: &str) -> String {
+	vfs_path.split('/')
+		.filter(|s| !s.is_empty())
+		.collect::<Vec<_>>()
+		.join("/")
+}
+
+fn split_parent(path: &str) -> (&str, &str) {
+	match path.rfind('/') {
+		Some(i) => (&path[..i], &path[i+1..]),
+		None => ("", path),
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SizingError {
+	/// The root directory of fat12/16 volumes is limited to 512 entries.
+	RootDirFull {entries: u64},
+	TooLarge {fat_type: FatType, clusters: u64},
+}
+
+impl fmt::Display for SizingError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			SizingError::RootDirFull {entries} => write!(f, "root directory needs {} entries, fat12/16 only have {}", entries, ROOT_DIR_ENTRIES),
+			SizingError::TooLarge {fat_type, clusters} => write!(f, "content needs {} clusters, more than {:?} supports", clusters, fat_type),
+		}
+	}
+}
+
+impl std::error::Error for SizingError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use std::io::Write;
+	
+	use crate::memdisk::MemDisk;
+	use crate::test_util::temp_dir;
+	
+	fn new_volume(size_bytes: u64, sector_size: u32) -> io::Result<MemDisk> {
+		let mut disk = MemDisk::new_fixed_size(size_bytes as usize);
+		format_volume(&mut disk, size_bytes, sector_size, 0)?;
+		Ok(disk)
+	}
+	
+	fn file(vfs_path: &str, size: u64) -> ImportEntry {
+		ImportEntry::File {source: PathBuf::new(), vfs_path: vfs_path.to_owned(), size, created: None, modified: None}
+	}
+	
+	fn dir_spec(source: &Path, target: &str, include: &[&str], exclude: &[&str]) -> FileSpec {
+		let patterns = |globs: &[&str]| globs.iter().map(|g| Pattern::new(g).unwrap()).collect();
+		FileSpec {
+			source: source.to_owned(),
+			target: target.to_owned(),
+			include: patterns(include),
+			exclude: patterns(exclude),
+		}
+	}
+	
+	fn vfs_paths(imports: &[ImportEntry]) -> Vec<&str> {
+		imports.iter().map(|e| match e {
+			ImportEntry::Dir {vfs_path} | ImportEntry::File {vfs_path, ..} => vfs_path.as_str(),
+		}).collect()
+	}
+	
+	/// Formats a volume of the given size and checks fatfs agrees on the type and free space.
+	fn check_volume(size: VolumeSize, sector_size: u32, min_free_clusters: u64) {
+		let mut disk = new_volume(size.size_bytes, sector_size).unwrap();
+		let fs = FileSystem::new(&mut disk, fatfs::FsOptions::new()).unwrap();
+		assert_eq!(fs.fat_type(), size.fat_type);
+		assert!(fs.stats().unwrap().free_clusters() as u64 >= min_free_clusters);
+	}
+	
+	#[test]
+	pub fn picks_smallest_fat_type() {
+		let small = min_volume_size(&[file("/kernel.elf", 100 * 1024)], 512, 0, None).unwrap();
+		assert_eq!(small.fat_type, FatType::Fat12);
+		check_volume(small, 512, 200);
+		
+		let medium = min_volume_size(&[file("/kernel.elf", 10 * 1024 * 1024)], 512, 0, None).unwrap();
+		assert_eq!(medium.fat_type, FatType::Fat16);
+		check_volume(medium, 512, 20480);
+		
+		let large = min_volume_size(&[file("/kernel.elf", 10 * 1024 * 1024)], 512, 30 * 1024 * 1024, None).unwrap();
+		assert_eq!(large.fat_type, FatType::Fat32);
+		check_volume(large, 512, 81920);
+		
+		let forced = min_volume_size(&[file("/kernel.elf", 1024)], 512, 0, Some(FatType::Fat32)).unwrap();
+		assert_eq!(forced.fat_type, FatType::Fat32);
+		check_volume(forced, 512, FAT32_MIN_CLUSTERS - 1);
+		
+		// Not a single sector to spare
+		assert!(new_volume(forced.size_bytes - 512, 512).map_or(true, |mut disk| {
+			FileSystem::new(&mut disk, fatfs::FsOptions::new()).unwrap().fat_type() != FatType::Fat32
+		}));
+	}
+	
+	#[test]
+	pub fn large_volumes_get_bigger_clusters() {
+		// More clusters of one sector than fatfs can mount
+		let size_bytes = 200 << 30;
+		let mut disk = MemDisk::new_sparse(size_bytes);
+		format_volume(&mut disk, size_bytes, 512, 0).unwrap();
+		{
+			let fs = FileSystem::new(&mut disk, fatfs::FsOptions::new()).unwrap();
+			assert_eq!(fs.fat_type(), FatType::Fat32);
+			assert_eq!(fs.cluster_size(), 2048);
+		}
+		
+		// Sized with the same cluster size format_volume picks
+		let size = min_volume_size(&[file("/big.bin", 150 << 30)], 512, 0, None).unwrap();
+		let total_sectors = size.size_bytes / 512;
+		assert_eq!(sectors_per_cluster(total_sectors, 512), 4);
+		assert!(clusters_of(total_sectors, 512, 4, FatType::Fat32) >= (150 << 30) / 2048);
+		
+		assert!(matches!(min_volume_size(&[file("/huge.bin", 8 << 40)], 512, 0, None), Err(SizingError::TooLarge {..})));
+		let err = format_volume(&mut MemDisk::new_sparse(8 << 40), 8 << 40, 4096, 0).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+	}
+	
+	#[test]
+	pub fn rejects_more_than_u32_sectors() {
+		let size_bytes = 3 << 40;
+		let err = format_volume(&mut MemDisk::new_sparse(size_bytes), size_bytes, 512, 0).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		
+		let content = [file("/big.bin", size_bytes)];
+		assert!(matches!(min_volume_size(&content, 512, 0, None), Err(SizingError::TooLarge {fat_type: FatType::Fat32, ..})));
+		assert!(min_volume_size(&content, 4096, 0, None).unwrap().size_bytes / 4096 <= u32::MAX as u64);
+	}
+	
+	#[test]
+	pub fn counts_directories() {
+		let imports = [
+			ImportEntry::Dir {vfs_path: "/empty".to_owned()},
+			file("/efi/boot/bootx64.efi", 1),
+			file("/efi/boot/nell_foo/nellbootx64.efi", 1),
+			file("/efi/boot/bootx64.efi", 1),
+		];
+		let tally = ContentTally::new(&imports);
+		
+		// "empty", "efi"
+		assert_eq!(tally.root_entries(), 2 + 2 + 1);
+		// 2 files, 4 dirs (empty, efi, efi/boot, efi/boot/nell_foo)
+		assert_eq!(tally.data_clusters(512, false), 2 + 4);
+		assert_eq!(tally.data_clusters(512, true), 2 + 5);
+		
+		let many = (0..600).map(|i| file(&format!("/{}", i), 1)).collect::<Vec<_>>();
+		assert_eq!(min_volume_size(&many, 512, 0, None).unwrap().fat_type, FatType::Fat32);
+		assert_eq!(min_volume_size(&many, 512, 0, Some(FatType::Fat16)), Err(SizingError::RootDirFull {entries: 1201}));
+	}
+	
+	#[test]
+	pub fn imported_content_fits() {
+		let dir = temp_dir("fit");
+		fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+		fs::write(dir.join("a_file_with_a_rather_long_name.bin"), vec![0xAB; 70_000]).unwrap();
+		fs::write(dir.join("sub/deeper/b.txt"), b"hello").unwrap();
+		
+		let imports = collect_imports(&[dir_spec(&dir, "/stuff", &[], &[])]).unwrap();
+		assert_eq!(imports.len(), 5);
+		
+		let size = min_volume_size(&imports, 512, 0, None).unwrap();
+		let mut disk = new_volume(size.size_bytes, 512).unwrap();
+		{
+			let mut fs = FileSystem::new(&mut disk, fatfs::FsOptions::new()).unwrap();
+			import(&mut fs, &imports).unwrap();
+			assert_eq!(fs.root_dir().open_file("stuff/sub/deeper/b.txt").unwrap().seek(SeekFrom::End(0)).unwrap(), 5);
+		}
+		
+		fs::remove_dir_all(&dir).unwrap();
+	}
+	
+	#[test]
+	pub fn include_and_exclude_globs() {
+		let dir = temp_dir("globs");
+		fs::create_dir_all(dir.join("bin/debug")).unwrap();
+		fs::create_dir_all(dir.join("docs")).unwrap();
+		fs::create_dir_all(dir.join(".git")).unwrap();
+		for path in &["bin/a.efi", "bin/a.pdb", "bin/debug/b.efi", "docs/readme.txt", ".git/config", "top.efi"] {
+			fs::write(dir.join(path), b"x").unwrap();
+		}
+		
+		let all = collect_imports(&[dir_spec(&dir, "/", &[], &[".git", "*.pdb"])]).unwrap();
+		assert_eq!(vfs_paths(&all), ["/", "/bin", "/bin/a.efi", "/bin/debug", "/bin/debug/b.efi", "/docs", "/docs/readme.txt", "/top.efi"]);
+		
+		let efi = collect_imports(&[dir_spec(&dir, "/efi", &["*.efi"], &["bin/debug"])]).unwrap();
+		assert_eq!(vfs_paths(&efi), ["/efi", "/efi/bin", "/efi/bin/a.efi", "/efi/top.efi"]);
+		
+		let nested = collect_imports(&[dir_spec(&dir, "/efi", &["bin/*"], &[])]).unwrap();
+		assert_eq!(vfs_paths(&nested), ["/efi", "/efi/bin", "/efi/bin/a.efi", "/efi/bin/a.pdb"]);
+		
+		fs::remove_dir_all(&dir).unwrap();
+	}
+	
+	#[test]
+	pub fn rejects_unsupported_names() {
+		assert!(check_vfs_path("/efi/boot/bootx64.efi").is_ok());
+		assert!(check_vfs_path("/a name with spaces; and [brackets]").is_ok());
+		assert!(check_vfs_path("relative/path").is_err());
+		assert!(check_vfs_path("/what?").is_err());
+		assert!(check_vfs_path("/a/../b").is_err());
+		assert!(check_vfs_name(&"x".repeat(MAX_LFN_LEN)).is_ok());
+		assert!(check_vfs_name(&"x".repeat(MAX_LFN_LEN + 1)).is_err());
+		
+		let dir = temp_dir("names");
+		fs::write(dir.join("Kernel.elf"), b"x").unwrap();
+		fs::write(dir.join("kernel.elf"), b"x").unwrap();
+		let err = collect_imports(&[dir_spec(&dir, "/", &[], &[])]).unwrap_err();
+		assert!(err.to_string().contains("only differ in case"), "{}", err);
+		
+		fs::write(dir.join("a*b"), b"x").unwrap();
+		let err = collect_imports(&[dir_spec(&dir, "/", &["a*"], &[])]).unwrap_err();
+		assert!(err.to_string().contains("a*b"), "{}", err);
+		
+		fs::remove_dir_all(&dir).unwrap();
+	}
+	
+	#[test]
+	pub fn fat_timestamps() {
+		let at = |secs: u64| fat_date_time(UNIX_EPOCH + std::time::Duration::from_millis(secs * 1000 + 250));
+		
+		let dt = at(1_600_000_000);
+		assert_eq!(dt.date, fatfs::Date {year: 2020, month: 9, day: 13});
+		assert_eq!(dt.time, fatfs::Time {hour: 12, min: 26, sec: 40, millis: 250});
+		
+		let leap = at(951_782_400);
+		assert_eq!(leap.date, fatfs::Date {year: 2000, month: 2, day: 29});
+		
+		assert_eq!(at(0).date, fatfs::Date {year: 1980, month: 1, day: 1});
+		assert_eq!(at(10_000_000_000).date, fatfs::Date {year: 2107, month: 12, day: 31});
+	}
+	
+	#[test]
+	pub fn import_checks_free_space() {
+		let dir = temp_dir("free_space");
+		fs::write(dir.join("big.bin"), vec![1; 600 * 1024]).unwrap();
+		let imports = collect_imports(&[dir_spec(&dir, "/", &[], &[])]).unwrap();
+		
+		let mut disk = new_volume(512 * 1024, 512).unwrap();
+		let mut fs = FileSystem::new(&mut disk, fatfs::FsOptions::new()).unwrap();
+		let err = import(&mut fs, &imports).unwrap_err();
+		assert!(matches!(err, Error::ContentTooLarge {..}), "{}", err);
+		assert_eq!(fs.root_dir().iter().count(), 0);
+		
+		// Directories can still use up the last clusters, fatfs reports that by its message only
+		let err = fs.root_dir().create_file("big.bin").unwrap().write_all(&[1; 600 * 1024]).unwrap_err();
+		assert!(matches!(import_error("/big.bin", err), Error::ContentTooLarge {..}));
+		
+		drop(fs);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+	
+	#[test]
+	pub fn import_keeps_timestamps() {
+		let dir = temp_dir("times");
+		fs::write(dir.join("file.bin"), b"contents").unwrap();
+		
+		let mut imports = collect_imports(&[dir_spec(&dir, "/", &[], &[])]).unwrap();
+		let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_234_567_890);
+		if let ImportEntry::File {modified: m, created: c, ..} = &mut imports[1] {
+			*m = Some(modified);
+			*c = Some(modified);
+		}
+		
+		let mut disk = new_volume(1024 * 1024, 512).unwrap();
+		{
+			let mut fs = FileSystem::new(&mut disk, fatfs::FsOptions::new()).unwrap();
+			import(&mut fs, &imports).unwrap();
+			
+			let entry = fs.root_dir().iter().map(|e| e.unwrap()).find(|e| e.file_name() == "file.bin").unwrap();
+			assert_eq!(entry.len(), 8);
+			assert_eq!(entry.modified(), fat_date_time(modified));
+			assert_eq!(entry.created().date, fat_date_time(modified).date);
+		}
+		
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
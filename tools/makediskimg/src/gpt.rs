@@ -1,104 +1,632 @@
-use std::{cmp, error, mem};
-use std::fs::File;
+use std::{cmp, error, fmt, io, ops, str};
 use std::hash::Hasher;
 use std::io::{Read, Seek, SeekFrom, Write};
 
-use byteorder::{LE, WriteBytesExt};
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
 use crc::{crc32, Hasher32};
+use serde::Serialize;
 
+use crate::Error;
+pub use crate::guid::Guid;
+use crate::blockdev::{BlockDevice, read_up_to};
 use crate::mbr::{self, MasterBootRecord};
 
 pub const DEFAULT_BLOCK_SIZE: usize = 512;
 
-pub type Guid = uuid::Uuid;
+/// Default alignment of partition starts in bytes.
+pub const DEFAULT_PARTITION_ALIGNMENT: u64 = 1024 * 1024;
+
+/// The `"EFI PART"` signature every gpt header starts with.
+pub const GPT_HEADER_SIGNATURE: u64 = 0x5452415020494645;
+
+/// Block sizes probed by [`GptDisk::read_from`] when looking for the primary header.
+const PROBE_BLOCK_SIZES: [u32; 2] = [512, 4096];
+
+/// Blocks of partition content written at once
+const CONTENT_BUFFER_BLOCKS: usize = 256;
+
 pub type Utf16LEChar = u16;
 
+/// Partition type guids, see [`type_name`](partition_types::type_name) for their names.
 pub mod partition_types {
 	use super::Guid;
 	
-	pub const UNUSED: Guid = Guid::from_bytes([0 as u8; 16]);
+	pub const UNUSED: Guid = Guid::nil();
+	
 	pub const EFI_SYSTEM: Guid = Guid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B);
+	pub const BIOS_BOOT: Guid = Guid::from_u128(0x21686148_6449_6E6F_744E_656564454649);
+	pub const MBR_PARTITION_SCHEME: Guid = Guid::from_u128(0x024DEE41_33E7_11D3_9D69_0008C781F39F);
+	
+	pub const MICROSOFT_RESERVED: Guid = Guid::from_u128(0xE3C9E316_0B5C_4DB8_817D_F92DF00215AE);
+	pub const MICROSOFT_BASIC_DATA: Guid = Guid::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7);
+	pub const WINDOWS_RECOVERY: Guid = Guid::from_u128(0xDE94BBA4_06D1_4D40_A16A_BFD50179D6AC);
+	
+	pub const LINUX_FILESYSTEM: Guid = Guid::from_u128(0x0FC63DAF_8483_4772_8E79_3D69D8477DE4);
+	pub const LINUX_SWAP: Guid = Guid::from_u128(0x0657FD6D_A4AB_43C4_84E5_0933C84B4F4F);
+	pub const LINUX_LVM: Guid = Guid::from_u128(0xE6D6D379_F507_44C2_A23C_238F2A3DF928);
+	pub const LINUX_RAID: Guid = Guid::from_u128(0xA19D880F_05FC_4D3B_A006_743F0F84911E);
+	pub const LINUX_ROOT_X86_64: Guid = Guid::from_u128(0x4F68BCE3_E8CD_4DB1_96E7_FBCAF984B709);
+	pub const LINUX_HOME: Guid = Guid::from_u128(0x933AC7E1_2EB4_4F13_B844_0E14E2AEF915);
+	pub const LINUX_EXTENDED_BOOT: Guid = Guid::from_u128(0xBC13C2FF_59E6_4262_A352_B275FD6F7172);
+	
+	pub const APPLE_HFS_PLUS: Guid = Guid::from_u128(0x48465300_0000_11AA_AA11_00306543ECAC);
+	pub const APPLE_APFS: Guid = Guid::from_u128(0x7C3457EF_0000_11AA_AA11_00306543ECAC);
+	
+	/// The bootstash, holds the kernel and whatever else is needed to boot
+	pub const NELL_BOOT: Guid = Guid::from_u128(0x77FFD558_C91D_42E0_B03D_7F1EFD959111);
+	/// Space set aside for the system, not formatted
+	pub const NELL_RESERVE: Guid = Guid::from_u128(0xB1B4F036_46AA_48BF_8A48_4DEA3AC714D1);
+	pub const NELL_SYSTEM: Guid = Guid::from_u128(0x7928FFAD_306C_41E9_8CB4_1E77E9DEC2FB);
+	pub const NELL_USER: Guid = Guid::from_u128(0x3381DDDB_663D_439E_9DE1_B0E2A261FE60);
+	
+	/// Every type guid above with its name.
+	pub const KNOWN: &[(Guid, &str)] = &[
+		(UNUSED, "Unused"),
+		(EFI_SYSTEM, "EFI System"),
+		(BIOS_BOOT, "BIOS boot"),
+		(MBR_PARTITION_SCHEME, "MBR partition scheme"),
+		(MICROSOFT_RESERVED, "Microsoft reserved"),
+		(MICROSOFT_BASIC_DATA, "Microsoft basic data"),
+		(WINDOWS_RECOVERY, "Windows recovery environment"),
+		(LINUX_FILESYSTEM, "Linux filesystem"),
+		(LINUX_SWAP, "Linux swap"),
+		(LINUX_LVM, "Linux LVM"),
+		(LINUX_RAID, "Linux RAID"),
+		(LINUX_ROOT_X86_64, "Linux root (x86-64)"),
+		(LINUX_HOME, "Linux /home"),
+		(LINUX_EXTENDED_BOOT, "Linux extended boot"),
+		(APPLE_HFS_PLUS, "Apple HFS+"),
+		(APPLE_APFS, "Apple APFS"),
+		(NELL_BOOT, "Nell boot"),
+		(NELL_RESERVE, "Nell reserve"),
+		(NELL_SYSTEM, "Nell system"),
+		(NELL_USER, "Nell user"),
+	];
+	
+	/// The name of a well-known type guid, `None` for unknown ones.
+	pub fn type_name(guid: Guid) -> Option<&'static str> {
+		KNOWN.iter()
+			.find(|(known, _)| *known == guid)
+			.map(|(_, name)| *name)
+	}
 }
 
-// DEBUG:
+#[cfg(test)]
 mod tests {
+	use std::io::{Seek, SeekFrom, Write};
+	
+	use super::*;
+	use crate::memdisk::MemDisk;
+	use crate::test_util::{mem_image, write_gpt};
+	
+	fn make_test_disk() -> GptDisk {
+		let mut disk = GptDisk::new_empty(512, 16384, None).unwrap();
+		
+		let name = PartitionName::new("Test Part").unwrap();
+		disk.create_partition(CreatePartitionOptions::new(partition_types::EFI_SYSTEM, None, 100, GptPartitionAttribs::zero(), name)).unwrap();
+		disk.create_partition(CreatePartitionOptions::new(partition_types::NELL_BOOT, None, 200, GptPartitionAttribs::type_specific(12), name)).unwrap();
+		disk
+	}
+	
 	#[test]
-	pub fn uuid_from_u128_endian() {
-		assert_eq!(
-			super::partition_types::EFI_SYSTEM,
-			uuid::Uuid::parse_str("C12A7328-F81F-11D2-BA4B-00A0C93EC93B").unwrap()			
-		);
+	pub fn type_names() {
+		assert_eq!(partition_types::type_name(partition_types::EFI_SYSTEM), Some("EFI System"));
+		assert_eq!(partition_types::type_name("77ffd558-c91d-42e0-b03d-7f1efd959111".parse().unwrap()), Some("Nell boot"));
+		assert_eq!(partition_types::type_name(Guid::from_u128(1)), None);
+		
+		// Every guid has exactly one name
+		for (i, (guid, _)) in partition_types::KNOWN.iter().enumerate() {
+			assert!(partition_types::KNOWN[i + 1..].iter().all(|(other, _)| other != guid), "{} is listed twice", guid);
+		}
+	}
+	
+	#[test]
+	pub fn read_back_written_disk() {
+		let disk = make_test_disk();
+		let mut file = write_gpt(&disk);
+		
+		let read = GptDisk::read_from(&mut file).unwrap();
+		assert_eq!(read.block_size(), 512);
+		assert_eq!(read.disk_size_lba(), disk.disk_size_lba());
+		assert_eq!(read.primary_header(), disk.primary_header());
+		assert_eq!(read.backup_header(), disk.backup_header());
+		assert_eq!(read.partitions().collect::<Vec<_>>(), disk.partitions().collect::<Vec<_>>());
+	}
+	
+	#[test]
+	pub fn read_falls_back_to_backup_header() {
+		let disk = make_test_disk();
+		let mut file = write_gpt(&disk);
+		
+		// Trash the primary header crc
+		file.seek(SeekFrom::Start(512 + 16)).unwrap();
+		file.write_all(&[0xDE, 0xAD, 0xBE, 0xEF]).unwrap();
+		
+		let read = GptDisk::read_from(&mut file).unwrap();
+		assert_eq!(read.primary_header(), disk.primary_header());
+		assert_eq!(read.partitions().count(), 2);
+		
+		// Intact primary header pointing to an array past the end of the disk
+		for start_lba in [disk.disk_size_lba() + 100, u64::MAX - 1].iter().copied() {
+			let mut header = disk.primary_header().clone();
+			header.partition_array_start_lba = start_lba;
+			let array_crc = read_header_at(&mut file, 512, disk.backup_header().my_lba).unwrap().partition_array_crc32;
+			file.seek(SeekFrom::Start(512)).unwrap();
+			header.write_to(&mut file, array_crc).unwrap();
+			
+			let read = GptDisk::read_from(&mut file).unwrap();
+			assert_eq!(read.partitions().collect::<Vec<_>>(), disk.partitions().collect::<Vec<_>>());
+		}
+	}
+	
+	fn options(size_in_lba: u64) -> CreatePartitionOptions {
+		CreatePartitionOptions::new(partition_types::EFI_SYSTEM, None, size_in_lba, GptPartitionAttribs::zero(), PartitionName::empty())
+	}
+	
+	#[test]
+	pub fn allocator_aligns_to_mib() {
+		let disk = make_test_disk();
+		let starts = disk.partitions().map(|p| p.start_lba).collect::<Vec<_>>();
+		assert_eq!(starts, vec![2048, 4096]);
+	}
+	
+	#[test]
+	pub fn allocator_fills_first_gap() {
+		let mut disk = GptDisk::new_empty(512, 16384, None).unwrap();
+		disk.create_partition(options(100).start_lba(6144)).unwrap();
+		
+		// Fits in front of the explicitly placed partition
+		assert_eq!(disk.create_partition(options(4096)).unwrap().start_lba, 2048);
+		// Doesn't fit anymore, goes behind it
+		assert_eq!(disk.create_partition(options(10)).unwrap().start_lba, 8192);
+		// Unaligned
+		assert_eq!(disk.create_partition(options(10).alignment_lba(1)).unwrap().start_lba, 34);
 	}
 	
 	#[test]
-	pub fn uuid_to_guid_mixed_endian() {
+	pub fn allocator_reports_errors() {
+		let mut disk = GptDisk::new_empty(512, 16384, None).unwrap();
+		disk.create_partition(options(100)).unwrap();
+		
+		assert_eq!(disk.create_partition(options(0)).unwrap_err(), PartitionError::ZeroSize);
+		assert_eq!(disk.create_partition(options(20000)).unwrap_err(), PartitionError::NoSpace {size_in_lba: 20000});
 		assert_eq!(
-			super::uuid_to_guid_mixed_endian(super::partition_types::EFI_SYSTEM),
-			u128::from_ne_bytes(*b"\x28\x73\x2A\xC1\x1F\xF8\xD2\x11\xBA\x4B\x00\xA0\xC9\x3E\xC9\x3B")
+			disk.create_partition(options(10).start_lba(2050)).unwrap_err(),
+			PartitionError::Overlap {start_lba: 2050, end_lba_incl: 2059, other: 0}
 		);
+		assert_eq!(
+			disk.create_partition(options(10).start_lba(1)).unwrap_err(),
+			PartitionError::OutsideUsableRange {start_lba: 1, end_lba_incl: 10}
+		);
+		assert_eq!(disk.partitions().count(), 1);
+	}
+	
+	#[test]
+	pub fn remove_and_reorder_partitions() {
+		let mut disk = GptDisk::new_empty(512, 16384, None).unwrap();
+		for size in [10, 20, 30].iter().copied() {
+			disk.create_partition(options(size)).unwrap();
+		}
+		
+		let removed = disk.remove_partition(1).unwrap();
+		assert_eq!(removed.size_in_lba, 20);
+		assert_eq!(disk.primary_header().num_partition_entries, 128);
+		assert_eq!(disk.backup_header().num_partition_entries, 128);
+		assert_eq!(disk.remove_partition(2).unwrap_err(), PartitionError::NoSuchPartition {index: 2});
+		
+		disk.set_partition_index(1, 0).unwrap();
+		let sizes = disk.partitions().map(|p| p.size_in_lba).collect::<Vec<_>>();
+		assert_eq!(sizes, vec![30, 10]);
+	}
+	
+	#[test]
+	pub fn resize_and_move_partitions() {
+		let mut disk = GptDisk::new_empty(512, 16384, None).unwrap();
+		disk.create_partition(options(100)).unwrap();
+		disk.create_partition(options(100)).unwrap();
+		
+		// Grow into the gap up to the next partition, but not beyond
+		disk.resize_partition(0, 2048).unwrap();
+		assert_eq!(disk.partition(0).unwrap().end_lba_incl, 4095);
+		assert!(matches!(disk.resize_partition(0, 2049), Err(PartitionError::Overlap {other: 1, ..})));
+		
+		// Shrink
+		disk.resize_partition(0, 50).unwrap();
+		assert_eq!(disk.partition(0).unwrap().end_lba_incl, 2097);
+		
+		// Move behind the second partition
+		disk.move_partition(0, 8192).unwrap();
+		assert_eq!((disk.partition(0).unwrap().start_lba, disk.partition(0).unwrap().end_lba_incl), (8192, 8241));
+		assert!(matches!(disk.move_partition(0, 4100), Err(PartitionError::Overlap {other: 1, ..})));
+		
+		// Ends beyond u64::MAX are outside the usable range
+		assert_eq!(disk.move_partition(0, u64::MAX).unwrap_err(), PartitionError::OutsideUsableRange {start_lba: u64::MAX, end_lba_incl: u64::MAX});
+		assert_eq!(disk.resize_partition(0, u64::MAX).unwrap_err(), PartitionError::OutsideUsableRange {start_lba: 8192, end_lba_incl: u64::MAX});
+		assert_eq!(disk.partition(0).unwrap().end_lba_incl, 8241);
+		
+		// Sizes whose end overflows don't fit anywhere
+		assert_eq!(disk.create_partition(options(u64::MAX)).unwrap_err(), PartitionError::NoSpace {size_in_lba: u64::MAX});
+		assert_eq!(disk.create_partition(options(u64::MAX - 2047)).unwrap_err(), PartitionError::NoSpace {size_in_lba: u64::MAX - 2047});
+		
+		let mut file = write_gpt(&disk);
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+	}
+	
+	#[test]
+	pub fn rejects_too_small_disk() {
+		// Mbr, two headers and two 32 lba arrays, plus one usable lba
+		assert_eq!(PartitionArrayLayout::STANDARD.min_disk_size_lba(512), 68);
+		assert!(matches!(GptDisk::new_empty(512, 67, None), Err(PartitionError::DiskTooSmall {min_size_lba: 68})));
+		assert_eq!(GptDisk::new_empty(512, 68, None).unwrap().primary_header().last_usable_lba, 34);
+		assert!(matches!(GptDisk::new_empty(512, 2, None), Err(PartitionError::DiskTooSmall {..})));
+	}
+	
+	#[test]
+	pub fn resize_disk_to_fit() {
+		let mut disk = GptDisk::new_empty(512, 1 << 30, None).unwrap();
+		disk.create_partition(options(100)).unwrap();
+		disk.create_partition(options(100)).unwrap();
+		
+		// Second partition ends at 4195, followed by the backup array and header
+		assert_eq!(disk.min_disk_size_lba(), 4196 + 33);
+		assert_eq!(disk.resize_disk(4196 + 32), Err(PartitionError::DiskTooSmall {min_size_lba: 4196 + 33}));
+		
+		disk.resize_disk(4196 + 33).unwrap();
+		assert_eq!(disk.primary_header().last_usable_lba, 4195);
+		assert_eq!(disk.backup_header().my_lba, 4196 + 32);
+		
+		let mut file = write_gpt(&disk);
+		assert_eq!(file.size(), (4196 + 33) * 512);
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+	}
+	
+	#[test]
+	pub fn standard_partition_array() {
+		let disk = make_test_disk();
+		assert_eq!(disk.primary_header().first_usable_lba, 34);
+		assert_eq!(disk.primary_header().last_usable_lba, 16384 - 34);
+		assert_eq!(disk.backup_header().partition_array_start_lba, 16384 - 33);
+		assert_eq!(disk.partition_array_bytes().len(), 16384);
+		
+		let mut file = write_gpt(&disk);
+		let read = GptDisk::read_from(&mut file).unwrap();
+		assert_eq!(read.partition_array_layout(), PartitionArrayLayout::STANDARD);
+		assert_eq!(read.partitions().count(), 2);
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+	}
+	
+	#[test]
+	pub fn custom_partition_array() {
+		let array = PartitionArrayLayout {num_entries: 4, entry_size: 256};
+		let mut disk = GptDisk::new_empty_with_array(512, 16384, None, array).unwrap();
+		assert_eq!(disk.primary_header().first_usable_lba, 4);
+		assert_eq!(disk.backup_header().partition_array_start_lba, 16383 - 2);
+		
+		for _ in 0..4 {
+			disk.create_partition(options(10)).unwrap();
+		}
+		assert_eq!(disk.create_partition(options(10)).unwrap_err(), PartitionError::PartitionArrayFull);
+		
+		let mut file = write_gpt(&disk);
+		let read = GptDisk::read_from(&mut file).unwrap();
+		assert_eq!(read.partition_array_layout(), array);
+		assert_eq!(read.partitions().collect::<Vec<_>>(), disk.partitions().collect::<Vec<_>>());
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+	}
+	
+	#[test]
+	pub fn native_4k_disk() {
+		let mut disk = GptDisk::new_empty(4096, 4096, None).unwrap();
+		assert_eq!(disk.primary_header().first_usable_lba, 6);
+		assert_eq!(disk.primary_header().last_usable_lba, 4096 - 6);
+		assert_eq!(disk.backup_header().partition_array_start_lba, 4096 - 5);
+		
+		// 1 MiB alignment is 256 blocks
+		assert_eq!(disk.create_partition(options(100)).unwrap().start_lba, 256);
+		
+		let mut file = write_gpt(&disk);
+		assert_eq!(file.size(), 4096 * 4096);
+		
+		let read = GptDisk::read_from(&mut file).unwrap();
+		assert_eq!(read.block_size(), 4096);
+		assert_eq!(read.primary_header(), disk.primary_header());
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+	}
+	
+	#[test]
+	pub fn attribs_display_parse_round_trip() {
+		let attribs = GptPartitionAttribs::REQUIRED_PARTITION
+			| GptPartitionAttribs::LEGACY_BIOS_BOOTABLE
+			| GptPartitionAttribs::type_specific(12)
+			| GptPartitionAttribs::from_bits(1 << 20);
+		assert_eq!(attribs.bits(), 0x1000_0000_0010_0005);
+		assert_eq!(attribs.type_specific_bits(), 0x1000);
+		assert_eq!(attribs.to_string(), "required,legacy_bios_bootable,bit:20,type:12");
+		assert_eq!(attribs.to_string().parse::<GptPartitionAttribs>().unwrap(), attribs);
+		
+		assert_eq!(GptPartitionAttribs::zero().to_string(), "none");
+		assert_eq!("none".parse::<GptPartitionAttribs>().unwrap(), GptPartitionAttribs::zero());
+		assert!("type:16".parse::<GptPartitionAttribs>().is_err());
+		assert!("bogus".parse::<GptPartitionAttribs>().is_err());
+	}
+	
+	#[test]
+	pub fn partition_names() {
+		let name = PartitionName::new("Nell Boot").unwrap();
+		assert_eq!(name.as_utf16().len(), 9);
+		assert_eq!(name.decode().unwrap(), "Nell Boot");
+		
+		// Non-bmp characters take two code units
+		let name = PartitionName::new("Nell \u{1F980}").unwrap();
+		assert_eq!(name.as_utf16().len(), 7);
+		assert_eq!(name.to_string(), "Nell \u{1F980}");
+		
+		// Exactly 36 code units, no terminator
+		let long = "x".repeat(36);
+		assert_eq!(PartitionName::new(&long).unwrap().decode().unwrap(), long);
+		assert_eq!(PartitionName::new(&"x".repeat(37)).unwrap_err(), PartitionNameError::TooLong {len: 37});
+		assert_eq!(PartitionName::new("a\0b").unwrap_err(), PartitionNameError::ContainsNul {index: 1});
+		assert_eq!(PartitionName::from_utf16(&[0x61, 0xD800, 0x62]).unwrap_err(), PartitionNameError::UnpairedSurrogate {index: 1});
+		
+		let mut raw = [0u16; 36];
+		raw[..2].copy_from_slice(&[0x61, 0xDC00]);
+		assert_eq!(PartitionName::from_raw(raw).decode().unwrap_err(), PartitionNameError::UnpairedSurrogate {index: 1});
+	}
+	
+	#[test]
+	pub fn crcs_follow_mutations() {
+		let mut disk = make_test_disk();
+		disk.partitions[0].partition_name = "Renamed".parse().unwrap();
+		let mut file = write_gpt(&disk);
+		
+		let read = GptDisk::read_from(&mut file).unwrap();
+		assert_eq!(read.partitions().next().unwrap().partition_name, disk.partitions[0].partition_name);
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+	}
+	
+	#[test]
+	pub fn integrity_of_written_disk() {
+		let disk = make_test_disk();
+		let mut file = write_gpt(&disk);
+		
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+	}
+	
+	#[test]
+	pub fn integrity_reports_broken_disk() {
+		let mut disk = make_test_disk();
+		disk.partitions[1].start_lba = disk.partitions[0].end_lba_incl;
+		disk.partitions[1].end_lba_incl = disk.disk_size_lba;
+		let mut file = write_gpt(&disk);
+		
+		// Trash the mbr signature and the primary header crc
+		file.seek(SeekFrom::Start(510)).unwrap();
+		file.write_all(&[0, 0]).unwrap();
+		file.seek(SeekFrom::Start(512 + 16)).unwrap();
+		file.write_all(&[0xDE, 0xAD, 0xBE, 0xEF]).unwrap();
+		
+		let issues = GptDisk::check_integrity(&mut file).unwrap();
+		assert!(issues.contains(&IntegrityIssue::MbrBadSignature {found: 0}));
+		assert!(issues.iter().any(|i| matches!(i, IntegrityIssue::HeaderCrcMismatch {header: HeaderKind::Primary, stored: 0xEFBEADDE, ..})));
+		assert!(issues.iter().any(|i| matches!(i, IntegrityIssue::PartitionOutsideUsableRange {index: 1, ..})));
+		assert!(issues.contains(&IntegrityIssue::PartitionsOverlap {first: 0, second: 1}));
+		assert!(!issues.iter().any(|i| matches!(i, IntegrityIssue::HeaderCrcMismatch {header: HeaderKind::Backup, ..})));
+	}
+	
+	#[test]
+	pub fn integrity_survives_garbage_header() {
+		let disk = make_test_disk();
+		let mut file = write_gpt(&disk);
+		
+		// Lbas and sizes far beyond the disk
+		file.seek(SeekFrom::Start(512)).unwrap();
+		file.write_all(&[0xFF; 512]).unwrap();
+		
+		let issues = GptDisk::check_integrity(&mut file).unwrap();
+		assert!(issues.contains(&IntegrityIssue::BadSignature {header: HeaderKind::Primary, found: u64::MAX}));
+		assert!(issues.contains(&IntegrityIssue::UsableRangeOverlapsGpt {header: HeaderKind::Primary}));
+		assert!(!issues.iter().any(|i| matches!(i, IntegrityIssue::BadSignature {header: HeaderKind::Backup, ..})));
+		
+		// Neither header left
+		file.seek(SeekFrom::Start((disk.disk_size_lba() - 1) * 512)).unwrap();
+		file.write_all(&[0xFF; 512]).unwrap();
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![IntegrityIssue::NoGptHeader]);
+		assert_eq!(GptDisk::check_integrity(MemDisk::new_sparse(16384 * 512)).unwrap(), vec![IntegrityIssue::NoGptHeader]);
+	}
+	
+	#[test]
+	pub fn garbage_partition_entries() {
+		// Entries are only checked, not trusted, so the arithmetic on them mustn't overflow
+		for (start_lba, end_lba_incl) in [(2048, u64::MAX), (u64::MAX, 2048), (3000, 2048)].iter().copied() {
+			let mut disk = make_test_disk();
+			disk.partitions[0].start_lba = start_lba;
+			disk.partitions[0].end_lba_incl = end_lba_incl;
+			let mut file = write_gpt(&disk);
+			
+			let issues = GptDisk::check_integrity(&mut file).unwrap();
+			assert!(issues.iter().any(|i| matches!(i, IntegrityIssue::PartitionOutsideUsableRange {index: 0, ..} | IntegrityIssue::PartitionEndsBeforeStart {index: 0, ..})), "{:?}", issues);
+			
+			let read = GptDisk::read_from(&mut file).unwrap();
+			assert_eq!(read.partition(0).unwrap().end_lba_incl, end_lba_incl);
+			
+			let report = crate::inspect::inspect(&mut file).unwrap();
+			assert!(report.partitions[0].filesystem.is_none());
+			assert!(!report.issues.is_empty());
+		}
+	}
+	
+	#[test]
+	pub fn integrity_of_hybrid_mbr_disk() {
+		let mut disk = make_test_disk();
+		disk.partitions[0].attributes.insert(GptPartitionAttribs::LEGACY_BIOS_BOOTABLE);
+		
+		let mut writer = disk.writer(mem_image(&disk));
+		writer.write_hybrid_mbr(&[(0, mbr::os_types::UEFI_SYSTEM), (1, mbr::os_types::FAT32_LBA)], &[0xFA, 0xEB]).unwrap();
+		writer.write_gpt_header(true).unwrap();
+		writer.write_gpt_header(false).unwrap();
+		let mut file = writer.flush().unwrap().into_inner();
+		
+		let mut raw = [0u8; 512];
+		file.seek(SeekFrom::Start(0)).unwrap();
+		file.read_exact(&mut raw).unwrap();
+		assert_eq!(&raw[..2], &[0xFA, 0xEB]);
+		assert_eq!(raw[446 + 16], 0x80); // Bootable esp mirror
+		assert_eq!(raw[446 + 16 + 4], mbr::os_types::UEFI_SYSTEM);
+		
+		assert_eq!(GptDisk::check_integrity(&mut file).unwrap(), vec![]);
+		assert!(disk.writer(mem_image(&disk)).write_hybrid_mbr(&[(2, mbr::os_types::FAT32_LBA)], &[]).is_err());
 	}
 }
 
 pub struct GptDisk {
 	block_size: u32,
 	disk_size_lba: u64,
+	alignment_lba: u64,
 	partitions: Vec<GptPartition>,
 	primary_header: GptHeader,
 	backup_header: GptHeader,
 }
 
 impl GptDisk {
-	/// Create a new, empty disk
-	pub fn new_empty(block_size: u32, disk_size_lba: u64, disk_guid: Option<Guid>) -> GptDisk {
+	/// Create a new, empty disk with a standard 128 entry partition array
+	pub fn new_empty(block_size: u32, disk_size_lba: u64, disk_guid: Option<Guid>) -> Result<GptDisk, PartitionError> {
+		Self::new_empty_with_array(block_size, disk_size_lba, disk_guid, PartitionArrayLayout::STANDARD)
+	}
+	
+	/// Create a new, empty disk with a custom partition array layout.
+	/// The usable lba range and the backup array location are derived from it.
+	///
+	/// Fails if the disk is too small to hold both gpt copies (see [`PartitionArrayLayout::min_disk_size_lba`]).
+	///
+	/// # Panics
+	/// If the entry size isn't a power of two of at least 128 bytes.
+	pub fn new_empty_with_array(block_size: u32, disk_size_lba: u64, disk_guid: Option<Guid>, array: PartitionArrayLayout) -> Result<GptDisk, PartitionError> {
+		assert!(array.entry_size >= 128 && array.entry_size.is_power_of_two(), "invalid partition entry size {}", array.entry_size);
+		
 		let real_disk_guid = disk_guid
-			.unwrap_or_else(|| Guid::new_v4());
+			.unwrap_or_else(Guid::new_v4);
+		
+		let min_size_lba = array.min_disk_size_lba(block_size);
+		if disk_size_lba < min_size_lba {
+			return Err(PartitionError::DiskTooSmall {min_size_lba});
+		}
+		let array_lba = array.size_lba(block_size);
 		
 		let primary_header_lba = 1;
 		let backup_header_lba = disk_size_lba - 1;
 		
 		// Make primary header
-		let mut primary_header = GptHeader {
-			signature: 0x5452415020494645,
+		let primary_header = GptHeader {
+			signature: GPT_HEADER_SIGNATURE,
 			revision: 0x00010000,
 			header_size: 92,
-			header_crc32: 0,
 			my_lba: primary_header_lba,
 			alternate_lba: backup_header_lba,
-			first_usable_lba: 34, // DEBUG: Temp
-			last_usable_lba: disk_size_lba - 1 - 33,
+			first_usable_lba: 2 + array_lba,
+			last_usable_lba: backup_header_lba - array_lba - 1,
 			disk_guid: real_disk_guid,
 			partition_array_start_lba: 2,
-			num_partition_entries: 0,
-			partition_entry_size: 128,
-			partition_array_crc32: 0,
+			num_partition_entries: array.num_entries,
+			partition_entry_size: array.entry_size,
 		};
-		primary_header.update_header_crc32();
 		
 		// Make backup header
-		let mut backup_header = primary_header.clone();
-		backup_header.my_lba = backup_header_lba;
-		backup_header.alternate_lba = primary_header_lba;
-		backup_header.partition_array_start_lba = disk_size_lba - 33;
-		backup_header.update_header_crc32();
+		let backup_header = primary_header.mirrored(backup_header_lba, backup_header_lba - array_lba);
 		
 		// Make disk object
-		GptDisk {
+		Ok(GptDisk {
 			block_size,
 			disk_size_lba,
+			alignment_lba: default_alignment_lba(block_size),
 			primary_header,
 			backup_header,
 			partitions: Vec::new(),
+		})
+	}
+	
+	/// Reads an existing gpt disk back from the given image.
+	///
+	/// The block size is probed by looking for the primary header signature.
+	/// If the primary header or its partition array is damaged the backup copy is used instead,
+	/// a damaged backup header is rebuilt from the primary one.
+	/// Unused partition entries are skipped, so the partitions end up compacted in array order.
+	pub fn read_from(mut reader: impl Read + Seek) -> io::Result<GptDisk> {
+		let disk_size_bytes = reader.seek(SeekFrom::End(0))?;
+		
+		let block_size = probe_block_size(&mut reader, disk_size_bytes)?
+			.ok_or_else(|| invalid_data("no gpt header signature found"))?;
+		let disk_size_lba = disk_size_bytes / block_size as u64;
+		
+		// Read both headers
+		let primary = read_header_at(&mut reader, block_size, 1).ok()
+			.filter(|h| h.is_valid());
+		let backup_lba = primary.as_ref().map_or(disk_size_lba - 1, |h| h.header.alternate_lba);
+		let backup = read_header_at(&mut reader, block_size, backup_lba).ok()
+			.filter(|h| h.is_valid());
+		
+		// Read the partition array of whichever header has an intact one
+		let mut partitions = None;
+		for h in primary.iter().chain(backup.iter()) {
+			// An array that can't be read counts as damaged
+			let (parts, crc) = match read_partition_array(&mut reader, block_size, &h.header) {
+				Ok(array) => array,
+				Err(_) => continue,
+			};
+			if crc == h.partition_array_crc32 {
+				partitions = Some(parts);
+				break;
+			}
 		}
+		let partitions = partitions.ok_or_else(|| invalid_data("no intact gpt header and partition array found"))?;
+		
+		// Rebuild a missing header from the other one
+		let (primary_header, backup_header) = match (primary.map(|h| h.header), backup.map(|h| h.header)) {
+			(Some(p), Some(b)) => (p, b),
+			(Some(p), None) => {
+				let b = p.mirrored(disk_size_lba - 1, disk_size_lba - 1 - array_size_lba(&p, block_size));
+				(p, b)
+			}
+			(None, Some(b)) => {
+				let p = b.mirrored(1, 2);
+				(p, b)
+			}
+			(None, None) => unreachable!(),
+		};
+		
+		Ok(GptDisk {
+			block_size,
+			disk_size_lba,
+			alignment_lba: default_alignment_lba(block_size),
+			partitions: partitions.into_iter()
+				.filter(|p| p.partition_type_guid != partition_types::UNUSED)
+				.collect(),
+			primary_header,
+			backup_header,
+		})
 	}
 	
-	pub fn create_partition(&mut self, options: CreatePartitionOptions) -> Option<&mut GptPartition> {
+	/// Creates a new partition and appends it to the partition array.
+	///
+	/// Without an explicit start lba the partition is put into the first free gap
+	/// big enough for it, starting at a multiple of the disk's partition alignment.
+	pub fn create_partition(&mut self, options: CreatePartitionOptions) -> Result<&mut GptPartition, PartitionError> {
+		let size_in_lba = options.size_in_lba;
+		if size_in_lba == 0 {
+			return Err(PartitionError::ZeroSize);
+		}
+		if self.partitions.len() >= self.primary_header.num_partition_entries as usize {
+			return Err(PartitionError::PartitionArrayFull);
+		}
+		
 		// Find block position
-		// DEBUG: For now always start at the first usable lba or the end of the prev partition
-//		let start_lba = self.primary_header.first_usable_lba;
-		let part_padding = 1; // DEBUG: Test padding in lba between partitions, remove later
-		let start_lba = self.partitions
-			.last().map_or(self.primary_header.first_usable_lba, |p| p.end_lba_incl + 1 + part_padding);
-		let end_lba_incl = start_lba + options.size_in_lba.saturating_sub(1); // TODO: Right now a partition will always have the size of atleast one lba, even if requested size was 0... But what should we do in that case anyways?
+		let (start_lba, end_lba_incl) = match options.start_lba {
+			Some(start_lba) => (start_lba, self.check_placement(start_lba, size_in_lba, None)?),
+			None => {
+				let start_lba = self.find_free_space(size_in_lba, options.alignment_lba.unwrap_or(self.alignment_lba), None)
+					.ok_or(PartitionError::NoSpace {size_in_lba})?;
+				(start_lba, start_lba + size_in_lba - 1)
+			}
+		};
 		
 		// Create partition
 		let mut partition = GptPartition::new(
@@ -114,32 +642,232 @@ impl GptDisk {
 		// Add partition to list
 		self.partitions.push(partition);
 		
-		// Update headers
-		let num_parts = self.partitions.len() as u32;
-		self.primary_header.num_partition_entries = num_parts;
-		self.backup_header.num_partition_entries = num_parts;
-		
-//		// Set layout
-//		let layout = GptPartitionLayout {
-//			start_lba,
-//			end_lba,
-//		};
-//		partition.disk_layout = layout;
-//		
-//		// Add partition to list
-//		self.partitions.push(partition);
-//		
-//		// Update partition count
-//		let num_parts = self.partitions.len() as u32;
-//		self.primary_header.num_partition_entries = num_parts;
-//		self.backup_header.num_partition_entries = num_parts;
-		
-		None
-	}
-	
-	// TODO:
-	#[deprecated]
-	pub fn check_integrity(&self) {}
+		Ok(self.partitions.last_mut().unwrap())
+	}
+	
+	/// Removes the partition at the given array index, moving all following entries down by one.
+	/// The freed entry at the end of the array is zeroed.
+	pub fn remove_partition(&mut self, index: usize) -> Result<GptPartition, PartitionError> {
+		self.check_index(index)?;
+		
+		Ok(self.partitions.remove(index))
+	}
+	
+	/// Grows or shrinks a partition in place, keeping its start lba.
+	/// Growing only succeeds if the space behind the partition is free.
+	pub fn resize_partition(&mut self, index: usize, new_size_in_lba: u64) -> Result<(), PartitionError> {
+		self.check_index(index)?;
+		if new_size_in_lba == 0 {
+			return Err(PartitionError::ZeroSize);
+		}
+		
+		let start_lba = self.partitions[index].start_lba;
+		let end_lba_incl = self.check_placement(start_lba, new_size_in_lba, Some(index))?;
+		
+		let partition = &mut self.partitions[index];
+		partition.size_in_lba = new_size_in_lba;
+		partition.end_lba_incl = end_lba_incl;
+		
+		Ok(())
+	}
+	
+	/// Moves a partition to a new start lba, keeping its size.
+	/// Only the partition entry is changed, moving the content is up to the caller.
+	pub fn move_partition(&mut self, index: usize, new_start_lba: u64) -> Result<(), PartitionError> {
+		self.check_index(index)?;
+		
+		let size_in_lba = self.partitions[index].size_in_lba;
+		let end_lba_incl = self.check_placement(new_start_lba, size_in_lba, Some(index))?;
+		
+		let partition = &mut self.partitions[index];
+		partition.start_lba = new_start_lba;
+		partition.end_lba_incl = end_lba_incl;
+		
+		Ok(())
+	}
+	
+	/// Moves a partition entry to another position in the partition array,
+	/// shifting the entries in between. Doesn't touch the on-disk layout.
+	pub fn set_partition_index(&mut self, index: usize, new_index: usize) -> Result<(), PartitionError> {
+		self.check_index(index)?;
+		self.check_index(new_index)?;
+		
+		let partition = self.partitions.remove(index);
+		self.partitions.insert(new_index, partition);
+		
+		Ok(())
+	}
+	
+	fn check_index(&self, index: usize) -> Result<(), PartitionError> {
+		match index < self.partitions.len() {
+			true => Ok(()),
+			false => Err(PartitionError::NoSuchPartition {index}),
+		}
+	}
+	
+	/// Finds the first aligned gap of at least `size_in_lba` blocks in the usable range.
+	/// The partition at index `ignore` (if any) is treated as free space.
+	fn find_free_space(&self, size_in_lba: u64, alignment_lba: u64, ignore: Option<usize>) -> Option<u64> {
+		let mut occupied = self.partitions.iter().enumerate()
+			.filter(|(i, _)| Some(*i) != ignore)
+			.map(|(_, p)| (p.start_lba, p.end_lba_incl))
+			.collect::<Vec<_>>();
+		occupied.sort_unstable();
+		
+		let mut candidate = align_up(self.primary_header.first_usable_lba, alignment_lba);
+		for (start, end_incl) in occupied {
+			if candidate.checked_add(size_in_lba).is_some_and(|end| end <= start) {
+				break;
+			}
+			candidate = cmp::max(candidate, align_up(end_incl + 1, alignment_lba));
+		}
+		
+		// An end beyond u64::MAX doesn't fit either
+		candidate.checked_add(size_in_lba)
+			.is_some_and(|end| end - 1 <= self.primary_header.last_usable_lba)
+			.then_some(candidate)
+	}
+	
+	/// Checks that a partition could be placed at exactly the given position, and returns its last lba.
+	/// The partition at index `ignore` (if any) is treated as free space.
+	fn check_placement(&self, start_lba: u64, size_in_lba: u64, ignore: Option<usize>) -> Result<u64, PartitionError> {
+		// An end beyond u64::MAX is reported as u64::MAX
+		let end_lba_incl = match start_lba.checked_add(size_in_lba - 1) {
+			Some(end) if start_lba >= self.primary_header.first_usable_lba && end <= self.primary_header.last_usable_lba => end,
+			end => return Err(PartitionError::OutsideUsableRange {start_lba, end_lba_incl: end.unwrap_or(u64::MAX)}),
+		};
+		
+		let overlapping = self.partitions.iter().enumerate()
+			.filter(|(i, _)| Some(*i) != ignore)
+			.find(|(_, p)| start_lba <= p.end_lba_incl && p.start_lba <= end_lba_incl);
+		if let Some((index, _)) = overlapping {
+			return Err(PartitionError::Overlap {start_lba, end_lba_incl, other: index});
+		}
+		
+		Ok(end_lba_incl)
+	}
+	
+	/// Sets the default alignment of newly created partitions, in bytes.
+	/// Values below the block size are treated as no alignment.
+	pub fn set_alignment(&mut self, alignment_bytes: u64) {
+		self.alignment_lba = cmp::max(1, alignment_bytes / self.block_size as u64);
+	}
+	
+	pub fn alignment_lba(&self) -> u64 {
+		self.alignment_lba
+	}
+	
+	/// Checks the gpt structures of the given image and reports every problem found.
+	///
+	/// Covers both headers and their crcs, the partition arrays, the partition layout
+	/// and the protective mbr. An empty list means the image is fine.
+	/// Only fails if the image can't be read at all.
+	pub fn check_integrity(mut reader: impl Read + Seek) -> io::Result<Vec<IntegrityIssue>> {
+		let mut issues = Vec::new();
+		
+		let disk_size_bytes = reader.seek(SeekFrom::End(0))?;
+		let block_size = match probe_block_size(&mut reader, disk_size_bytes)? {
+			Some(bs) => bs,
+			None => DEFAULT_BLOCK_SIZE as u32,
+		};
+		let disk_size_lba = disk_size_bytes / block_size as u64;
+		if disk_size_lba < 3 {
+			issues.push(IntegrityIssue::DiskTooSmall {disk_size_lba});
+			return Ok(issues);
+		}
+		
+		// Backup header, found through the primary one if that looks sane
+		let primary_stored = read_header_at(&mut reader, block_size, 1)?;
+		let primary = &primary_stored.header;
+		let backup_lba = if primary.alternate_lba > 1 && primary.alternate_lba < disk_size_lba {
+			primary.alternate_lba
+		} else {
+			disk_size_lba - 1
+		};
+		let backup_stored = read_header_at(&mut reader, block_size, backup_lba)?;
+		let backup = &backup_stored.header;
+		
+		// Checking the fields of whatever is there would only list garbage
+		if primary.signature != GPT_HEADER_SIGNATURE && backup.signature != GPT_HEADER_SIGNATURE {
+			issues.push(IntegrityIssue::NoGptHeader);
+			return Ok(issues);
+		}
+		
+		check_protective_mbr(&mut reader, disk_size_lba, &mut issues)?;
+		check_header(&primary_stored, HeaderKind::Primary, 1, block_size, &mut issues);
+		if backup_lba != disk_size_lba - 1 {
+			issues.push(IntegrityIssue::BackupNotAtEnd {backup_lba, expected: disk_size_lba - 1});
+		}
+		check_header(&backup_stored, HeaderKind::Backup, backup_lba, block_size, &mut issues);
+		
+		// my_lba/alternate_lba symmetry
+		if primary.alternate_lba != backup.my_lba {
+			issues.push(IntegrityIssue::AlternateLbaMismatch {header: HeaderKind::Primary, alternate_lba: primary.alternate_lba, expected: backup.my_lba});
+		}
+		if backup.alternate_lba != primary.my_lba {
+			issues.push(IntegrityIssue::AlternateLbaMismatch {header: HeaderKind::Backup, alternate_lba: backup.alternate_lba, expected: primary.my_lba});
+		}
+		
+		// Primary vs backup
+		let disagreements = [
+			("first_usable_lba", primary.first_usable_lba != backup.first_usable_lba),
+			("last_usable_lba", primary.last_usable_lba != backup.last_usable_lba),
+			("disk_guid", primary.disk_guid != backup.disk_guid),
+			("num_partition_entries", primary.num_partition_entries != backup.num_partition_entries),
+			("partition_entry_size", primary.partition_entry_size != backup.partition_entry_size),
+			("partition_array_crc32", primary_stored.partition_array_crc32 != backup_stored.partition_array_crc32),
+		];
+		for (field, _) in disagreements.iter().filter(|(_, differs)| *differs) {
+			issues.push(IntegrityIssue::HeadersDisagree {field});
+		}
+		
+		// Usable range must not overlap any gpt structure
+		let primary_array_end = primary.partition_array_start_lba.checked_add(array_size_lba(primary, block_size));
+		if primary_array_end.is_none_or(|end| primary.first_usable_lba < end) {
+			issues.push(IntegrityIssue::UsableRangeOverlapsGpt {header: HeaderKind::Primary});
+		}
+		if primary.last_usable_lba >= backup.partition_array_start_lba.min(backup_lba) {
+			issues.push(IntegrityIssue::UsableRangeOverlapsGpt {header: HeaderKind::Backup});
+		}
+		
+		// Partition arrays
+		let primary_parts = check_partition_array(&mut reader, &primary_stored, HeaderKind::Primary, block_size, &mut issues)?;
+		let backup_parts = check_partition_array(&mut reader, &backup_stored, HeaderKind::Backup, block_size, &mut issues)?;
+		if let (Some(p), Some(b)) = (&primary_parts, &backup_parts) {
+			if p != b {
+				issues.push(IntegrityIssue::PartitionArraysDisagree);
+			}
+		}
+		
+		// Partition layout
+		if let Some(parts) = primary_parts.or(backup_parts) {
+			let used = parts.iter().enumerate()
+				.filter(|(_, p)| p.partition_type_guid != partition_types::UNUSED)
+				.collect::<Vec<_>>();
+			
+			for (i, p) in used.iter().copied() {
+				if p.end_lba_incl < p.start_lba {
+					issues.push(IntegrityIssue::PartitionEndsBeforeStart {index: i, start_lba: p.start_lba, end_lba_incl: p.end_lba_incl});
+				} else if p.start_lba < primary.first_usable_lba || p.end_lba_incl > primary.last_usable_lba {
+					issues.push(IntegrityIssue::PartitionOutsideUsableRange {index: i, start_lba: p.start_lba, end_lba_incl: p.end_lba_incl});
+				}
+			}
+			
+			for (n, (i, a)) in used.iter().copied().enumerate() {
+				for (j, b) in used[n+1..].iter().copied() {
+					if a.start_lba <= b.end_lba_incl && b.start_lba <= a.end_lba_incl {
+						issues.push(IntegrityIssue::PartitionsOverlap {first: i, second: j});
+					}
+				}
+			}
+		}
+		
+		Ok(issues)
+	}
+	
+	pub fn partition(&self, index: usize) -> Option<&GptPartition> {
+		self.partitions.get(index)
+	}
 	
 	pub fn partitions<'a>(&'a self) -> PartitionIter<'a> {
 		PartitionIter {
@@ -152,6 +880,30 @@ impl GptDisk {
 		self.disk_size_lba
 	}
 	
+	/// Smallest disk size that still holds all partitions and both gpt copies.
+	pub fn min_disk_size_lba(&self) -> u64 {
+		let trailer_lba = self.disk_size_lba - 1 - self.primary_header.last_usable_lba;
+		let used_end = self.partitions.iter()
+			.map(|p| p.end_lba_incl + 1)
+			.max()
+			.unwrap_or(self.primary_header.first_usable_lba + 1);
+		used_end + trailer_lba
+	}
+	
+	/// Moves the end of the disk and with it the backup gpt, the partitions stay where they are.
+	pub fn resize_disk(&mut self, disk_size_lba: u64) -> Result<(), PartitionError> {
+		let min_size_lba = self.min_disk_size_lba();
+		if disk_size_lba < min_size_lba {
+			return Err(PartitionError::DiskTooSmall {min_size_lba});
+		}
+		
+		let resized = Self::new_empty_with_array(self.block_size, disk_size_lba, Some(self.primary_header.disk_guid), self.partition_array_layout())?;
+		self.disk_size_lba = disk_size_lba;
+		self.primary_header = resized.primary_header;
+		self.backup_header = resized.backup_header;
+		Ok(())
+	}
+	
 	pub fn block_size(&self) -> u32 {
 		self.block_size
 	}
@@ -164,22 +916,42 @@ impl GptDisk {
 		&self.backup_header
 	}
 	
-	pub fn update_crc(&mut self) {
-		// Update partition table crcs
-		// (Parition table crcs must be updated first,
-		//  because they are fed into the gpt header crc!)
-		self.primary_header.update_partition_array_crc32(&self.partitions, self.primary_header.partition_entry_size);
-		self.backup_header.update_partition_array_crc32(&self.partitions, self.backup_header.partition_entry_size);
+	pub fn partition_array_layout(&self) -> PartitionArrayLayout {
+		PartitionArrayLayout {
+			num_entries: self.primary_header.num_partition_entries,
+			entry_size: self.primary_header.partition_entry_size,
+		}
+	}
+	
+	/// Serializes the whole partition array, including zeroed unused entries.
+	pub fn partition_array_bytes(&self) -> Vec<u8> {
+		let entry_size = self.primary_header.partition_entry_size as usize;
+		let mut raw = vec![0u8; self.primary_header.num_partition_entries as usize * entry_size];
 		
-		// Update header crcs
-		self.primary_header.update_header_crc32();
-		self.backup_header.update_header_crc32();
+		for (part, mut entry) in self.partitions.iter().zip(raw.chunks_exact_mut(entry_size)) {
+			entry.write_all(&part.partition_type_guid.to_efi_bytes()).unwrap();
+			entry.write_all(&part.unique_guid.to_efi_bytes()).unwrap();
+			entry.write_u64::<LE>(part.start_lba).unwrap();
+			entry.write_u64::<LE>(part.end_lba_incl).unwrap();
+			entry.write_u64::<LE>(part.attributes.bits()).unwrap();
+			for c in part.partition_name.as_raw().iter().copied() {
+				entry.write_u16::<LE>(c).unwrap();
+			}
+		}
+		
+		raw
+	}
+	
+	/// The crc over the partition array, as it would be written to the disk.
+	pub fn partition_array_crc32(&self) -> u32 {
+		crc32::checksum_ieee(&self.partition_array_bytes())
 	}
 	
-	pub fn writer<'a>(&'a self, file: File) -> GptDiskWriter<'a> {
+	/// Writes the disk to a device with the same block size and at least as many blocks.
+	pub fn writer<D: BlockDevice>(&self, device: D) -> GptDiskWriter<'_, D> {
 		GptDiskWriter {
 			disk: self,
-			file,
+			device,
 			initialized: false,
 		}
 	}
@@ -190,22 +962,68 @@ pub struct CreatePartitionOptions {
 	unique_guid: Guid,
 	size_in_lba: u64,
 	attributes: GptPartitionAttribs,
-	partition_name: [Utf16LEChar; 36],
+	partition_name: PartitionName,
+	start_lba: Option<u64>,
+	alignment_lba: Option<u64>,
 }
 
 impl CreatePartitionOptions {
-	pub fn new(partition_type: Guid, unique_guid: Option<Guid>, size_in_lba: u64, attributes: GptPartitionAttribs, name: [Utf16LEChar; 36]) -> Self {
+	pub fn new(partition_type: Guid, unique_guid: Option<Guid>, size_in_lba: u64, attributes: GptPartitionAttribs, name: PartitionName) -> Self {
 		CreatePartitionOptions {
 			partition_type,
 			unique_guid: unique_guid
-				.unwrap_or_else(|| Guid::new_v4()),
+				.unwrap_or_else(Guid::new_v4),
 			size_in_lba,
 			attributes,
 			partition_name: name,
+			start_lba: None,
+			alignment_lba: None,
+		}
+	}
+	
+	/// Places the partition at exactly this lba instead of searching for free space.
+	pub fn start_lba(mut self, start_lba: u64) -> Self {
+		self.start_lba = Some(start_lba);
+		self
+	}
+	
+	/// Overrides the disk's default alignment for this partition.
+	pub fn alignment_lba(mut self, alignment_lba: u64) -> Self {
+		self.alignment_lba = Some(cmp::max(1, alignment_lba));
+		self
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionError {
+	NoSuchPartition {index: usize},
+	ZeroSize,
+	/// All entries of the partition array are in use.
+	PartitionArrayFull,
+	/// No free gap big enough was found.
+	NoSpace {size_in_lba: u64},
+	/// The requested range overlaps the partition at index `other`.
+	Overlap {start_lba: u64, end_lba_incl: u64, other: usize},
+	OutsideUsableRange {start_lba: u64, end_lba_incl: u64},
+	DiskTooSmall {min_size_lba: u64},
+}
+
+impl fmt::Display for PartitionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PartitionError::NoSuchPartition {index} => write!(f, "no partition at index {}", index),
+			PartitionError::ZeroSize => write!(f, "partition size must not be zero"),
+			PartitionError::PartitionArrayFull => write!(f, "partition array is full"),
+			PartitionError::NoSpace {size_in_lba} => write!(f, "no free space for a partition of {} lba", size_in_lba),
+			PartitionError::Overlap {start_lba, end_lba_incl, other} => write!(f, "lba range {}..={} overlaps partition {}", start_lba, end_lba_incl, other),
+			PartitionError::OutsideUsableRange {start_lba, end_lba_incl} => write!(f, "lba range {}..={} lies outside the usable range", start_lba, end_lba_incl),
+			PartitionError::DiskTooSmall {min_size_lba} => write!(f, "disk must be at least {} lba to hold the gpt and all partitions", min_size_lba),
 		}
 	}
 }
 
+impl error::Error for PartitionError {}
+
 pub struct PartitionIter<'a> {
 	disk: &'a GptDisk,
 	front: usize,
@@ -228,49 +1046,79 @@ impl<'a> Iterator for PartitionIter<'a> {
 //	disk: &'a mut GptDisk,
 //}
 
-pub struct GptDiskWriter<'a> {
+pub struct GptDiskWriter<'a, D> {
 	disk: &'a GptDisk,
-	file: File,
+	device: D,
 	initialized: bool,
 }
 
-impl<'a> GptDiskWriter<'a> {
-	fn ensure_init(&mut self) -> Result<(), Box<dyn error::Error>> {
+impl<'a, D: BlockDevice> GptDiskWriter<'a, D> {
+	fn ensure_init(&mut self) -> Result<(), Error> {
 		if !self.initialized {
+			check_device(&self.device, self.disk.block_size, self.disk.disk_size_lba)?;
 			self.initialized = true;
-			
-			// Resize file
-			self.file.set_len(self.disk.disk_size_lba * self.disk.block_size as u64)?;
 		}
 		
 		Ok(())
 	}
 	
-	pub fn write_protective_mbr(&mut self) -> Result<(), Box<dyn error::Error>> {
+	pub fn write_protective_mbr(&mut self) -> Result<(), Error> {
+		let raw_mbr = mbr::make_gpt_protective_mbr(self.disk.disk_size_lba);
+		self.write_mbr(&raw_mbr)
+	}
+	
+	/// Writes a hybrid mbr instead of a protective one, so mbr-only firmware sees the mirrored partitions too.
+	/// `mirrored` lists up to 3 partition indices with the mbr os type to give them,
+	/// partitions with the legacy bios bootable attribute are marked active.
+	pub fn write_hybrid_mbr(&mut self, mirrored: &[(usize, mbr::MbrOsType)], bootstrap_code: &[u8]) -> Result<(), Error> {
+		let mut hybrid_partitions = Vec::with_capacity(mirrored.len());
+		for &(index, os_type) in mirrored {
+			self.disk.check_index(index)?;
+			let part = &self.disk.partitions[index];
+			
+			hybrid_partitions.push(mbr::HybridPartition {
+				start_lba: part.start_lba,
+				size_in_lba: part.size_in_lba,
+				os_type,
+				bootable: part.attributes.contains(GptPartitionAttribs::LEGACY_BIOS_BOOTABLE),
+			});
+		}
+		
+		let raw_mbr = mbr::make_hybrid_mbr(self.disk.disk_size_lba, &hybrid_partitions, bootstrap_code)?;
+		self.write_mbr(&raw_mbr)
+	}
+	
+	fn write_mbr(&mut self, raw_mbr: &MasterBootRecord) -> Result<(), Error> {
 		// Init
 		self.ensure_init()?;
-		let file = &mut self.file;
-		
-		file.seek(SeekFrom::Start(0))?;
 		
-		// Make protective mbr struct
-		let raw_mbr = mbr::make_gpt_protective_mbr(0xFFFFFF);
-		
-		// Cast to slice
-		let mbr_slice = unsafe {mem::transmute::<&MasterBootRecord, &[u8; mem::size_of::<MasterBootRecord>()]>(&raw_mbr)};
-		
-		// Write to file
-		file.write_all(mbr_slice)?;
+		// The rest of a bigger first block is kept
+		let mut block = vec![0u8; self.disk.block_size as usize];
+		self.device.read_blocks(0, &mut block)
+			.and_then(|_| {
+				block[..MasterBootRecord::SIZE].copy_from_slice(&raw_mbr.to_bytes());
+				self.device.write_blocks(0, &block)
+			})
+			.map_err(|e| Error::io("can't write the mbr", e))?;
 		
 		Ok(())
 	}
 	
-	pub fn write_gpt_header(&mut self, primary: bool) -> Result<(), Box<dyn error::Error>> {
+	pub fn write_gpt_header(&mut self, primary: bool) -> Result<(), Error> {
 		// Init
 		self.ensure_init()?;
-		let file = &mut self.file;
 		
 		let block_size = self.disk.block_size;
+		let kind = match primary {
+			true => HeaderKind::Primary,
+			false => HeaderKind::Backup,
+		};
+		
+		// Derive crcs from the current disk state
+		// (The partition array crc must be computed first,
+		//  because it is fed into the header crc!)
+		let partition_array = self.disk.partition_array_bytes();
+		let partition_array_crc32 = crc32::checksum_ieee(&partition_array);
 		
 		{// Serialize header
 			let (h, pos_lba) = match primary {
@@ -278,94 +1126,56 @@ impl<'a> GptDiskWriter<'a> {
 				false => (&self.disk.backup_header, (self.disk.disk_size_lba - 1)),
 			};
 			
-			// Seek to header pos
-			file.seek(SeekFrom::Start(pos_lba * block_size as u64))?;
-			
-			// Serialize
-			file.write_u64::<LE>(h.signature)?;
-			file.write_u32::<LE>(h.revision)?;
-			file.write_u32::<LE>(h.header_size)?;
-			file.write_u32::<LE>(h.header_crc32)?;
-			file.write_u32::<LE>(0)?;
-			file.write_u64::<LE>(h.my_lba)?;
-			file.write_u64::<LE>(h.alternate_lba)?;
-			file.write_u64::<LE>(h.first_usable_lba)?;
-			file.write_u64::<LE>(h.last_usable_lba)?;
-			file.write_u128::<LE>(uuid_to_guid_mixed_endian(h.disk_guid))?;
-			file.write_u64::<LE>(h.partition_array_start_lba)?;
-			file.write_u32::<LE>(h.num_partition_entries)?;
-			file.write_u32::<LE>(h.partition_entry_size)?;
-			file.write_u32::<LE>(h.partition_array_crc32)?;
-		}
-		
-		// Serialize primary partition array
+			// Serialize, the rest of the block is zeroed
+			let mut block = vec![0u8; block_size as usize];
+			h.write_to(&mut &mut block[..], partition_array_crc32)
+				.and_then(|_| self.device.write_blocks(pos_lba, &block))
+				.map_err(|e| Error::io(format!("can't write the {} gpt header", kind), e))?;
+		}
+		
+		// Serialize partition array
 		{
-			let (h, start_lba) = match primary {
-				true => (&self.disk.primary_header, self.disk.primary_header.partition_array_start_lba),
-				false => (&self.disk.backup_header, self.disk.backup_header.partition_array_start_lba),
+			let start_lba = match primary {
+				true => self.disk.primary_header.partition_array_start_lba,
+				false => self.disk.backup_header.partition_array_start_lba,
 			};
 			
-			for (i, part) in self.disk.partitions().enumerate() {
-				// Seek to entry pos
-				file.seek(SeekFrom::Start(start_lba * block_size as u64 + (i as u64 * h.partition_entry_size as u64)))?;
-				
-				file.write_u128::<LE>(uuid_to_guid_mixed_endian(part.partition_type_guid))?;
-				file.write_u128::<LE>(uuid_to_guid_mixed_endian(part.unique_guid))?;
-//				file.write_all(part.partition_type_guid.as_bytes())?;
-//				file.write_all(part.unique_guid.as_bytes())?;
-				file.write_u64::<LE>(part.start_lba)?;
-				file.write_u64::<LE>(part.end_lba_incl)?;
-				file.write_u64::<LE>(*part.attributes.as_raw())?;
-				file.write_all(unsafe {mem::transmute::<&[u16; 36], &[u8; 72]>(&part.partition_name)})?;
-			}
+			// Padded to whole blocks
+			let mut blocks = partition_array;
+			blocks.resize((self.disk.partition_array_layout().size_lba(block_size) * block_size as u64) as usize, 0);
+			self.device.write_blocks(start_lba, &blocks)
+				.map_err(|e| Error::io(format!("can't write the {} partition array", kind), e))?;
 		}
 		
 		Ok(())
 	}
 	
-	pub fn write_partition_content(&mut self, partition: &GptPartition, stream: &mut dyn Read) -> Result<(), Box<dyn error::Error>> {
+	pub fn write_partition_content(&mut self, partition: &GptPartition, stream: &mut dyn Read) -> Result<(), Error> {
 		// Init
 		self.ensure_init()?;
 		
-		let mut read_buffer = [0 as u8; 128];
-		
-		// Seek in disk file
-		let content_start = partition.start_lba * self.disk.block_size as u64;
-		self.file.seek(SeekFrom::Start(content_start))?;
-		
-//		let layout = partition.disk_layout;
-		let mut remaining_size = ((partition.end_lba_incl - partition.start_lba) + 1) * self.disk.block_size as u64;
-		loop {
-			let bytes_read = stream.read(&mut read_buffer)?;
-			
-			if bytes_read == 0 {
-//				println!("[write_partition_content] Read zero bytes, breaking", );
-				break;
-			}
-			
-			let real_size = cmp::min(bytes_read as u64, remaining_size);
-			
-			// Write to file
-			self.file.write_all(&read_buffer[0..real_size as usize])?;
-			
-			// Subtract read size
-			remaining_size -= real_size;
-		}
-		
-		Ok(())
+		write_content(&mut self.device, partition.start_lba, partition.end_lba_incl + 1, stream)
+			.map_err(|e| Error::io("can't write partition content", e))
 	}
 	
-	pub fn flush(self) -> File {
-		self.file
+	/// Flushes and returns the device.
+	pub fn flush(mut self) -> Result<D, Error> {
+		self.device.flush().map_err(|e| Error::io("can't flush the disk", e))?;
+		Ok(self.device)
 	}
 }
 
-#[derive(Clone)]
+/// The layout part of a gpt header.
+///
+/// Deliberately doesn't carry the header and partition array crcs,
+/// those are derived from the disk whenever a header is serialized
+/// (see [`GptDiskWriter::write_gpt_header`]) so they can never be out of date.
+/// The crcs actually stored in an image are found in [`StoredGptHeader`].
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct GptHeader {
 	pub signature: u64,
 	pub revision: u32,
 	pub header_size: u32,
-	pub header_crc32: u32,
 	pub my_lba: u64,
 	pub alternate_lba: u64,
 	pub first_usable_lba: u64,
@@ -374,11 +1184,29 @@ pub struct GptHeader {
 	pub partition_array_start_lba: u64,
 	pub num_partition_entries: u32,
 	pub partition_entry_size: u32,
-	pub partition_array_crc32: u32,
 }
 
 impl GptHeader {
-	pub fn update_header_crc32(&mut self) {
+	/// Serializes the header, with its crc derived from the crc of its partition array.
+	pub fn write_to(&self, writer: &mut impl Write, partition_array_crc32: u32) -> io::Result<()> {
+		writer.write_u64::<LE>(self.signature)?;
+		writer.write_u32::<LE>(self.revision)?;
+		writer.write_u32::<LE>(self.header_size)?;
+		writer.write_u32::<LE>(self.header_crc32(partition_array_crc32))?;
+		writer.write_u32::<LE>(0)?;
+		writer.write_u64::<LE>(self.my_lba)?;
+		writer.write_u64::<LE>(self.alternate_lba)?;
+		writer.write_u64::<LE>(self.first_usable_lba)?;
+		writer.write_u64::<LE>(self.last_usable_lba)?;
+		writer.write_all(&self.disk_guid.to_efi_bytes())?;
+		writer.write_u64::<LE>(self.partition_array_start_lba)?;
+		writer.write_u32::<LE>(self.num_partition_entries)?;
+		writer.write_u32::<LE>(self.partition_entry_size)?;
+		writer.write_u32::<LE>(partition_array_crc32)
+	}
+	
+	/// Computes the header crc, given the crc of the partition array belonging to this header.
+	pub fn header_crc32(&self, partition_array_crc32: u32) -> u32 {
 		let mut digest = crc32::Digest::new(crc32::IEEE);
 		
 		digest.write_u64(self.signature);
@@ -390,47 +1218,117 @@ impl GptHeader {
 		digest.write_u64(self.alternate_lba);
 		digest.write_u64(self.first_usable_lba);
 		digest.write_u64(self.last_usable_lba);
-		digest.write_u128(uuid_to_guid_mixed_endian(self.disk_guid));
+		Hasher::write(&mut digest, &self.disk_guid.to_efi_bytes());
 		digest.write_u64(self.partition_array_start_lba);
 		digest.write_u32(self.num_partition_entries);
 		digest.write_u32(self.partition_entry_size);
-		digest.write_u32(self.partition_array_crc32);
+		digest.write_u32(partition_array_crc32);
 		
-		self.header_crc32 = digest.sum32();
+		digest.sum32()
 	}
 	
-	pub fn update_partition_array_crc32(&mut self, partitions: &[GptPartition], partition_entry_size: u32) {
-		let mut digest = crc32::Digest::new(crc32::IEEE);
+	/// Makes the alternate copy of this header, living at `my_lba`.
+	fn mirrored(&self, my_lba: u64, partition_array_start_lba: u64) -> GptHeader {
+		let mut h = self.clone();
+		h.alternate_lba = self.my_lba;
+		h.my_lba = my_lba;
+		h.partition_array_start_lba = partition_array_start_lba;
+		h
+	}
+}
+
+/// A gpt header as found in an image, including the crcs stored alongside it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredGptHeader {
+	pub header: GptHeader,
+	pub header_crc32: u32,
+	pub partition_array_crc32: u32,
+}
+
+impl StoredGptHeader {
+	/// Parses a header from the current position of the given reader.
+	/// Doesn't validate anything, see [`StoredGptHeader::is_valid`].
+	pub fn read_from(reader: &mut impl Read) -> io::Result<StoredGptHeader> {
+		let signature = reader.read_u64::<LE>()?;
+		let revision = reader.read_u32::<LE>()?;
+		let header_size = reader.read_u32::<LE>()?;
+		let header_crc32 = reader.read_u32::<LE>()?;
+		let _reserved = reader.read_u32::<LE>()?;
 		
-		for p in partitions.iter() {
-//			crc::crc32::Hasher32::write(&mut digest, p.partition_type_guid.as_bytes());
-//			crc::crc32::Hasher32::write(&mut digest, p.unique_guid.as_bytes());
-			digest.write_u128(uuid_to_guid_mixed_endian(p.partition_type_guid));
-			digest.write_u128(uuid_to_guid_mixed_endian(p.unique_guid));
-			digest.write_u64(p.start_lba);
-			digest.write_u64(p.end_lba_incl);
-			digest.write_u64(*p.attributes.as_raw());
-			
-			for c in p.partition_name.iter().copied() {
-				digest.write_u16(c);
-			}
-			
-			// Digest extra padding (if the entry size is bigger than 128 bit)
-			for _ in 128..partition_entry_size {
-				digest.write_u8(0);
-			}
-		}
+		let header = GptHeader {
+			signature,
+			revision,
+			header_size,
+			my_lba: reader.read_u64::<LE>()?,
+			alternate_lba: reader.read_u64::<LE>()?,
+			first_usable_lba: reader.read_u64::<LE>()?,
+			last_usable_lba: reader.read_u64::<LE>()?,
+			disk_guid: read_guid(reader)?,
+			partition_array_start_lba: reader.read_u64::<LE>()?,
+			num_partition_entries: reader.read_u32::<LE>()?,
+			partition_entry_size: reader.read_u32::<LE>()?,
+		};
 		
-		self.partition_array_crc32 = digest.sum32();
+		Ok(StoredGptHeader {
+			header,
+			header_crc32,
+			partition_array_crc32: reader.read_u32::<LE>()?,
+		})
+	}
+	
+	/// The header crc this header should have stored.
+	pub fn computed_header_crc32(&self) -> u32 {
+		self.header.header_crc32(self.partition_array_crc32)
+	}
+	
+	/// Whether the signature matches and the stored header crc is correct.
+	pub fn is_valid(&self) -> bool {
+		self.header.signature == GPT_HEADER_SIGNATURE && self.computed_header_crc32() == self.header_crc32
+	}
+}
+
+/// Number and size of the entries of a partition array.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartitionArrayLayout {
+	pub num_entries: u32,
+	pub entry_size: u32,
+}
+
+impl PartitionArrayLayout {
+	/// 128 entries of 128 bytes each (16 KiB), what most firmware and tools expect.
+	pub const STANDARD: PartitionArrayLayout = PartitionArrayLayout {
+		num_entries: 128,
+		entry_size: 128,
+	};
+	
+	pub fn size_bytes(&self) -> u64 {
+		self.num_entries as u64 * self.entry_size as u64
+	}
+	
+	/// Number of whole lbas the array occupies.
+	pub fn size_lba(&self, block_size: u32) -> u64 {
+		self.size_bytes().div_ceil(block_size as u64)
+	}
+	
+	/// Smallest disk holding the mbr, both headers and arrays, and a single usable lba.
+	pub fn min_disk_size_lba(&self, block_size: u32) -> u64 {
+		2 * (1 + self.size_lba(block_size)) + 2
+	}
+}
+
+impl Default for PartitionArrayLayout {
+	fn default() -> Self {
+		Self::STANDARD
 	}
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct GptPartition {
 	pub partition_type_guid: Guid,
 	pub unique_guid: Guid,
 	pub size_in_lba: u64,
 	pub attributes: GptPartitionAttribs,
-	pub partition_name: [Utf16LEChar; 36],
+	pub partition_name: PartitionName,
 	pub start_lba: u64,
 	pub end_lba_incl: u64,
 //	pub disk_layout: GptPartitionLayout,
@@ -443,23 +1341,134 @@ impl GptPartition {
 			unique_guid,
 			size_in_lba: (end_lba_incl - start_lba) + 1,
 			attributes,
-			partition_name: [0x0; 36],
+			partition_name: PartitionName::empty(),
 			start_lba,
 			end_lba_incl,
 		}
 	}
+}
+
+/// A partition name of up to 36 utf-16 code units, nul padded as stored in a partition entry.
+///
+/// Names built through [`PartitionName::new`] are always valid utf-16,
+/// names read from an image are kept as-is and checked when decoding.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PartitionName([Utf16LEChar; PartitionName::MAX_LEN]);
+
+impl PartitionName {
+	/// Max length in utf-16 code units. Names of exactly this length have no nul terminator.
+	pub const MAX_LEN: usize = 36;
+	
+	pub const fn empty() -> Self {
+		Self([0; Self::MAX_LEN])
+	}
+	
+	pub fn new(name: &str) -> Result<Self, PartitionNameError> {
+		Self::from_utf16(&name.encode_utf16().collect::<Vec<_>>())
+	}
 	
-	pub fn set_name_ascii(&mut self, name_acii: &[u8]) {
-		let real_len = cmp::min(name_acii.len(), 36);
+	/// Makes a name from utf-16 code units, rejecting names that are too long,
+	/// contain nul characters or unpaired surrogates.
+	pub fn from_utf16(units: &[Utf16LEChar]) -> Result<Self, PartitionNameError> {
+		if units.len() > Self::MAX_LEN {
+			return Err(PartitionNameError::TooLong {len: units.len()});
+		}
+		if let Some(index) = units.iter().position(|c| *c == 0) {
+			return Err(PartitionNameError::ContainsNul {index});
+		}
+		if let Some(index) = first_unpaired_surrogate(units) {
+			return Err(PartitionNameError::UnpairedSurrogate {index});
+		}
 		
-		let own_name = &mut self.partition_name;
-		for i in 0..real_len {
-			own_name[i] = name_acii[i] as u16;
+		let mut raw = [0; Self::MAX_LEN];
+		raw[..units.len()].copy_from_slice(units);
+		Ok(Self(raw))
+	}
+	
+	/// Takes a raw name as found in a partition entry, without any validation.
+	pub const fn from_raw(raw: [Utf16LEChar; Self::MAX_LEN]) -> Self {
+		Self(raw)
+	}
+	
+	pub fn as_raw(&self) -> &[Utf16LEChar; Self::MAX_LEN] {
+		&self.0
+	}
+	
+	/// The code units up to the nul terminator.
+	pub fn as_utf16(&self) -> &[Utf16LEChar] {
+		let len = self.0.iter().position(|c| *c == 0).unwrap_or(Self::MAX_LEN);
+		&self.0[..len]
+	}
+	
+	pub fn is_empty(&self) -> bool {
+		self.0[0] == 0
+	}
+	
+	/// Decodes the name, fails if it isn't valid utf-16.
+	/// Use the [`Display`](fmt::Display) impl for a lossy conversion.
+	pub fn decode(&self) -> Result<String, PartitionNameError> {
+		let units = self.as_utf16();
+		match first_unpaired_surrogate(units) {
+			Some(index) => Err(PartitionNameError::UnpairedSurrogate {index}),
+			None => Ok(String::from_utf16_lossy(units)),
+		}
+	}
+}
+
+impl Default for PartitionName {
+	fn default() -> Self {
+		Self::empty()
+	}
+}
+
+impl str::FromStr for PartitionName {
+	type Err = PartitionNameError;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::new(s)
+	}
+}
+
+impl fmt::Display for PartitionName {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", String::from_utf16_lossy(self.as_utf16()))
+	}
+}
+
+impl fmt::Debug for PartitionName {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "PartitionName({:?})", String::from_utf16_lossy(self.as_utf16()))
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionNameError {
+	TooLong {len: usize},
+	ContainsNul {index: usize},
+	UnpairedSurrogate {index: usize},
+}
+
+impl fmt::Display for PartitionNameError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			PartitionNameError::TooLong {len} => write!(f, "partition name is {} utf-16 code units long, max is {}", len, PartitionName::MAX_LEN),
+			PartitionNameError::ContainsNul {index} => write!(f, "partition name contains a nul character at {}", index),
+			PartitionNameError::UnpairedSurrogate {index} => write!(f, "partition name contains an unpaired surrogate at {}", index),
 		}
-		for i in real_len..36 {
-			own_name[i] = 0;
+	}
+}
+
+impl error::Error for PartitionNameError {}
+
+fn first_unpaired_surrogate(units: &[Utf16LEChar]) -> Option<usize> {
+	let mut i = 0;
+	for c in char::decode_utf16(units.iter().copied()) {
+		match c {
+			Ok(c) => i += c.len_utf16(),
+			Err(_) => return Some(i),
 		}
 	}
+	None
 }
 
 //#[deprecated]
@@ -468,55 +1477,447 @@ impl GptPartition {
 //	pub end_lba: u64,
 //}
 
+/// Partition attribute flags.
+///
+/// Bits 0 to 2 are defined by the uefi spec for all partitions,
+/// bits 48 to 63 are reserved for the partition type to define
+/// (see [`GptPartitionAttribs::type_specific`]), everything in between is reserved.
+///
+/// Formats as a comma separated list of flag names, e.g. `required,legacy_bios_bootable,type:12`,
+/// which can be parsed back with [`str::parse`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct GptPartitionAttribs(u64);
 
 impl GptPartitionAttribs {
-	pub fn zero() -> Self {
+	/// The partition is required for the platform to function and must not be deleted.
+	pub const REQUIRED_PARTITION: Self = Self(1 << 0);
+	/// Firmware must not produce a block io protocol for this partition.
+	pub const NO_BLOCK_IO_PROTOCOL: Self = Self(1 << 1);
+	/// Legacy bios bootable, the equivalent of the mbr active flag.
+	pub const LEGACY_BIOS_BOOTABLE: Self = Self(1 << 2);
+	
+	/// First of the 16 partition type specific bits.
+	pub const TYPE_SPECIFIC_SHIFT: u32 = 48;
+	
+	const NAMED_FLAGS: [(Self, &'static str); 3] = [
+		(Self::REQUIRED_PARTITION, "required"),
+		(Self::NO_BLOCK_IO_PROTOCOL, "no_block_io"),
+		(Self::LEGACY_BIOS_BOOTABLE, "legacy_bios_bootable"),
+	];
+	
+	pub const fn zero() -> Self {
 		Self(0)
 	}
 	
-	pub unsafe fn from_raw(raw_bits: u64) -> Self {
-		Self(raw_bits)
+	pub const fn from_bits(bits: u64) -> Self {
+		Self(bits)
 	}
 	
-	pub fn as_raw(&self) -> &u64 {
-		&self.0
+	/// The type specific attribute bit `n` (0 to 15), i.e. bit `48 + n` of the attributes.
+	///
+	/// # Panics
+	/// If `n` is larger than 15.
+	pub const fn type_specific(n: u32) -> Self {
+		assert!(n < 16, "type specific attribute bit out of range");
+		Self(1 << (Self::TYPE_SPECIFIC_SHIFT + n))
+	}
+	
+	pub const fn bits(&self) -> u64 {
+		self.0
+	}
+	
+	/// The 16 type specific bits, shifted down.
+	pub const fn type_specific_bits(&self) -> u16 {
+		(self.0 >> Self::TYPE_SPECIFIC_SHIFT) as u16
+	}
+	
+	pub const fn is_empty(&self) -> bool {
+		self.0 == 0
+	}
+	
+	pub const fn contains(&self, other: Self) -> bool {
+		self.0 & other.0 == other.0
+	}
+	
+	pub const fn with(self, other: Self) -> Self {
+		Self(self.0 | other.0)
+	}
+	
+	pub fn insert(&mut self, other: Self) {
+		self.0 |= other.0;
+	}
+	
+	pub fn remove(&mut self, other: Self) {
+		self.0 &= !other.0;
 	}
 }
 
-pub fn uuid_to_guid_mixed_endian(uuid: uuid::Uuid) -> u128 {
-	let src = uuid.to_u128_le().to_ne_bytes();
-	let mut buf = [0u8; 16];
+impl ops::BitOr for GptPartitionAttribs {
+	type Output = Self;
 	
-	buf[0] = src[3];
-	buf[1] = src[2];
-	buf[2] = src[1];
-	buf[3] = src[0];
+	fn bitor(self, rhs: Self) -> Self {
+		self.with(rhs)
+	}
+}
+
+impl ops::BitOrAssign for GptPartitionAttribs {
+	fn bitor_assign(&mut self, rhs: Self) {
+		self.insert(rhs);
+	}
+}
+
+impl fmt::Display for GptPartitionAttribs {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.is_empty() {
+			return write!(f, "none");
+		}
+		
+		let mut first = true;
+		for bit in 0..64 {
+			let flag = Self(1 << bit);
+			if !self.contains(flag) {
+				continue;
+			}
+			
+			if !first {
+				write!(f, ",")?;
+			}
+			first = false;
+			
+			match Self::NAMED_FLAGS.iter().find(|(named, _)| *named == flag) {
+				Some((_, name)) => write!(f, "{}", name)?,
+				None if bit >= Self::TYPE_SPECIFIC_SHIFT => write!(f, "type:{}", bit - Self::TYPE_SPECIFIC_SHIFT)?,
+				None => write!(f, "bit:{}", bit)?,
+			}
+		}
+		Ok(())
+	}
+}
+
+impl str::FromStr for GptPartitionAttribs {
+	type Err = ParseAttribsError;
 	
-	buf[4] = src[5];
-	buf[5] = src[4];
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut attribs = Self::zero();
+		if s.trim() == "none" {
+			return Ok(attribs);
+		}
+		
+		for flag in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+			let parse_bit = |n: &str, max: u32| n.parse::<u32>().ok()
+				.filter(|n| *n < max)
+				.ok_or_else(|| ParseAttribsError(flag.to_string()));
+			
+			let named = Self::NAMED_FLAGS.iter().find(|(_, name)| *name == flag);
+			attribs |= match (named, flag.split_once(':')) {
+				(Some((named, _)), _) => *named,
+				(None, Some(("type", n))) => Self::type_specific(parse_bit(n, 16)?),
+				(None, Some(("bit", n))) => Self(1 << parse_bit(n, 64)?),
+				_ => return Err(ParseAttribsError(flag.to_string())),
+			};
+		}
+		Ok(attribs)
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseAttribsError(String);
+
+impl fmt::Display for ParseAttribsError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "unknown partition attribute \"{}\"", self.0)
+	}
+}
+
+impl error::Error for ParseAttribsError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderKind {
+	Primary,
+	Backup,
+}
+
+impl fmt::Display for HeaderKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			HeaderKind::Primary => write!(f, "primary"),
+			HeaderKind::Backup => write!(f, "backup"),
+		}
+	}
+}
+
+/// A single problem found by [`GptDisk::check_integrity`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IntegrityIssue {
+	DiskTooSmall {disk_size_lba: u64},
+	NoGptHeader,
+	MbrBadSignature {found: u16},
+	MbrNoProtectiveEntry,
+	MbrProtectiveEntryBadStart {starting_lba: u32},
+	MbrProtectiveEntryBadSize {size_in_lba: u32, expected: u32},
+	MbrPartitionBeyondDisk {index: usize},
+	BadSignature {header: HeaderKind, found: u64},
+	UnsupportedRevision {header: HeaderKind, found: u32},
+	BadHeaderSize {header: HeaderKind, found: u32},
+	HeaderCrcMismatch {header: HeaderKind, stored: u32, computed: u32},
+	MyLbaMismatch {header: HeaderKind, my_lba: u64, expected: u64},
+	AlternateLbaMismatch {header: HeaderKind, alternate_lba: u64, expected: u64},
+	BackupNotAtEnd {backup_lba: u64, expected: u64},
+	HeadersDisagree {field: &'static str},
+	UsableRangeOverlapsGpt {header: HeaderKind},
+	BadPartitionEntrySize {header: HeaderKind, found: u32},
+	PartitionArrayUnreadable {header: HeaderKind},
+	PartitionArrayCrcMismatch {header: HeaderKind, stored: u32, computed: u32},
+	PartitionArraysDisagree,
+	PartitionEndsBeforeStart {index: usize, start_lba: u64, end_lba_incl: u64},
+	PartitionOutsideUsableRange {index: usize, start_lba: u64, end_lba_incl: u64},
+	PartitionsOverlap {first: usize, second: usize},
+}
+
+impl fmt::Display for IntegrityIssue {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use IntegrityIssue::*;
+		match self {
+			DiskTooSmall {disk_size_lba} => write!(f, "disk is too small to hold a gpt ({} lba)", disk_size_lba),
+			NoGptHeader => write!(f, "no gpt header found, neither at lba 1 nor at the end of the disk"),
+			MbrBadSignature {found} => write!(f, "protective mbr has bad signature {:#06x}", found),
+			MbrNoProtectiveEntry => write!(f, "protective mbr has no gpt protective partition entry"),
+			MbrProtectiveEntryBadStart {starting_lba} => write!(f, "protective mbr entry starts at lba {} instead of 1", starting_lba),
+			MbrProtectiveEntryBadSize {size_in_lba, expected} => write!(f, "protective mbr entry has size {:#x} lba, expected {:#x}", size_in_lba, expected),
+			MbrPartitionBeyondDisk {index} => write!(f, "mbr partition entry {} extends beyond the end of the disk", index),
+			BadSignature {header, found} => write!(f, "{} header has bad signature {:#018x}", header, found),
+			UnsupportedRevision {header, found} => write!(f, "{} header has unsupported revision {:#010x}", header, found),
+			BadHeaderSize {header, found} => write!(f, "{} header has bad header size {}", header, found),
+			HeaderCrcMismatch {header, stored, computed} => write!(f, "{} header crc is {:#010x}, should be {:#010x}", header, stored, computed),
+			MyLbaMismatch {header, my_lba, expected} => write!(f, "{} header claims to live at lba {} but was found at lba {}", header, my_lba, expected),
+			AlternateLbaMismatch {header, alternate_lba, expected} => write!(f, "{} header points to alternate lba {}, expected {}", header, alternate_lba, expected),
+			BackupNotAtEnd {backup_lba, expected} => write!(f, "backup header is at lba {} instead of the last lba {}", backup_lba, expected),
+			HeadersDisagree {field} => write!(f, "primary and backup header disagree on {}", field),
+			UsableRangeOverlapsGpt {header} => write!(f, "usable lba range overlaps the {} gpt structures", header),
+			BadPartitionEntrySize {header, found} => write!(f, "{} header has bad partition entry size {}", header, found),
+			PartitionArrayUnreadable {header} => write!(f, "{} partition array lies outside the disk", header),
+			PartitionArrayCrcMismatch {header, stored, computed} => write!(f, "{} partition array crc is {:#010x}, should be {:#010x}", header, stored, computed),
+			PartitionArraysDisagree => write!(f, "primary and backup partition arrays differ"),
+			PartitionEndsBeforeStart {index, start_lba, end_lba_incl} => write!(f, "partition {} ends before it starts ({}..={})", index, start_lba, end_lba_incl),
+			PartitionOutsideUsableRange {index, start_lba, end_lba_incl} => write!(f, "partition {} ({}..={}) lies outside the usable lba range", index, start_lba, end_lba_incl),
+			PartitionsOverlap {first, second} => write!(f, "partitions {} and {} overlap", first, second),
+		}
+	}
+}
+
+fn read_guid(reader: &mut impl Read) -> io::Result<Guid> {
+	let mut efi_bytes = [0u8; 16];
+	reader.read_exact(&mut efi_bytes)?;
+	Ok(Guid::from_efi_bytes(efi_bytes))
+}
+
+/// Checks the device fits a disk with the given block size and size.
+pub(crate) fn check_device(device: &impl BlockDevice, block_size: u32, disk_size_lba: u64) -> Result<(), Error> {
+	if device.block_size() != block_size {
+		return Err(Error::invalid_layout(format!("device has {} byte blocks, the disk {} byte blocks", device.block_size(), block_size)));
+	}
+	if device.block_count() < disk_size_lba {
+		return Err(Error::invalid_layout(format!("device has {} blocks, the disk needs {}", device.block_count(), disk_size_lba)));
+	}
+	Ok(())
+}
+
+/// Copies the stream to the blocks from `start_lba` up to `end_lba`, the last block is padded with zeroes.
+/// Anything beyond `end_lba` is cut off.
+pub(crate) fn write_content(device: &mut impl BlockDevice, start_lba: u64, end_lba: u64, stream: &mut dyn Read) -> io::Result<()> {
+	let block_size = device.block_size() as usize;
+	let mut buffer = vec![0u8; CONTENT_BUFFER_BLOCKS * block_size];
 	
-	buf[6] = src[7];
-	buf[7] = src[6];
+	let mut lba = start_lba;
+	while lba < end_lba {
+		let max_len = cmp::min(buffer.len() as u64, (end_lba - lba) * block_size as u64) as usize;
+		let len = read_up_to(stream, &mut buffer[..max_len])?;
+		if len == 0 {
+			break;
+		}
+		
+		let padded_len = len.div_ceil(block_size) * block_size;
+		buffer[len..padded_len].fill(0);
+		device.write_blocks(lba, &buffer[..padded_len])?;
+		lba += (padded_len / block_size) as u64;
+		
+		if len < max_len {
+			break;
+		}
+	}
+	Ok(())
+}
+
+/// Looks for the primary header signature at lba 1 for each supported block size.
+pub(crate) fn probe_block_size(reader: &mut (impl Read + Seek), disk_size_bytes: u64) -> io::Result<Option<u32>> {
+	for bs in PROBE_BLOCK_SIZES.iter().copied() {
+		if disk_size_bytes < bs as u64 * 2 {
+			continue;
+		}
+		reader.seek(SeekFrom::Start(bs as u64))?;
+		if reader.read_u64::<LE>()? == GPT_HEADER_SIGNATURE {
+			return Ok(Some(bs));
+		}
+	}
+	Ok(None)
+}
+
+fn check_protective_mbr(reader: &mut (impl Read + Seek), disk_size_lba: u64, issues: &mut Vec<IntegrityIssue>) -> io::Result<()> {
+	reader.seek(SeekFrom::Start(0))?;
+	let raw_mbr = MasterBootRecord::read_from(reader)?;
 	
-	buf[6] = src[7];
-	buf[7] = src[6];
+	if raw_mbr.signature != mbr::PROTECTIVE_MBR_SIGNATURE {
+		issues.push(IntegrityIssue::MbrBadSignature {found: raw_mbr.signature});
+	}
 	
-	buf[8] = src[8];
-	buf[9] = src[9];
-	buf[10] = src[10];
-	buf[11] = src[11];
-	buf[12] = src[12];
-	buf[13] = src[13];
-	buf[14] = src[14];
-	buf[15] = src[15];
+	// Hybrid mbrs mirror gpt partitions next to a shrunk protective partition
+	let hybrid = raw_mbr.partitions.iter().any(|e| !e.is_empty() && e.os_type != mbr::os_types::GPT_PROTECTIVE);
 	
-//	let (a, b, c, d) = uuid.to_fields_le();
-//	
-//	(a.swap_bytes() as u128)
-//	| ((b.swap_bytes() as u128) << 4)
-//	| ((c.swap_bytes() as u128) << 6)
-//	| ((u64::from_ne_bytes(*d) as u128) << 8)
+	let mut found_protective = false;
+	for (i, entry) in raw_mbr.partitions.iter().enumerate() {
+		let os_type = entry.os_type;
+		let starting_lba = entry.starting_lba();
+		let size_in_lba = entry.size_in_lba();
+		
+		if os_type == mbr::os_types::GPT_PROTECTIVE {
+			found_protective = true;
+			
+			if starting_lba != 1 {
+				issues.push(IntegrityIssue::MbrProtectiveEntryBadStart {starting_lba});
+			}
+			let expected = mbr::protective_partition_size_lba(disk_size_lba);
+			if size_in_lba != expected && !(hybrid && size_in_lba < expected) {
+				issues.push(IntegrityIssue::MbrProtectiveEntryBadSize {size_in_lba, expected});
+			}
+		} else if os_type != 0 && starting_lba as u64 + size_in_lba as u64 > disk_size_lba {
+			issues.push(IntegrityIssue::MbrPartitionBeyondDisk {index: i});
+		}
+	}
+	if !found_protective {
+		issues.push(IntegrityIssue::MbrNoProtectiveEntry);
+	}
 	
-	u128::from_le_bytes(buf)
+	Ok(())
+}
+
+fn check_header(stored: &StoredGptHeader, kind: HeaderKind, found_at_lba: u64, block_size: u32, issues: &mut Vec<IntegrityIssue>) {
+	let header = &stored.header;
+	
+	if header.signature != GPT_HEADER_SIGNATURE {
+		issues.push(IntegrityIssue::BadSignature {header: kind, found: header.signature});
+	}
+	if header.revision != 0x00010000 {
+		issues.push(IntegrityIssue::UnsupportedRevision {header: kind, found: header.revision});
+	}
+	if header.header_size < 92 || header.header_size > block_size {
+		issues.push(IntegrityIssue::BadHeaderSize {header: kind, found: header.header_size});
+	}
+	
+	let computed = stored.computed_header_crc32();
+	if computed != stored.header_crc32 {
+		issues.push(IntegrityIssue::HeaderCrcMismatch {header: kind, stored: stored.header_crc32, computed});
+	}
+	
+	if header.my_lba != found_at_lba {
+		issues.push(IntegrityIssue::MyLbaMismatch {header: kind, my_lba: header.my_lba, expected: found_at_lba});
+	}
+}
+
+/// Reads and crc checks the partition array of the given header.
+/// Returns `None` if the array couldn't be read.
+fn check_partition_array(reader: &mut (impl Read + Seek), stored: &StoredGptHeader, kind: HeaderKind, block_size: u32, issues: &mut Vec<IntegrityIssue>) -> io::Result<Option<Vec<GptPartition>>> {
+	let header = &stored.header;
+	
+	if header.partition_entry_size < 128 || !header.partition_entry_size.is_power_of_two() {
+		issues.push(IntegrityIssue::BadPartitionEntrySize {header: kind, found: header.partition_entry_size});
+		return Ok(None);
+	}
+	
+	let disk_size_lba = reader.seek(SeekFrom::End(0))? / block_size as u64;
+	let array_end = header.partition_array_start_lba.checked_add(array_size_lba(header, block_size));
+	if array_end.is_none_or(|end| end > disk_size_lba) {
+		issues.push(IntegrityIssue::PartitionArrayUnreadable {header: kind});
+		return Ok(None);
+	}
+	
+	let (parts, crc) = read_partition_array(reader, block_size, header)?;
+	if crc != stored.partition_array_crc32 {
+		issues.push(IntegrityIssue::PartitionArrayCrcMismatch {header: kind, stored: stored.partition_array_crc32, computed: crc});
+	}
+	
+	Ok(Some(parts))
+}
+
+pub(crate) fn read_header_at(reader: &mut (impl Read + Seek), block_size: u32, lba: u64) -> io::Result<StoredGptHeader> {
+	reader.seek(SeekFrom::Start(lba * block_size as u64))?;
+	StoredGptHeader::read_from(reader)
+}
+
+/// Reads all partition entries described by the given header,
+/// returns them together with the crc over the raw array.
+pub(crate) fn read_partition_array(reader: &mut (impl Read + Seek), block_size: u32, header: &GptHeader) -> io::Result<(Vec<GptPartition>, u32)> {
+	let entry_size = header.partition_entry_size as usize;
+	if entry_size < 128 {
+		return Err(invalid_data("partition entry size smaller than 128 bytes"));
+	}
+	
+	// Check the size before allocating, it comes straight from the disk
+	let disk_size_lba = reader.seek(SeekFrom::End(0))? / block_size as u64;
+	let array_end = header.partition_array_start_lba.checked_add(array_size_lba(header, block_size));
+	if array_end.is_none_or(|end| end > disk_size_lba) {
+		return Err(invalid_data("partition array lies outside the disk"));
+	}
+	
+	// Read raw array
+	let mut raw = vec![0u8; header.num_partition_entries as usize * entry_size];
+	reader.seek(SeekFrom::Start(header.partition_array_start_lba * block_size as u64))?;
+	reader.read_exact(&mut raw)?;
+	
+	let crc = crc32::checksum_ieee(&raw);
+	
+	// Parse entries
+	let mut partitions = Vec::with_capacity(header.num_partition_entries as usize);
+	for mut entry in raw.chunks_exact(entry_size) {
+		let partition_type_guid = read_guid(&mut entry)?;
+		let unique_guid = read_guid(&mut entry)?;
+		let start_lba = entry.read_u64::<LE>()?;
+		let end_lba_incl = entry.read_u64::<LE>()?;
+		let attributes = GptPartitionAttribs::from_bits(entry.read_u64::<LE>()?);
+		
+		let mut partition_name = [0u16; 36];
+		entry.read_u16_into::<LE>(&mut partition_name)?;
+		
+		partitions.push(GptPartition {
+			partition_type_guid,
+			unique_guid,
+			size_in_lba: end_lba_incl.saturating_add(1).saturating_sub(start_lba),
+			attributes,
+			partition_name: PartitionName::from_raw(partition_name),
+			start_lba,
+			end_lba_incl,
+		});
+	}
+	
+	Ok((partitions, crc))
+}
+
+/// Number of lbas the partition array of the given header occupies.
+pub(crate) fn array_size_lba(header: &GptHeader, block_size: u32) -> u64 {
+	PartitionArrayLayout {
+		num_entries: header.num_partition_entries,
+		entry_size: header.partition_entry_size,
+	}.size_lba(block_size)
+}
+
+fn default_alignment_lba(block_size: u32) -> u64 {
+	cmp::max(1, DEFAULT_PARTITION_ALIGNMENT / block_size as u64)
+}
+
+fn align_up(lba: u64, alignment_lba: u64) -> u64 {
+	lba.div_ceil(alignment_lba) * alignment_lba
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, msg)
 }
@@ -0,0 +1,175 @@
+use std::{fmt, str};
+
+use serde::{Deserialize, Serialize};
+
+/// A guid as used by gpt and uefi.
+///
+/// Written like any uuid (`"C12A7328-F81F-11D2-BA4B-00A0C93EC93B"`),
+/// but stored on disk in efi's mixed endian layout: the first three fields are little endian,
+/// the last eight bytes stay in order.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Guid(uuid::Uuid);
+
+impl Guid {
+	/// The guid as written, `0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B` for `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`.
+	pub const fn from_u128(v: u128) -> Guid {
+		Guid(uuid::Uuid::from_u128(v))
+	}
+	
+	/// Bytes in the order the guid is written, see [`Guid::from_efi_bytes`] for the on disk layout.
+	pub const fn from_bytes(bytes: [u8; 16]) -> Guid {
+		Guid(uuid::Uuid::from_bytes(bytes))
+	}
+	
+	pub const fn nil() -> Guid {
+		Guid::from_bytes([0; 16])
+	}
+	
+	pub fn new_v4() -> Guid {
+		Guid(uuid::Uuid::new_v4())
+	}
+	
+	/// Name based guid (sha-1, rfc 4122), the same namespace and name always give the same guid.
+	pub fn new_v5(namespace: &Guid, name: &[u8]) -> Guid {
+		Guid(uuid::Uuid::new_v5(&namespace.0, name))
+	}
+	
+	pub fn is_nil(&self) -> bool {
+		self.0.is_nil()
+	}
+	
+	pub fn as_u128(&self) -> u128 {
+		self.0.as_u128()
+	}
+	
+	pub fn as_bytes(&self) -> &[u8; 16] {
+		self.0.as_bytes()
+	}
+	
+	/// The first four bytes as a little endian u32, for the 32 bit ids of mbr disks and fat volumes.
+	pub fn first_u32(&self) -> u32 {
+		let bytes = self.as_bytes();
+		u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+	}
+	
+	pub fn as_uuid(&self) -> &uuid::Uuid {
+		&self.0
+	}
+	
+	/// The guid in efi's mixed endian layout, as stored in gpt headers and partition entries.
+	pub fn to_efi_bytes(&self) -> [u8; 16] {
+		let mut bytes = *self.as_bytes();
+		bytes[0..4].reverse();
+		bytes[4..6].reverse();
+		bytes[6..8].reverse();
+		bytes
+	}
+	
+	/// Inverse of [`Guid::to_efi_bytes`].
+	pub fn from_efi_bytes(mut bytes: [u8; 16]) -> Guid {
+		bytes[0..4].reverse();
+		bytes[4..6].reverse();
+		bytes[6..8].reverse();
+		Guid::from_bytes(bytes)
+	}
+}
+
+impl From<uuid::Uuid> for Guid {
+	fn from(uuid: uuid::Uuid) -> Self {
+		Guid(uuid)
+	}
+}
+
+impl From<Guid> for uuid::Uuid {
+	fn from(guid: Guid) -> Self {
+		guid.0
+	}
+}
+
+impl str::FromStr for Guid {
+	type Err = uuid::Error;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		uuid::Uuid::parse_str(s).map(Guid)
+	}
+}
+
+impl fmt::Display for Guid {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fmt::Display::fmt(&self.0, f)
+	}
+}
+
+impl fmt::Debug for Guid {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fmt::Display::fmt(&self.0, f)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use proptest::prelude::*;
+	
+	/// Guids with their on disk bytes as listed in the uefi spec and written by other tools.
+	const KNOWN_GUIDS: &[(&str, [u8; 16])] = &[
+		// Efi system partition
+		("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", *b"\x28\x73\x2A\xC1\x1F\xF8\xD2\x11\xBA\x4B\x00\xA0\xC9\x3E\xC9\x3B"),
+		// Microsoft basic data
+		("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", *b"\xA2\xA0\xD0\xEB\xE5\xB9\x33\x44\x87\xC0\x68\xB6\xB7\x26\x99\xC7"),
+		// Linux filesystem data
+		("0FC63DAF-8483-4772-8E79-3D69D8477DE4", *b"\xAF\x3D\xC6\x0F\x83\x84\x72\x47\x8E\x79\x3D\x69\xD8\x47\x7D\xE4"),
+		// Nell bootstash
+		("77FFD558-C91D-42E0-B03D-7F1EFD959111", *b"\x58\xD5\xFF\x77\x1D\xC9\xE0\x42\xB0\x3D\x7F\x1E\xFD\x95\x91\x11"),
+	];
+	
+	#[test]
+	pub fn known_efi_bytes() {
+		for (text, efi_bytes) in KNOWN_GUIDS.iter() {
+			let guid = text.parse::<Guid>().unwrap();
+			assert_eq!(&guid.to_efi_bytes(), efi_bytes, "{}", text);
+			assert_eq!(Guid::from_efi_bytes(*efi_bytes), guid, "{}", text);
+		}
+		
+		assert_eq!(Guid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B), KNOWN_GUIDS[0].0.parse().unwrap());
+		assert_eq!(Guid::nil().to_efi_bytes(), [0; 16]);
+		
+		// From the text order bytes, not the efi ones
+		assert_eq!(Guid::from_u128(0xC12A7328_F81F_11D2_BA4B_00A0C93EC93B).first_u32(), 0x28732AC1);
+	}
+	
+	#[test]
+	pub fn name_based() {
+		// Example of rfc 4122, "www.example.com" in the dns namespace
+		let dns = Guid::from_u128(0x6BA7B810_9DAD_11D1_80B4_00C04FD430C8);
+		assert_eq!(Guid::new_v5(&dns, b"www.example.com"), Guid::from_u128(0x2ED6657D_E927_568B_95E1_2665A8AEA6A2));
+		assert_ne!(Guid::new_v5(&dns, b"www.example.org"), Guid::new_v5(&dns, b"www.example.com"));
+	}
+	
+	proptest! {
+		#[test]
+		fn efi_bytes_round_trip(v: u128) {
+			let guid = Guid::from_u128(v);
+			prop_assert_eq!(Guid::from_efi_bytes(guid.to_efi_bytes()), guid);
+		}
+		
+		#[test]
+		fn efi_bytes_layout(v: u128) {
+			// The fields of the written form, little endian on disk
+			let guid = Guid::from_u128(v);
+			let efi_bytes = guid.to_efi_bytes();
+			prop_assert_eq!(u32::from_le_bytes([efi_bytes[0], efi_bytes[1], efi_bytes[2], efi_bytes[3]]), (v >> 96) as u32);
+			prop_assert_eq!(u16::from_le_bytes([efi_bytes[4], efi_bytes[5]]), (v >> 80) as u16);
+			prop_assert_eq!(u16::from_le_bytes([efi_bytes[6], efi_bytes[7]]), (v >> 64) as u16);
+			prop_assert_eq!(&efi_bytes[8..], &v.to_be_bytes()[8..]);
+		}
+		
+		#[test]
+		fn text_round_trip(v: u128) {
+			let guid = Guid::from_u128(v);
+			prop_assert_eq!(guid.to_string().parse::<Guid>().unwrap(), guid);
+		}
+	}
+}
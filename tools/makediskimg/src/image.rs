@@ -0,0 +1,238 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use fatfs::{Dir, FileSystem, FsOptions, ReadWriteSeek};
+
+use crate::Error;
+use crate::blockdev::{BlockIo, IoDevice, PartitionDevice};
+use crate::fat;
+use crate::gpt::{GptDisk, GptPartition};
+use crate::manifest::FileSpec;
+use crate::sparse::{self, SparseFile};
+
+/// Finds a partition of an existing image by its name, or by its position if no partition has that name.
+pub fn find_partition<'a>(disk: &'a GptDisk, selector: &str) -> Result<&'a GptPartition, Error> {
+	let by_name = disk.partitions().find(|p| p.partition_name.to_string() == selector);
+	let by_index = || selector.parse().ok().and_then(|i| disk.partition(i));
+	
+	by_name.or_else(by_index).ok_or_else(|| {
+		let names = disk.partitions()
+			.map(|p| format!("\"{}\"", p.partition_name))
+			.collect::<Vec<_>>();
+		Error::invalid_layout(format!("no partition \"{}\", the image has {}", selector, names.join(", ")))
+	})
+}
+
+/// Copies files into the fat filesystem of a partition, replacing files that already exist.
+/// The rest of the image, including the other partitions, is left as it is.
+pub fn update_files<T: Read + Write + Seek>(img: T, disk: &GptDisk, partition: &GptPartition, files: &[FileSpec]) -> Result<(), Error> {
+	let imports = fat::collect_imports(files)?;
+	
+	let mut device = IoDevice::new(img, disk.block_size()).map_err(image_error)?;
+	let mut storage = partition_io(&mut device, partition)?;
+	{
+		let mut vfs = FileSystem::new(&mut storage, FsOptions::new()).map_err(format_error)?;
+		fat::import(&mut vfs, &imports)?;
+		vfs.unmount().map_err(format_error)?;
+	}
+	storage.flush().map_err(image_error)?;
+	
+	Ok(())
+}
+
+/// Copies the raw content of a partition to a (sparse) file.
+pub fn extract_partition<T: Read + Write + Seek>(img: T, disk: &GptDisk, partition: &GptPartition, out_path: &Path) -> Result<(), Error> {
+	let mut device = IoDevice::new(img, disk.block_size()).map_err(image_error)?;
+	let mut storage = partition_io(&mut device, partition)?;
+	
+	let out_error = |e| Error::io(format!("can't write {}", out_path.display()), e);
+	let out_file = OpenOptions::new()
+		.create(true).write(true).read(true).truncate(true)
+		.open(out_path)
+		.map_err(out_error)?;
+	let mut out = SparseFile::new(out_file, sparse::DEFAULT_BUFFER_SIZE).map_err(out_error)?;
+	
+	// Read errors are those of the image, write errors those of the output
+	let mut buf = vec![0u8; sparse::DEFAULT_BUFFER_SIZE];
+	loop {
+		let read = storage.read(&mut buf).map_err(image_error)?;
+		if read == 0 {
+			break;
+		}
+		out.write_all(&buf[..read]).map_err(out_error)?;
+	}
+	out.flush().map_err(out_error)?;
+	
+	Ok(())
+}
+
+/// Copies files or directories out of the fat filesystem of a partition into `out_dir`, each keeping its name.
+/// `/` extracts the whole filesystem.
+pub fn extract_files<T: Read + Write + Seek>(img: T, disk: &GptDisk, partition: &GptPartition, vfs_paths: &[&str], out_dir: &Path) -> Result<(), Error> {
+	let mut device = IoDevice::new(img, disk.block_size()).map_err(image_error)?;
+	let mut storage = partition_io(&mut device, partition)?;
+	let vfs = FileSystem::new(&mut storage, FsOptions::new()).map_err(format_error)?;
+	
+	fs::create_dir_all(out_dir).map_err(|e| Error::io(format!("can't create {}", out_dir.display()), e))?;
+	for vfs_path in vfs_paths.iter() {
+		let segs = vfs_path.split('/').filter(|s| !s.is_empty()).collect::<Vec<_>>();
+		
+		// The root's content goes straight into the output directory
+		let (name, parent) = match segs.split_last() {
+			Some((name, parent)) => (*name, parent),
+			None => {
+				extract_dir(&vfs.root_dir(), out_dir)?;
+				continue;
+			}
+		};
+		
+		let not_found = || Error::invalid_layout(format!("no file {} in the filesystem", vfs_path));
+		let parent_dir = match parent.is_empty() {
+			true => vfs.root_dir(),
+			false => vfs.root_dir().open_dir(&parent.join("/")).map_err(|_| not_found())?,
+		};
+		let entry = parent_dir.iter()
+			.filter_map(Result::ok)
+			.find(|e| e.file_name().eq_ignore_ascii_case(name))
+			.ok_or_else(not_found)?;
+		
+		let out_path = out_dir.join(entry.file_name());
+		match entry.is_dir() {
+			true => extract_dir(&entry.to_dir(), &out_path)?,
+			false => extract_file(&mut entry.to_file(), &out_path)?,
+		}
+	}
+	
+	Ok(())
+}
+
+fn extract_dir<T: ReadWriteSeek>(dir: &Dir<T>, out_dir: &Path) -> Result<(), Error> {
+	fs::create_dir_all(out_dir).map_err(|e| Error::io(format!("can't create {}", out_dir.display()), e))?;
+	
+	for entry in dir.iter() {
+		let entry = entry.map_err(format_error)?;
+		let name = entry.file_name();
+		if name == "." || name == ".." {
+			continue;
+		}
+		
+		let out_path = out_dir.join(&name);
+		match entry.is_dir() {
+			true => extract_dir(&entry.to_dir(), &out_path)?,
+			false => extract_file(&mut entry.to_file(), &out_path)?,
+		}
+	}
+	Ok(())
+}
+
+fn extract_file(file: &mut impl Read, out_path: &Path) -> Result<(), Error> {
+	let out_error = |e| Error::io(format!("can't write {}", out_path.display()), e);
+	let mut out = File::create(out_path).map_err(out_error)?;
+	
+	let mut buf = [0u8; 64 * 1024];
+	loop {
+		let read = file.read(&mut buf).map_err(format_error)?;
+		if read == 0 {
+			break;
+		}
+		out.write_all(&buf[..read]).map_err(out_error)?;
+	}
+	Ok(())
+}
+
+fn partition_io<'a, T: Read + Write + Seek>(device: &'a mut IoDevice<T>, partition: &GptPartition) -> Result<BlockIo<PartitionDevice<'a, IoDevice<T>>>, Error> {
+	let partition = PartitionDevice::new(device, partition.start_lba, partition.size_in_lba).map_err(image_error)?;
+	Ok(BlockIo::new(partition))
+}
+
+fn image_error(e: io::Error) -> Error {
+	Error::io("can't access the image", e)
+}
+
+fn format_error(error: io::Error) -> Error {
+	Error::FilesystemFormat {partition: None, error}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use std::io::SeekFrom;
+	
+	use crate::file_spec;
+	use crate::gpt::{CreatePartitionOptions, GptPartitionAttribs, PartitionName, partition_types};
+	use crate::memdisk::MemDisk;
+	use crate::test_util::{format_fat, gpt_image, temp_dir};
+	
+	/// A disk with two empty fat partitions, "ESP" and "Nell Boot".
+	fn test_image() -> (GptDisk, MemDisk) {
+		let partitions = ["ESP", "Nell Boot"].iter()
+			.map(|name| CreatePartitionOptions::new(partition_types::EFI_SYSTEM, None, 4096, GptPartitionAttribs::zero(), PartitionName::new(name).unwrap()))
+			.collect();
+		let (disk, mut image) = gpt_image(partitions);
+		for partition in disk.partitions() {
+			format_fat(&mut image, partition, &[]);
+		}
+		(disk, image)
+	}
+	
+	#[test]
+	pub fn find_partitions() {
+		let (disk, _) = test_image();
+		assert_eq!(find_partition(&disk, "Nell Boot").unwrap().partition_name.to_string(), "Nell Boot");
+		assert_eq!(find_partition(&disk, "0").unwrap().partition_name.to_string(), "ESP");
+		
+		let err = find_partition(&disk, "Nell System").unwrap_err().to_string();
+		assert!(err.contains("the image has \"ESP\", \"Nell Boot\""), "{}", err);
+	}
+	
+	#[test]
+	pub fn update_and_extract() {
+		let dir = temp_dir("image_update");
+		let (disk, mut img) = test_image();
+		let esp = find_partition(&disk, "ESP").unwrap();
+		let nell_boot = find_partition(&disk, "Nell Boot").unwrap();
+		
+		fs::write(dir.join("kernel.elf"), vec![1; 5000]).unwrap();
+		fs::write(dir.join("bootx64.efi"), vec![2; 3000]).unwrap();
+		update_files(&mut img, &disk, nell_boot, &[file_spec(&dir.join("kernel.elf"), "/kernel.elf")]).unwrap();
+		update_files(&mut img, &disk, esp, &[file_spec(&dir.join("bootx64.efi"), "/efi/boot/bootx64.efi")]).unwrap();
+		let esp_before = {
+			let mut raw = vec![0u8; esp.size_in_lba as usize * 512];
+			img.seek(SeekFrom::Start(esp.start_lba * 512)).unwrap();
+			img.read_exact(&mut raw).unwrap();
+			raw
+		};
+		
+		// Replace the kernel with a smaller one, the esp stays untouched
+		fs::write(dir.join("kernel.elf"), vec![3; 100]).unwrap();
+		update_files(&mut img, &disk, nell_boot, &[file_spec(&dir.join("kernel.elf"), "/kernel.elf")]).unwrap();
+		
+		let out = dir.join("out");
+		extract_files(&mut img, &disk, nell_boot, &["/kernel.elf"], &out).unwrap();
+		assert_eq!(fs::read(out.join("kernel.elf")).unwrap(), vec![3; 100]);
+		
+		extract_files(&mut img, &disk, esp, &["/"], &out.join("esp")).unwrap();
+		assert_eq!(fs::read(out.join("esp/efi/boot/bootx64.efi")).unwrap(), vec![2; 3000]);
+		assert!(extract_files(&mut img, &disk, esp, &["/efi/nope"], &out).is_err());
+		
+		extract_partition(&mut img, &disk, esp, &out.join("esp.img")).unwrap();
+		assert!(fs::read(out.join("esp.img")).unwrap() == esp_before);
+		
+		assert_eq!(GptDisk::check_integrity(&mut img).unwrap(), vec![]);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+	
+	#[test]
+	pub fn update_reports_full_partition() {
+		let dir = temp_dir("image_full");
+		let (disk, mut img) = test_image();
+		
+		fs::write(dir.join("big.bin"), vec![1; 3 * 1024 * 1024]).unwrap();
+		let err = update_files(&mut img, &disk, disk.partition(0).unwrap(), &[file_spec(&dir.join("big.bin"), "/big.bin")]).unwrap_err();
+		assert!(matches!(err, Error::ContentTooLarge {..}), "{}", err);
+		
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,492 @@
+use std::{fmt, io};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use fatfs::{Dir, FsOptions, ReadWriteSeek};
+use serde::Serialize;
+
+use crate::blockdev::{BlockIo, IoDevice, PartitionDevice};
+use crate::gpt::{self, GptHeader, GptPartition, Guid, HeaderKind, StoredGptHeader, partition_types};
+use crate::mbr::{self, MasterBootRecord};
+
+/// Everything [`inspect`] found out about an image.
+/// Printed as a listing by its `Display` impl, or serialized to json.
+#[derive(Debug, Serialize)]
+pub struct DiskReport {
+	pub size_bytes: u64,
+	/// Probed from the gpt header, the default block size for disks without one
+	pub block_size: u32,
+	pub mbr: MbrReport,
+	/// Primary and backup header, empty if the disk has no gpt
+	pub gpt_headers: Vec<GptHeaderReport>,
+	/// Used entries of the first intact partition array
+	pub partitions: Vec<PartitionReport>,
+	/// Problems found by [`GptDisk::check_integrity`](gpt::GptDisk::check_integrity)
+	pub issues: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MbrReport {
+	pub signature: u16,
+	pub signature_ok: bool,
+	pub disk_signature: u32,
+	/// Non-empty entries
+	pub entries: Vec<MbrEntryReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MbrEntryReport {
+	pub index: usize,
+	pub bootable: bool,
+	pub os_type: mbr::MbrOsType,
+	pub start_lba: u32,
+	pub size_in_lba: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GptHeaderReport {
+	pub kind: HeaderKind,
+	/// Where the header was read from
+	pub lba: u64,
+	pub signature_ok: bool,
+	pub revision: u32,
+	pub my_lba: u64,
+	pub alternate_lba: u64,
+	pub first_usable_lba: u64,
+	pub last_usable_lba: u64,
+	pub disk_guid: Guid,
+	pub partition_array_start_lba: u64,
+	pub num_partition_entries: u32,
+	pub partition_entry_size: u32,
+	pub header_crc32: u32,
+	pub header_crc_ok: bool,
+	pub partition_array_crc32: u32,
+	/// `None` if the partition array can't be read
+	pub partition_array_crc_ok: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionReport {
+	/// Index in the partition array
+	pub index: usize,
+	pub type_guid: Guid,
+	pub type_name: Option<&'static str>,
+	pub unique_guid: Guid,
+	pub name: String,
+	pub start_lba: u64,
+	pub end_lba_incl: u64,
+	pub size_bytes: u64,
+	pub attributes: String,
+	/// The fat filesystem on the partition, if there is one
+	pub filesystem: Option<FatReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FatReport {
+	pub fat_type: String,
+	pub volume_label: String,
+	pub files: Vec<FileReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+	pub name: String,
+	pub dir: bool,
+	/// Zero for directories
+	pub size: u64,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub children: Vec<FileReport>,
+}
+
+/// Reads the layout of an image: the mbr, the gpt headers, the partitions and their fat file trees.
+///
+/// Damaged structures are reported rather than failing,
+/// only a disk that can't be read fails. Nothing is written to the disk.
+pub fn inspect<T: Read + Seek>(disk: T) -> io::Result<DiskReport> {
+	// The block device and fatfs want a writable stream
+	let mut disk = ReadOnly(disk);
+	let size_bytes = disk.seek(SeekFrom::End(0))?;
+	
+	disk.seek(SeekFrom::Start(0))?;
+	let raw_mbr = MasterBootRecord::read_from(&mut disk)?;
+	let mbr = MbrReport {
+		signature: raw_mbr.signature,
+		signature_ok: raw_mbr.signature == mbr::PROTECTIVE_MBR_SIGNATURE,
+		disk_signature: u32::from_le_bytes(raw_mbr.unique_mbr_signature),
+		entries: raw_mbr.partitions.iter()
+			.enumerate()
+			.filter(|(_, entry)| !entry.is_empty())
+			.map(|(index, entry)| MbrEntryReport {
+				index,
+				bootable: entry.boot_indicator == 0x80,
+				os_type: entry.os_type,
+				start_lba: entry.starting_lba(),
+				size_in_lba: entry.size_in_lba(),
+			})
+			.collect(),
+	};
+	
+	let block_size = match gpt::probe_block_size(&mut disk, size_bytes)? {
+		Some(block_size) => block_size,
+		None => {
+			return Ok(DiskReport {
+				size_bytes,
+				block_size: gpt::DEFAULT_BLOCK_SIZE as u32,
+				mbr,
+				gpt_headers: Vec::new(),
+				partitions: Vec::new(),
+				issues: vec!["no gpt header found".to_owned()],
+			});
+		}
+	};
+	let disk_size_lba = size_bytes / block_size as u64;
+	
+	// Headers, the backup one found like check_integrity does
+	let primary = gpt::read_header_at(&mut disk, block_size, 1)?;
+	let backup_lba = match primary.header.alternate_lba {
+		lba if lba > 1 && lba < disk_size_lba => lba,
+		_ => disk_size_lba - 1,
+	};
+	let backup = gpt::read_header_at(&mut disk, block_size, backup_lba)?;
+	
+	let mut gpt_headers = Vec::with_capacity(2);
+	let mut partition_array = None;
+	for (kind, lba, stored) in [(HeaderKind::Primary, 1, &primary), (HeaderKind::Backup, backup_lba, &backup)].iter() {
+		let array = read_partition_array(&mut disk, block_size, disk_size_lba, &stored.header)?;
+		let partition_array_crc_ok = array.as_ref().map(|(_, crc)| *crc == stored.partition_array_crc32);
+		
+		if partition_array.is_none() && stored.is_valid() && partition_array_crc_ok == Some(true) {
+			partition_array = array.map(|(parts, _)| parts);
+		}
+		gpt_headers.push(header_report(*kind, *lba, stored, partition_array_crc_ok));
+	}
+	
+	// Partitions
+	let mut partitions = Vec::new();
+	let mut device = IoDevice::new(&mut disk, block_size)?;
+	for (index, part) in partition_array.unwrap_or_default().into_iter().enumerate() {
+		if part.partition_type_guid == partition_types::UNUSED {
+			continue;
+		}
+		
+		let filesystem = match PartitionDevice::new(&mut device, part.start_lba, part.size_in_lba) {
+			Ok(partition) => fat_report(BlockIo::new(partition))?,
+			Err(_) => None,
+		};
+		partitions.push(partition_report(index, &part, block_size, filesystem));
+	}
+	
+	let issues = gpt::GptDisk::check_integrity(&mut disk)?
+		.iter()
+		.map(ToString::to_string)
+		.collect();
+	
+	Ok(DiskReport {
+		size_bytes,
+		block_size,
+		mbr,
+		gpt_headers,
+		partitions,
+		issues,
+	})
+}
+
+/// Reads a partition array if the header describes one that fits on the disk,
+/// returns the partitions and the crc over the array.
+fn read_partition_array(disk: &mut (impl Read + Seek), block_size: u32, disk_size_lba: u64, header: &GptHeader) -> io::Result<Option<(Vec<GptPartition>, u32)>> {
+	let fits = header.partition_entry_size >= 128
+		&& header.partition_array_start_lba.checked_add(gpt::array_size_lba(header, block_size)).is_some_and(|end| end <= disk_size_lba);
+	if !fits {
+		return Ok(None);
+	}
+	
+	gpt::read_partition_array(disk, block_size, header).map(Some)
+}
+
+/// Passes reads through and fails all writes.
+struct ReadOnly<T>(T);
+
+impl<T: Read> Read for ReadOnly<T> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.0.read(buf)
+	}
+}
+
+impl<T> Write for ReadOnly<T> {
+	fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+		Err(io::Error::new(io::ErrorKind::PermissionDenied, "the disk is opened read-only"))
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl<T: Seek> Seek for ReadOnly<T> {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		self.0.seek(pos)
+	}
+}
+
+fn header_report(kind: HeaderKind, lba: u64, stored: &StoredGptHeader, partition_array_crc_ok: Option<bool>) -> GptHeaderReport {
+	let header = &stored.header;
+	
+	GptHeaderReport {
+		kind,
+		lba,
+		signature_ok: header.signature == gpt::GPT_HEADER_SIGNATURE,
+		revision: header.revision,
+		my_lba: header.my_lba,
+		alternate_lba: header.alternate_lba,
+		first_usable_lba: header.first_usable_lba,
+		last_usable_lba: header.last_usable_lba,
+		disk_guid: header.disk_guid,
+		partition_array_start_lba: header.partition_array_start_lba,
+		num_partition_entries: header.num_partition_entries,
+		partition_entry_size: header.partition_entry_size,
+		header_crc32: stored.header_crc32,
+		header_crc_ok: stored.computed_header_crc32() == stored.header_crc32,
+		partition_array_crc32: stored.partition_array_crc32,
+		partition_array_crc_ok,
+	}
+}
+
+fn partition_report(index: usize, part: &GptPartition, block_size: u32, filesystem: Option<FatReport>) -> PartitionReport {
+	PartitionReport {
+		index,
+		type_guid: part.partition_type_guid,
+		type_name: partition_types::type_name(part.partition_type_guid),
+		unique_guid: part.unique_guid,
+		name: part.partition_name.to_string(),
+		start_lba: part.start_lba,
+		end_lba_incl: part.end_lba_incl,
+		size_bytes: part.size_in_lba.saturating_mul(block_size as u64),
+		attributes: part.attributes.to_string(),
+		filesystem,
+	}
+}
+
+/// Lists the fat filesystem of a partition, `None` if it doesn't hold one.
+fn fat_report<T: ReadWriteSeek>(mut partition: T) -> io::Result<Option<FatReport>> {
+	// Don't let fatfs guess at partitions without a boot sector
+	let mut boot_sector = [0u8; 512];
+	partition.seek(SeekFrom::Start(0))?;
+	if partition.read_exact(&mut boot_sector).is_err() || boot_sector[510..] != [0x55, 0xAA] {
+		return Ok(None);
+	}
+	partition.seek(SeekFrom::Start(0))?;
+	
+	let vfs = match fatfs::FileSystem::new(&mut partition, FsOptions::new()) {
+		Ok(vfs) => vfs,
+		Err(_) => return Ok(None),
+	};
+	
+	let files = file_reports(&vfs.root_dir())?;
+	Ok(Some(FatReport {
+		fat_type: format!("{:?}", vfs.fat_type()).to_lowercase(),
+		volume_label: vfs.volume_label(),
+		files,
+	}))
+}
+
+fn file_reports<T: ReadWriteSeek>(dir: &Dir<T>) -> io::Result<Vec<FileReport>> {
+	let mut files = Vec::new();
+	for entry in dir.iter() {
+		let entry = entry?;
+		let name = entry.file_name();
+		if name == "." || name == ".." {
+			continue;
+		}
+		
+		files.push(FileReport {
+			dir: entry.is_dir(),
+			size: if entry.is_dir() {0} else {entry.len()},
+			children: if entry.is_dir() {file_reports(&entry.to_dir())?} else {Vec::new()},
+			name,
+		});
+	}
+	Ok(files)
+}
+
+impl fmt::Display for DiskReport {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "disk: {}, {} blocks of {} bytes", format_size(self.size_bytes), self.size_bytes / self.block_size as u64, self.block_size)?;
+		
+		let ok = |ok: bool| if ok {"ok"} else {"bad"};
+		
+		writeln!(f, "\nmbr: signature {:#06x} ({}), disk signature {:#010x}", self.mbr.signature, ok(self.mbr.signature_ok), self.mbr.disk_signature)?;
+		for entry in self.mbr.entries.iter() {
+			writeln!(f, "  {}: type {:#04x}{}, lba {}..={}",
+				entry.index,
+				entry.os_type,
+				if entry.bootable {", bootable"} else {""},
+				entry.start_lba,
+				(entry.start_lba as u64 + entry.size_in_lba as u64).saturating_sub(1))?;
+		}
+		
+		for header in self.gpt_headers.iter() {
+			writeln!(f, "\n{} gpt header at lba {}: signature {}, crc {:#010x} ({}), partition array crc {:#010x} ({})",
+				header.kind,
+				header.lba,
+				ok(header.signature_ok),
+				header.header_crc32,
+				ok(header.header_crc_ok),
+				header.partition_array_crc32,
+				header.partition_array_crc_ok.map_or("unreadable", ok))?;
+			writeln!(f, "  revision {:#010x}, my lba {}, alternate lba {}", header.revision, header.my_lba, header.alternate_lba)?;
+			writeln!(f, "  disk guid {}", header.disk_guid)?;
+			writeln!(f, "  usable lba {}..={}", header.first_usable_lba, header.last_usable_lba)?;
+			writeln!(f, "  partition array at lba {}, {} entries of {} bytes", header.partition_array_start_lba, header.num_partition_entries, header.partition_entry_size)?;
+		}
+		
+		for part in self.partitions.iter() {
+			writeln!(f, "\npartition {}: \"{}\"", part.index, part.name)?;
+			match part.type_name {
+				Some(name) => writeln!(f, "  type {} ({})", name, part.type_guid)?,
+				None => writeln!(f, "  type {}", part.type_guid)?,
+			}
+			writeln!(f, "  guid {}", part.unique_guid)?;
+			writeln!(f, "  lba {}..={}, {}", part.start_lba, part.end_lba_incl, format_size(part.size_bytes))?;
+			writeln!(f, "  attributes {}", part.attributes)?;
+			
+			if let Some(fs) = &part.filesystem {
+				writeln!(f, "  {} volume \"{}\"", fs.fat_type, fs.volume_label.trim_end())?;
+				write_tree(f, &fs.files, 2)?;
+			}
+		}
+		
+		match self.issues.is_empty() {
+			true => writeln!(f, "\nno issues found"),
+			false => {
+				writeln!(f, "\nissues:")?;
+				for issue in self.issues.iter() {
+					writeln!(f, "  {}", issue)?;
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+fn write_tree(f: &mut fmt::Formatter, files: &[FileReport], depth: usize) -> fmt::Result {
+	for file in files.iter() {
+		match file.dir {
+			true => writeln!(f, "{:indent$}{}/", "", file.name, indent = depth * 2)?,
+			false => writeln!(f, "{:indent$}{} ({})", "", file.name, format_size(file.size), indent = depth * 2)?,
+		}
+		write_tree(f, &file.children, depth + 1)?;
+	}
+	Ok(())
+}
+
+/// Bytes with the largest binary unit that keeps the number at least 1.
+fn format_size(bytes: u64) -> String {
+	const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+	
+	if bytes < 1024 {
+		return format!("{} bytes", bytes);
+	}
+	let mut size = bytes as f64 / 1024.0;
+	let mut unit = 0;
+	while size >= 1024.0 && unit < UNITS.len() - 1 {
+		size /= 1024.0;
+		unit += 1;
+	}
+	format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use crate::gpt::{CreatePartitionOptions, GptPartitionAttribs, PartitionName};
+	use crate::memdisk::MemDisk;
+	use crate::test_util::{TEST_DISK_SIZE_LBA, format_fat, gpt_image};
+	
+	/// A disk with an efi system partition holding `/efi/boot/bootx64.efi` and an empty nell boot partition.
+	fn test_image() -> MemDisk {
+		let (disk, mut image) = gpt_image(vec![
+			CreatePartitionOptions::new(partition_types::EFI_SYSTEM, None, 8192, GptPartitionAttribs::zero(), PartitionName::new("ESP").unwrap()),
+			CreatePartitionOptions::new(partition_types::NELL_BOOT, None, 100, GptPartitionAttribs::type_specific(12), PartitionName::new("Nell Boot").unwrap()),
+		]);
+		format_fat(&mut image, disk.partition(0).unwrap(), &[("efi/boot/bootx64.efi", &[0xCC; 1000])]);
+		image
+	}
+	
+	#[test]
+	pub fn reports_layout_and_files() {
+		let mut image = test_image();
+		let report = inspect(&mut image).unwrap();
+		
+		assert_eq!(report.block_size, 512);
+		assert!(report.mbr.signature_ok);
+		assert_eq!(report.mbr.entries.len(), 1);
+		assert_eq!(report.mbr.entries[0].os_type, mbr::os_types::GPT_PROTECTIVE);
+		
+		assert_eq!(report.gpt_headers.len(), 2);
+		assert!(report.gpt_headers.iter().all(|h| h.header_crc_ok && h.partition_array_crc_ok == Some(true)));
+		assert_eq!(report.gpt_headers[1].lba, TEST_DISK_SIZE_LBA - 1);
+		assert!(report.issues.is_empty(), "{:?}", report.issues);
+		
+		assert_eq!(report.partitions.len(), 2);
+		let esp = &report.partitions[0];
+		assert_eq!(esp.type_name, Some("EFI System"));
+		assert_eq!(esp.name, "ESP");
+		assert_eq!(esp.size_bytes, 8192 * 512);
+		
+		let fs = esp.filesystem.as_ref().unwrap();
+		assert_eq!(fs.fat_type, "fat16");
+		assert_eq!(fs.files.len(), 1);
+		let boot = &fs.files[0].children[0];
+		assert_eq!((boot.name.as_str(), boot.dir), ("boot", true));
+		assert_eq!((boot.children[0].name.as_str(), boot.children[0].size), ("bootx64.efi", 1000));
+		
+		let nell = &report.partitions[1];
+		assert_eq!(nell.type_name, Some("Nell boot"));
+		assert_eq!(nell.attributes, "type:12");
+		assert!(nell.filesystem.is_none());
+		
+		let text = report.to_string();
+		assert!(text.contains("type EFI System (c12a7328-f81f-11d2-ba4b-00a0c93ec93b)"), "{}", text);
+		assert!(text.contains("      bootx64.efi (1000 bytes)"), "{}", text);
+		
+		let json = serde_json::to_value(&report).unwrap();
+		assert_eq!(json["gpt_headers"][0]["kind"], "primary");
+		assert_eq!(json["partitions"][0]["filesystem"]["files"][0]["children"][0]["name"], "boot");
+		assert_eq!(json["partitions"][1]["type_guid"], "77ffd558-c91d-42e0-b03d-7f1efd959111");
+		
+		// Works on streams that can't be written
+		let mut raw = Vec::new();
+		image.seek(SeekFrom::Start(0)).unwrap();
+		image.read_to_end(&mut raw).unwrap();
+		let read_only = inspect(io::Cursor::new(&raw[..])).unwrap();
+		assert_eq!(serde_json::to_value(&read_only).unwrap(), json);
+	}
+	
+	#[test]
+	pub fn reports_damaged_headers() {
+		let mut image = test_image();
+		
+		// Break the primary header crc, the backup still has the partitions
+		image.seek(SeekFrom::Start(512 + 16)).unwrap();
+		image.write_all(&[0xFF; 4]).unwrap();
+		
+		let report = inspect(&mut image).unwrap();
+		assert!(!report.gpt_headers[0].header_crc_ok);
+		assert!(report.gpt_headers[1].header_crc_ok);
+		assert_eq!(report.partitions.len(), 2);
+		assert!(report.issues.iter().any(|i| i.contains("primary header crc")), "{:?}", report.issues);
+		
+		// No gpt at all
+		let report = inspect(MemDisk::new_sparse(64 * 512)).unwrap();
+		assert!(!report.mbr.signature_ok);
+		assert!(report.gpt_headers.is_empty());
+		assert_eq!(report.issues, vec!["no gpt header found"]);
+	}
+	
+	#[test]
+	pub fn size_formatting() {
+		assert_eq!(format_size(512), "512 bytes");
+		assert_eq!(format_size(1536), "1.5 KiB");
+		assert_eq!(format_size(33_548_800), "32.0 MiB");
+	}
+}
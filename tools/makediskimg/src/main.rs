@@ -1,235 +1,732 @@
-#![feature(try_blocks)]
 //#![feature(const_generics)]
-#![feature(bool_to_option)]
 
-use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::Arg;
+use clap::{AppSettings, Arg, ArgGroup, SubCommand};
 use fatfs::{FatType, FsOptions, ReadWriteSeek};
-use widestring::{U16Str, U16String};
 
-use crate::gpt::{CreatePartitionOptions, GptDisk, GptPartitionAttribs, Guid};
-use crate::memdisk::MemDisk;
+use crate::blockdev::{BlockIo, IoDevice, PartitionDevice};
+use crate::fat::ImportEntry;
+use crate::gpt::{CreatePartitionOptions, GptDisk, GptPartitionAttribs, Guid, PartitionName};
+use crate::manifest::{ByteSize, DiskSpec, FileSpec, Filesystem, Manifest, PartitionSpec, Scheme};
+use crate::mbr::{MbrDisk, MbrOsType};
+use crate::reproducible::Reproducible;
+use crate::sparse::SparseFile;
 
+pub mod blockdev;
+pub mod error;
+pub mod fat;
 pub mod gpt;
+pub mod guid;
+pub mod image;
+pub mod inspect;
+pub mod manifest;
 pub mod mbr;
 pub mod memdisk;
+pub mod reproducible;
+pub mod sparse;
+#[cfg(test)]
+mod test_util;
 
-const NELL_BOOTSTASH_PARTITION_GPT_TYPE_GUID: Guid = Guid::from_u128(0x77ffd558_c91d_42e0_b03d_7f1efd959111);
+pub use crate::error::Error;
+
+/// Type specific attribute of nell partitions: the os must not write to the partition.
+/// Same bit as the read-only flag of microsoft basic data partitions.
+const NELL_PARTITION_ATTRIB_READ_ONLY: GptPartitionAttribs = GptPartitionAttribs::type_specific(12);
+
+const DEFAULT_IMAGE_PATH: &str = "build/boot.img";
+
+/// Partitions of the default layout, `update` finds them by name
+const ESP_NAME: &str = "UEFI System";
+const NELL_BOOT_NAME: &str = "Nell Boot";
+
+/// Where the default layout puts the bootloader
+const BOOTLOADER_TARGETS: [&str; 3] = [
+	// Note that the nell folder can be custom named to allow multiple installations (all using the same efi system partition, as it should)
+	"/efi/boot/nell_foo/nellbootx64.efi",
+	// Needed for automatic boot instead of getting dumped into the uefi shell
+	"/efi/boot/bootx64.efi",
+	"/nellbootx64.efi",
+];
+const KERNEL_TARGET: &str = "/kernel.elf";
+
+/// Options of `create` that configure the default layout
+const LAYOUT_ARGS: &[&str] = &["bootloaderefi", "kernelelf", "blocksize", "scheme", "hybridmbr", "mbrbootcode", "autosize", "headroom"];
 
 fn main() {
-	let matches = clap::App::new("makediskimg")
-		.arg(Arg::with_name("bootloaderefi").long("bootloaderefi").takes_value(true))
-		.arg(Arg::with_name("kernelelf").long("kernelelf").takes_value(true))
-		.get_matches();
-	
-	let bootloader_efi_path = PathBuf::from_str(matches.value_of("bootloaderefi")
-		.unwrap_or("../../bootloader_uefi/target/x86_64-unknown-uefi/debug/bootloader_uefi.efi")).unwrap();
-	
-	let kernel_path = PathBuf::from_str(matches.value_of("kernelelf").unwrap()).unwrap();
+	let matches = cli().get_matches();
 	
 //	// DEBUG:
 //	simple_logger::SimpleLogger::new()
 //		.with_level(log::LevelFilter::Trace)
 //		.init().unwrap();
 	
-	// Partitions:
-	// efi_system
-	// nell_boot
-	// nell_reserve
-	// nell_system
-	// nell_user
-	
-	let gpt_block_size = 512;
-//	let disk_size_lba = 33_548_800 * 2 / block_size;
-	let disk_size_lba = (33_548_800 * 4) / gpt_block_size; // for 2 parts
-//	let disk_size_lba = (33_548_800 * 2) / gpt_block_size; // for 1 part
-	
-	let img_path = PathBuf::from("build/boot.img");
-	
-	// Create gpt disk
-	let mut gpt_disk = GptDisk::new_empty(gpt_block_size as u32, disk_size_lba as u64, None);
-	
-	// Build efi system parition
-	let mut efi_partition = build_efi_partition(&bootloader_efi_path);
-	
-	gpt_disk.create_partition(CreatePartitionOptions::new(
-		gpt::partition_types::EFI_SYSTEM,
-		None,
-		(efi_partition.memdisk.size() / gpt_block_size) as u64,
-		GptPartitionAttribs::zero(),
-		utf16_to_array_nul(&U16String::from_str("UEFI System"))
-	));
-	
-	// Build nell bootstash partition
-	let mut bootstash_partition = build_bootstash_partition(&kernel_path);
-	
-	gpt_disk.create_partition(CreatePartitionOptions::new(
-		NELL_BOOTSTASH_PARTITION_GPT_TYPE_GUID,
-//		Guid::from_u128(0xEBD0A0A2_B9E5_4433_87C0_68B6B72699C7),
-//		gpt::partition_types::EFI_SYSTEM,
-		Some(Guid::from_u128(0xA4A4A4A4_A4A4_A4A4_A4A4_A4A4A4A4A4A4)),
-		(bootstash_partition.memdisk.size() / gpt_block_size) as u64,
-		GptPartitionAttribs::zero(),
-		utf16_to_array_nul(&U16String::from_str("Nell Boot"))
-	));
-	
-	// Update header crc
-	// TODO: THIS IS SUPER STUPID, FIX THIS API, I JUST SPENT AN HOUR DEBUGGING THIS, THE CRC SHOULD NEVER BE ABLE TO BE OUT OF DATE!!
-	gpt_disk.update_crc();
+	if let Err(e) = run(&matches) {
+		eprintln!("error: {}", e);
+		process::exit(1);
+	}
+}
+
+fn cli() -> clap::App<'static, 'static> {
+	let image_arg = || Arg::with_name("image").required(true)
+		.help("The disk image");
+	let partition_arg = || Arg::with_name("partition").long("partition").takes_value(true)
+		.help("Name of the partition, or its position starting at 0");
+	
+	clap::App::new("makediskimg")
+		.setting(AppSettings::SubcommandRequiredElseHelp)
+		.subcommand(SubCommand::with_name("create")
+			.about("Builds an image from a manifest or the default layout")
+			.arg(Arg::with_name("manifest").long("manifest").takes_value(true)
+				.conflicts_with_all(LAYOUT_ARGS)
+				.help("Toml manifest describing the disk, instead of the default layout"))
+			.arg(Arg::with_name("bootloaderefi").long("bootloaderefi").takes_value(true))
+			.arg(Arg::with_name("kernelelf").long("kernelelf").takes_value(true)
+				.required_unless("manifest"))
+			.arg(Arg::with_name("blocksize").long("blocksize").takes_value(true)
+				.possible_values(&["512", "4096"])
+				.help("Logical block size of the disk, 4096 for 4Kn disks (512e disks use 512)"))
+			.arg(Arg::with_name("scheme").long("scheme").takes_value(true)
+				.possible_values(&["gpt", "mbr"])
+				.help("Partitioning scheme of the disk, mbr for mbr-only media (default gpt)"))
+			.arg(Arg::with_name("hybridmbr").long("hybrid-mbr")
+				.help("Write a hybrid mbr mirroring the partitions, for firmware that only understands mbr (gpt only)"))
+			.arg(Arg::with_name("mbrbootcode").long("mbr-bootcode").takes_value(true)
+				.help("File with up to 440 bytes of legacy bios boot code to put into the mbr, marks the efi system partition bootable"))
+			.arg(Arg::with_name("autosize").long("auto-size")
+				.help("Size the partitions by their content and the disk by its partitions, for small test images"))
+			.arg(Arg::with_name("headroom").long("headroom").takes_value(true)
+				.requires("autosize")
+				.validator(validate_byte_size)
+				.help("Free space to leave in each automatically sized filesystem, e.g. 4M"))
+			.arg(Arg::with_name("buffersize").long("buffer-size").takes_value(true)
+				.validator(validate_byte_size)
+				.help("Size of the buffer for writing the image, e.g. 8M (default 1M)"))
+			.arg(Arg::with_name("output").long("output").short("o").takes_value(true)
+				.default_value(DEFAULT_IMAGE_PATH)
+				.help("Where to write the image"))
+			.arg(Arg::with_name("seed").long("seed").takes_value(true)
+				.help("Build reproducibly: derive guids and volume serials from this seed instead of picking them randomly"))
+			.arg(Arg::with_name("sourcedateepoch").long("source-date-epoch").takes_value(true)
+				.env("SOURCE_DATE_EPOCH")
+				.validator(|s| unix_time(&s).map(|_| ()))
+				.help("Build reproducibly, with all fat timestamps set to this unix time")))
+		.subcommand(SubCommand::with_name("update")
+			.about("Replaces files inside an existing (gpt) image, leaving everything else as it is")
+			.arg(image_arg())
+			.arg(Arg::with_name("bootloaderefi").long("bootloaderefi").takes_value(true)
+				.help("New bootloader for the efi system partition of the default layout"))
+			.arg(Arg::with_name("kernelelf").long("kernelelf").takes_value(true)
+				.help("New kernel for the nell boot partition of the default layout"))
+			.arg(partition_arg()
+				.requires("file"))
+			.arg(Arg::with_name("file").long("file").takes_value(true).multiple(true).number_of_values(1)
+				.requires("partition")
+				.help("Host file or directory to copy into the partition, as SOURCE=TARGET"))
+			.group(ArgGroup::with_name("changes")
+				.args(&["bootloaderefi", "kernelelf", "file"])
+				.multiple(true)
+				.required(true)))
+		.subcommand(SubCommand::with_name("extract")
+			.about("Copies files or a whole partition out of a (gpt) image")
+			.arg(image_arg())
+			.arg(partition_arg()
+				.required(true))
+			.arg(Arg::with_name("file").long("file").takes_value(true).multiple(true).number_of_values(1)
+				.help("File or directory of the partition's filesystem to copy, / for all of them"))
+			.arg(Arg::with_name("output").long("output").short("o").takes_value(true)
+				.required(true)
+				.help("Directory to copy files to, or the file to write the raw partition to without --file")))
+		.subcommand(SubCommand::with_name("verify")
+			.about("Checks the gpt structures of an image")
+			.arg(image_arg()))
+		.subcommand(SubCommand::with_name("inspect")
+			.about("Prints the mbr, gpt headers, partitions and fat file trees of an image")
+			.arg(image_arg())
+			.arg(Arg::with_name("json").long("json")
+				.help("Print the layout as json")))
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<(), Error> {
+	match matches.subcommand() {
+		("create", Some(matches)) => create_image(matches),
+		("update", Some(matches)) => update_image(matches),
+		("extract", Some(matches)) => extract_from_image(matches),
+		("verify", Some(matches)) => {
+			let img_path = image_path(matches);
+			verify_image(&img_path)?;
+			println!("{}: ok", img_path.display());
+			Ok(())
+		}
+		("inspect", Some(matches)) => inspect_image(matches),
+		_ => unreachable!("clap requires a subcommand"),
+	}
+}
+
+fn create_image(matches: &clap::ArgMatches) -> Result<(), Error> {
+	let manifest = match matches.value_of("manifest") {
+		Some(path) => Manifest::load(Path::new(path))?,
+		None => default_manifest(matches)?,
+	};
+	
+	let buffer_size = match matches.value_of("buffersize") {
+		Some(s) => parse_byte_size(s).0 as usize,
+		None => sparse::DEFAULT_BUFFER_SIZE,
+	};
+	
+	let img_path = PathBuf::from(matches.value_of_os("output").unwrap_or_default());
+	if let Some(dir) = img_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+		fs::create_dir_all(dir).map_err(|e| Error::io(format!("can't create {}", dir.display()), e))?;
+	}
 	
-	let img_file = OpenOptions::new()
-		.create(true).write(true).read(true).truncate(true)
+	// Built next to the output and renamed once verified, so a failed build leaves nothing behind
+	let tmp_path = temp_image_path(&img_path);
+	let result = build_image(&manifest, &tmp_path, buffer_size, reproducible(matches).as_ref())
+		.and_then(|()| match manifest.disk.scheme {
+			Scheme::Gpt => verify_image(&tmp_path),
+			Scheme::Mbr => Ok(()),
+		})
+		.and_then(|()| fs::rename(&tmp_path, &img_path).map_err(|e| Error::io(format!("can't create {}", img_path.display()), e)));
+	if result.is_err() {
+		let _ = fs::remove_file(&tmp_path);
+	}
+	result
+}
+
+/// Hidden file next to the image, e.g. `build/.boot.img.tmp`.
+fn temp_image_path(img_path: &Path) -> PathBuf {
+	let mut name = OsString::from(".");
+	name.push(img_path.file_name().unwrap_or_default());
+	name.push(".tmp");
+	img_path.with_file_name(name)
+}
+
+fn update_image(matches: &clap::ArgMatches) -> Result<(), Error> {
+	let img_path = image_path(matches);
+	
+	// Files per partition, the shorthands target the default layout
+	let mut updates = Vec::<(&str, Vec<FileSpec>)>::new();
+	if let Some(path) = matches.value_of_os("bootloaderefi") {
+		let files = BOOTLOADER_TARGETS.iter()
+			.map(|target| file_spec(Path::new(path), target))
+			.collect();
+		updates.push((ESP_NAME, files));
+	}
+	if let Some(path) = matches.value_of_os("kernelelf") {
+		updates.push((NELL_BOOT_NAME, vec![file_spec(Path::new(path), KERNEL_TARGET)]));
+	}
+	if let Some(partition) = matches.value_of("partition") {
+		let files = matches.values_of("file").into_iter().flatten()
+			.map(|file| match file.rsplit_once('=') {
+				Some((source, target)) => Ok(file_spec(Path::new(source), target)),
+				None => Err(Error::invalid_layout(format!("--file {} isn't of the form SOURCE=TARGET", file))),
+			})
+			.collect::<Result<_, _>>()?;
+		updates.push((partition, files));
+	}
+	
+	let mut img_file = OpenOptions::new()
+		.read(true).write(true)
 		.open(&img_path)
-		.unwrap();
+		.map_err(|e| Error::missing_input(&img_path, e))?;
+	let disk = read_gpt(&mut img_file, &img_path)?;
+	
+	for (selector, files) in updates.iter() {
+		let partition = image::find_partition(&disk, selector)?;
+		let name = partition.partition_name.to_string();
+		image::update_files(&mut img_file, &disk, partition, files)
+			.map_err(|e| e.in_partition(&name))?;
+	}
+	img_file.sync_all().map_err(|e| Error::io(format!("can't write {}", img_path.display()), e))?;
 	
-	let mut writer = gpt_disk.writer(img_file);
-	writer.write_protective_mbr().unwrap();
-	writer.write_gpt_header(true).unwrap();
-	writer.write_gpt_header(false).unwrap();
+	verify_image(&img_path)
+}
+
+fn extract_from_image(matches: &clap::ArgMatches) -> Result<(), Error> {
+	let img_path = image_path(matches);
+	let out_path = Path::new(matches.value_of_os("output").unwrap_or_default());
+	
+	// Read-only, extracting never writes to the image
+	let mut img_file = File::open(&img_path).map_err(|e| Error::missing_input(&img_path, e))?;
+	let disk = read_gpt(&mut img_file, &img_path)?;
+	let partition = image::find_partition(&disk, matches.value_of("partition").unwrap_or_default())?;
+	
+	match matches.values_of("file") {
+		Some(files) => image::extract_files(&mut img_file, &disk, partition, &files.collect::<Vec<_>>(), out_path),
+		None => image::extract_partition(&mut img_file, &disk, partition, out_path),
+	}.map_err(|e| e.in_partition(&partition.partition_name.to_string()))
+}
+
+/// Checks the gpt structures of a written image.
+fn verify_image(img_path: &Path) -> Result<(), Error> {
+	let issues = File::open(img_path)
+		.and_then(GptDisk::check_integrity)
+		.map_err(|e| Error::io(format!("can't verify {}", img_path.display()), e))?;
+	
+	match issues.is_empty() {
+		true => Ok(()),
+		false => Err(Error::Verification {path: img_path.to_owned(), issues}),
+	}
+}
+
+fn read_gpt(img_file: &mut File, img_path: &Path) -> Result<GptDisk, Error> {
+	GptDisk::read_from(img_file)
+		.map_err(|e| Error::io(format!("can't read the gpt of {}", img_path.display()), e))
+}
+
+fn image_path(matches: &clap::ArgMatches) -> PathBuf {
+	PathBuf::from(matches.value_of_os("image").unwrap_or_default())
+}
+
+fn inspect_image(matches: &clap::ArgMatches) -> Result<(), Error> {
+	let img_path = image_path(matches);
+	
+	// Read-only, inspecting never writes
+	let img_file = File::open(&img_path).map_err(|e| Error::missing_input(&img_path, e))?;
+	let report = inspect::inspect(img_file)
+		.map_err(|e| Error::io(format!("can't read {}", img_path.display()), e))?;
+	
+	let mut stdout = io::stdout();
+	match matches.is_present("json") {
+		true => serde_json::to_writer_pretty(&mut stdout, &report)
+			.map_err(io::Error::from)
+			.and_then(|_| writeln!(stdout)),
+		false => write!(stdout, "{}", report),
+	}.map_err(|e| Error::io("can't print the report", e))
+}
+
+/// Reproducible build settings if a seed or timestamp is given.
+/// Without a timestamp files are stamped 1980-01-01, without a seed ids are derived from an empty one.
+fn reproducible(matches: &clap::ArgMatches) -> Option<Reproducible> {
+	let seed = matches.value_of("seed");
+	let timestamp = matches.value_of("sourcedateepoch")
+		.map(|s| unix_time(s).expect("validated by clap"));
+	if seed.is_none() && timestamp.is_none() {
+		return None;
+	}
+	Some(Reproducible::new(seed.unwrap_or(""), timestamp.unwrap_or(UNIX_EPOCH)))
+}
+
+/// Parses seconds since the unix epoch, as long as the system time can hold them.
+fn unix_time(s: &str) -> Result<SystemTime, String> {
+	let secs = s.parse::<u64>().map_err(|e| format!("not a unix timestamp: {}", e))?;
+	UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+		.ok_or_else(|| format!("unix timestamp {} is too far in the future", secs))
+}
+
+fn validate_byte_size(s: String) -> Result<(), String> {
+	s.parse::<ByteSize>().map(|_| ())
+}
+
+/// Parses a size checked by [`validate_byte_size`].
+fn parse_byte_size(s: &str) -> ByteSize {
+	s.parse().expect("validated by clap")
+}
+
+/// The built-in layout (see disk.toml), configured by the command line:
+/// an efi system partition with the bootloader and the nell bootstash with the kernel.
+fn default_manifest(matches: &clap::ArgMatches) -> Result<Manifest, Error> {
+	let bootloader_efi_path = PathBuf::from(matches.value_of("bootloaderefi")
+		.unwrap_or("../../bootloader_uefi/target/x86_64-unknown-uefi/debug/bootloader_uefi.efi"));
+	let kernel_path = matches.value_of("kernelelf")
+		.map(PathBuf::from)
+		.ok_or_else(|| Error::invalid_layout("--kernelelf is required without --manifest"))?;
+	
+	let block_size = match matches.value_of("blocksize") {
+		Some("4096") => 4096,
+		_ => gpt::DEFAULT_BLOCK_SIZE as u32,
+	};
+	let scheme = match matches.value_of("scheme") {
+		Some("mbr") => Scheme::Mbr,
+		_ => Scheme::Gpt,
+	};
+	let hybrid_mbr = matches.is_present("hybridmbr");
+	if hybrid_mbr && scheme == Scheme::Mbr {
+		return Err(Error::invalid_layout("--hybrid-mbr only applies to gpt disks"));
+	}
+	let mbr_bootcode = matches.value_of("mbrbootcode").map(PathBuf::from);
 	
-	// Write partition contents
-	efi_partition.memdisk.seek(SeekFrom::Start(0)).unwrap();
-	writer.write_partition_content(gpt_disk.partitions().nth(0).unwrap(), &mut efi_partition.memdisk)
-		.unwrap();
-	
-	bootstash_partition.memdisk.seek(SeekFrom::Start(0)).unwrap();
-	writer.write_partition_content(gpt_disk.partitions().nth(1).unwrap(), &mut bootstash_partition.memdisk)
-		.unwrap();
-	
-	writer.flush().sync_all().unwrap();
-	
-	/*
-	// DEBUG:
-	{
-		let disk = ::gpt::disk::read_disk(&img_path).unwrap();
-		for (k, v) in disk.partitions().iter() {
-			println!("Part#{:02}:\n{}", k, v);
-			
-			let start = v.bytes_start(::gpt::disk::LogicalBlockSize::Lb512).unwrap();
-			let len = v.bytes_len(::gpt::disk::LogicalBlockSize::Lb512).unwrap();
-			
-			let disk2 = File::open(&img_path).unwrap();
-			let vfs_slice = fscommon::StreamSlice::new(disk2, start, start+len).unwrap();
-			
-			let vfs = fatfs::FileSystem::new(vfs_slice, FsOptions::new()).unwrap();
-			for f in vfs.root_dir().iter() {
-				println!("file \"{}\"", f.unwrap().file_name());
-			}
-		}
+	let auto_size = matches.is_present("autosize");
+	let headroom = match matches.value_of("headroom") {
+		Some(s) => parse_byte_size(s),
+		None => ByteSize(0),
+	};
+	// Without auto sizing each partition is the smallest fat32 volume, which uefi wants on the esp
+	let partition_size = match auto_size {
+		true => None,
+		false => Some(ByteSize(fat::min_volume_size(&[], block_size, 0, Some(FatType::Fat32))?.size_bytes)),
+	};
+	
+	Ok(Manifest {
+		disk: DiskSpec {
+			scheme,
+			block_size,
+			// Just big enough for the partitions
+			size: None,
+			slack: ByteSize(0),
+			guid: None,
+			mbr_bootcode: mbr_bootcode.clone(),
+		},
+		partitions: vec![
+			PartitionSpec {
+				name: ESP_NAME.to_owned(),
+				type_guid: gpt::partition_types::EFI_SYSTEM,
+				guid: None,
+				attributes: match mbr_bootcode {
+					Some(_) => GptPartitionAttribs::LEGACY_BIOS_BOOTABLE,
+					None => GptPartitionAttribs::zero(),
+				},
+				size: partition_size,
+				headroom,
+				mbr_type: None,
+				mbr_mirror: hybrid_mbr,
+				mbr_logical: false,
+				// Uefi only has to read fat32 esps, fat12/16 ones don't boot everywhere
+				filesystem: Filesystem::Fat32,
+				files: BOOTLOADER_TARGETS.iter()
+					.map(|target| file_spec(&bootloader_efi_path, target))
+					.collect(),
+			},
+			PartitionSpec {
+				name: NELL_BOOT_NAME.to_owned(),
+				type_guid: gpt::partition_types::NELL_BOOT,
+				guid: Some(Guid::from_u128(0xA4A4A4A4_A4A4_A4A4_A4A4_A4A4A4A4A4A4)),
+				attributes: NELL_PARTITION_ATTRIB_READ_ONLY,
+				size: partition_size,
+				headroom,
+				mbr_type: None,
+				mbr_mirror: hybrid_mbr,
+				mbr_logical: false,
+				filesystem: Filesystem::Fat,
+				files: vec![
+					file_spec(&kernel_path, KERNEL_TARGET),
+				],
+			},
+		],
+	})
+}
+
+fn file_spec(source: &Path, target: &str) -> FileSpec {
+	FileSpec {
+		source: source.to_owned(),
+		target: target.to_owned(),
+		include: Vec::new(),
+		exclude: Vec::new(),
 	}
-	*/
 }
 
-fn build_efi_partition(booloader_efi_path: &Path) -> BuiltPartition {
-	let vfs_size_bytes = 33_548_800 + 1032*512;
-	// Create mem disk buffer
-	let mut vfs_buf = MemDisk::new_fixed_size(vfs_size_bytes);
+/// Writes the disk image described by the manifest.
+/// Partitions are formatted in place, the image is sparse so unused space takes up no room on the host.
+/// With reproducible settings the same manifest and files always give the same bytes.
+fn build_image(manifest: &Manifest, img_path: &Path, buffer_size: usize, reproducible: Option<&Reproducible>) -> Result<(), Error> {
+	let block_size = manifest.disk.block_size as usize;
+	let img_error = |e| Error::io(format!("can't write {}", img_path.display()), e);
+	
+	let mut planned_partitions = Vec::with_capacity(manifest.partitions.len());
+	for (i, spec) in manifest.partitions.iter().enumerate() {
+		let planned = plan_partition(manifest, i, block_size, reproducible)
+			.map_err(|e| e.in_partition(&spec.name))?;
+		planned_partitions.push(planned);
+	}
 	
-	let vfs_sector_size = 512;
+	let bootcode = match &manifest.disk.mbr_bootcode {
+		Some(path) => Some(fs::read(path).map_err(|e| Error::missing_input(path, e))?),
+		None => None,
+	};
 	
-	let format_opts = fatfs::FormatVolumeOptions::new()
-		.fat_type(FatType::Fat32)
-		.bytes_per_sector(vfs_sector_size as u16)
-		.total_sectors((vfs_size_bytes / vfs_sector_size) as u32)
-		.bytes_per_cluster(vfs_sector_size as u32);
+	let img_file = OpenOptions::new()
+		.create(true).write(true).read(true).truncate(true)
+		.open(img_path)
+		.map_err(|e| Error::io(format!("can't create {}", img_path.display()), e))?;
+	let mut img = SparseFile::new(img_file, buffer_size).map_err(img_error)?;
 	
-	fatfs::format_volume(&mut vfs_buf, format_opts)
-		.unwrap();
+	let partition_ranges = match manifest.disk.scheme {
+		Scheme::Gpt => write_gpt_layout(manifest, &mut img, bootcode.as_deref(), &planned_partitions, reproducible)?,
+		Scheme::Mbr => write_mbr_layout(manifest, &mut img, bootcode.as_deref(), &planned_partitions, reproducible)?,
+	};
 	
-	vfs_buf.seek(SeekFrom::Start(0)).unwrap();
-	let mut vfs = fatfs::FileSystem::new(&mut vfs_buf, FsOptions::new()).unwrap();
+	// Fatfs stamps new directories with the current time
+	let fs_options = match reproducible {
+		Some(reproducible) => FsOptions::new().time_provider(reproducible.time_provider()),
+		None => FsOptions::new(),
+	};
 	
-	{// Populate fs
-		// "../../bootloader_uefi/target/x86_64-unknown-uefi/debug/bootloader_uefi.efi"
-		copy_to_vfs(&mut vfs, booloader_efi_path, "/efi/boot/nell_foo/nellbootx64.efi").unwrap(); // Note that the nell folder can be custom named to allow multiple installations (all using the same efi system partition, as it should)
-		copy_to_vfs(&mut vfs, booloader_efi_path, "/efi/boot/bootx64.efi").unwrap(); // Needed for automatic boot instead of getting dumped into the uefi shell
-		copy_to_vfs(&mut vfs, booloader_efi_path, "/nellbootx64.efi").unwrap();
+	// Write partition contents
+	let mut device = IoDevice::new(&mut img, block_size as u32).map_err(img_error)?;
+	for (i, (planned, range)) in planned_partitions.iter().zip(partition_ranges).enumerate() {
+		let spec = &manifest.partitions[i];
+		let volume_id = match reproducible {
+			Some(reproducible) => reproducible.volume_id(&spec.name, name_repetition(manifest, i)),
+			None => Guid::new_v4().first_u32(),
+		};
+		let mut partition = BlockIo::new(PartitionDevice::new(&mut device, range.start, range.end - range.start).map_err(img_error)?);
+		fill_partition(spec, planned, &mut partition, block_size, volume_id, fs_options)
+			.and_then(|_| partition.flush().map_err(|e| Error::io("can't write partition content", e)))
+			.map_err(|e| e.in_partition(&spec.name))?;
 	}
-	vfs.unmount().unwrap();
 	
-	BuiltPartition {
-		memdisk: vfs_buf,
-	}
+	img.flush().map_err(img_error)?;
+	img.get_ref().sync_all().map_err(img_error)?;
+	Ok(())
 }
 
-fn build_bootstash_partition(kernel_path: &Path) -> BuiltPartition {
-	let vfs_size_bytes = 33_548_800 + 1032*512;
-	// Create mem disk buffer
-	let mut vfs_buf = MemDisk::new_fixed_size(vfs_size_bytes);
+/// Writes the gpt structures, returns the lba range of each partition.
+fn write_gpt_layout(manifest: &Manifest, img: &mut SparseFile, bootcode: Option<&[u8]>, planned_partitions: &[PlannedPartition], reproducible: Option<&Reproducible>) -> Result<Vec<Range<u64>>, Error> {
+	let block_size = manifest.disk.block_size as usize;
+	let img_error = |e| Error::io("can't write the image", e);
+	
+	// Create gpt disk, automatically sized ones start out as big as possible and shrink once the partitions are placed
+	let disk_size_lba = manifest.disk.size.map_or(u64::MAX / block_size as u64, |size| size.0 / block_size as u64);
+	let disk_guid = manifest.disk.guid.or_else(|| reproducible.map(Reproducible::disk_guid));
+	let mut gpt_disk = GptDisk::new_empty(block_size as u32, disk_size_lba, disk_guid)?;
+	
+	for (i, (spec, planned)) in manifest.partitions.iter().zip(planned_partitions.iter()).enumerate() {
+		let guid = spec.guid.or_else(|| reproducible.map(|r| r.partition_guid(&spec.name, name_repetition(manifest, i))));
+		gpt_disk.create_partition(CreatePartitionOptions::new(
+			spec.type_guid,
+			guid,
+			planned.size_in_lba,
+			spec.attributes,
+			PartitionName::new(&spec.name).map_err(|e| Error::from(e).in_partition(&spec.name))?
+		)).map_err(|e| Error::from(e).in_partition(&spec.name))?;
+	}
+	
+	if manifest.disk.size.is_none() {
+		let slack_lba = manifest.disk.slack.0.div_ceil(block_size as u64);
+		gpt_disk.resize_disk(gpt_disk.min_disk_size_lba() + slack_lba)?;
+	}
 	
-	let vfs_sector_size = 512;
+	// All holes until written
+	img.set_len(gpt_disk.disk_size_lba() * block_size as u64).map_err(img_error)?;
 	
-	let format_opts = fatfs::FormatVolumeOptions::new()
-		.fat_type(FatType::Fat32)
-		.bytes_per_sector(vfs_sector_size as u16)
-		.total_sectors((vfs_size_bytes / vfs_sector_size) as u32)
-		.bytes_per_cluster(vfs_sector_size as u32);
+	let mut writer = gpt_disk.writer(IoDevice::new(&mut *img, block_size as u32).map_err(img_error)?);
 	
-	fatfs::format_volume(&mut vfs_buf, format_opts)
-		.unwrap();
+	let mirrored = manifest.partitions.iter()
+		.enumerate()
+		.filter(|(_, spec)| spec.mbr_mirror)
+		.map(|(i, spec)| (i, spec.mbr_os_type()))
+		.collect::<Vec<(usize, MbrOsType)>>();
 	
-	vfs_buf.seek(SeekFrom::Start(0)).unwrap();
-	let mut vfs = fatfs::FileSystem::new(&mut vfs_buf, FsOptions::new()).unwrap();
+	if !mirrored.is_empty() || bootcode.is_some() {
+		writer.write_hybrid_mbr(&mirrored, bootcode.unwrap_or(&[]))?;
+	} else {
+		writer.write_protective_mbr()?;
+	}
+	writer.write_gpt_header(true)?;
+	writer.write_gpt_header(false)?;
+	
+	writer.flush()?;
+	
+	let ranges = gpt_disk.partitions()
+		.map(|p| p.start_lba..p.end_lba_incl + 1)
+		.collect();
+	Ok(ranges)
+}
+
+/// Writes the boot records of a pure mbr disk, the legacy bios bootable attribute marks the active partition.
+/// Returns the lba range of each partition.
+fn write_mbr_layout(manifest: &Manifest, img: &mut SparseFile, bootcode: Option<&[u8]>, planned_partitions: &[PlannedPartition], reproducible: Option<&Reproducible>) -> Result<Vec<Range<u64>>, Error> {
+	let block_size = manifest.disk.block_size as usize;
+	let img_error = |e| Error::io("can't write the image", e);
+	
+	let disk_size_lba = manifest.disk.size.map_or(u64::MAX / block_size as u64, |size| size.0 / block_size as u64);
+	let mut mbr_disk = MbrDisk::new_empty(block_size as u32, disk_size_lba, reproducible.map(Reproducible::mbr_disk_signature));
+	if let Some(bootcode) = bootcode {
+		mbr_disk.set_bootstrap_code(bootcode)?;
+	}
 	
-	{// Populate fs
-		copy_to_vfs(&mut vfs, kernel_path, "/kernel.elf").unwrap();
+	let mut ranges = Vec::with_capacity(manifest.partitions.len());
+	for (spec, planned) in manifest.partitions.iter().zip(planned_partitions.iter()) {
+		let bootable = spec.attributes.contains(GptPartitionAttribs::LEGACY_BIOS_BOOTABLE);
+		
+		let partition = match spec.mbr_logical {
+			true => mbr_disk.add_logical_partition(spec.mbr_os_type(), planned.size_in_lba, bootable),
+			false => mbr_disk.add_primary_partition(spec.mbr_os_type(), planned.size_in_lba, bootable),
+		}.map_err(|e| Error::from(e).in_partition(&spec.name))?;
+		ranges.push(partition.start_lba..partition.end_lba_incl + 1);
 	}
-//	vfs.root_dir().create_file("test.txt").unwrap();
-	vfs.unmount().unwrap();
 	
-	BuiltPartition {
-		memdisk: vfs_buf,
+	if manifest.disk.size.is_none() {
+		let slack_lba = manifest.disk.slack.0.div_ceil(block_size as u64);
+		mbr_disk.resize_disk(mbr_disk.min_disk_size_lba() + slack_lba)?;
 	}
+	
+	// All holes until written
+	img.set_len(mbr_disk.disk_size_lba() * block_size as u64).map_err(img_error)?;
+	
+	let mut writer = mbr_disk.writer(IoDevice::new(&mut *img, block_size as u32).map_err(img_error)?);
+	writer.write_boot_records()?;
+	writer.flush()?;
+	
+	Ok(ranges)
 }
 
-struct BuiltPartition {
-	memdisk: MemDisk,
+/// Works out the size of a partition, rounded up to whole blocks, and what to import into it.
+/// Fat partitions without a size get the smallest volume that holds their files.
+fn plan_partition(manifest: &Manifest, index: usize, block_size: usize, reproducible: Option<&Reproducible>) -> Result<PlannedPartition, Error> {
+	let spec = &manifest.partitions[index];
+	let mut imports = match spec.filesystem.is_fat() {
+		true => fat::collect_imports(&spec.files)?,
+		false => Vec::new(),
+	};
+	if let Some(reproducible) = reproducible {
+		reproducible.fix_timestamps(&mut imports);
+	}
+	let size_bytes = match spec.size {
+		Some(size) => size.0,
+		None => fat::min_volume_size(&imports, block_size as u32, spec.headroom.0, spec.filesystem.fat_type())?.size_bytes,
+	};
+	
+	Ok(PlannedPartition {
+		imports,
+		size_in_lba: size_bytes.div_ceil(block_size as u64),
+	})
 }
 
-fn copy_to_vfs<T: ReadWriteSeek>(fs: &mut fatfs::FileSystem<T>, src_path: impl AsRef<Path>, vfs_path: &str) -> io::Result<()> {
-	let mut src_file = File::open(src_path.as_ref())?;
+/// Formats the partition and imports its files, partitions without a filesystem are left zeroed.
+fn fill_partition<T: ReadWriteSeek>(spec: &PartitionSpec, planned: &PlannedPartition, mut storage: T, block_size: usize, volume_id: u32, fs_options: FsOptions) -> Result<(), Error> {
+	if !spec.filesystem.is_fat() {
+		return Ok(());
+	}
+	let format_error = |error| Error::FilesystemFormat {partition: None, error};
 	
-	let (target_dir, file_name) = {
-		let segs = vfs_path.split('/').collect::<Vec<_>>();
-		
-		let mut prev = fs.root_dir();
-		for (i, s) in segs.iter().copied().enumerate() {
-			if (i > 0 || !s.is_empty()) && i < segs.len()-1 {
-				prev = prev.create_dir(s)?;
-			}
+	let size_bytes = planned.size_in_lba * block_size as u64;
+	fat::format_volume(&mut storage, size_bytes, block_size as u32, volume_id).map_err(format_error)?;
+	
+	let mut vfs = fatfs::FileSystem::new(&mut storage, fs_options).map_err(format_error)?;
+	if let Some(fat_type) = spec.filesystem.fat_type() {
+		if vfs.fat_type() != fat_type {
+			return Err(Error::invalid_layout(format!("a volume of {} bytes is {:?}, not {:?}", size_bytes, vfs.fat_type(), fat_type)));
 		}
-		(prev, *segs.last().unwrap())
-	};
+	}
 	
-	// Create dirs
-	let mut vfs_file = target_dir
-		.create_file(file_name)?;
+	// Populate fs
+	fat::import(&mut vfs, &planned.imports)?;
+	vfs.unmount().map_err(format_error)?;
 	
-	io::copy(&mut src_file, &mut vfs_file)?;
 	Ok(())
 }
 
-//fn utf16_to_array_nul<const N: usize>(ustr: &U16Str) -> [u16; N] {
-fn utf16_to_array_nul(ustr: &U16Str) -> [u16; 36] {
-	let mut buf = [0u16; 36];
-	for (i, c) in ustr.as_slice().iter().copied().take(36usize.saturating_sub(1)).enumerate() {
-		buf[i] = c;
-	}
-	buf[buf.len()-1] = b'\0' as _;
-	buf
+/// How many partitions before the `index`th one share its name, tells them apart when deriving ids.
+fn name_repetition(manifest: &Manifest, index: usize) -> usize {
+	let name = &manifest.partitions[index].name;
+	manifest.partitions[..index].iter().filter(|p| &p.name == name).count()
+}
+
+struct PlannedPartition {
+	imports: Vec<ImportEntry>,
+	size_in_lba: u64,
 }
 
-//trait U16ToArray {
-//	fn to_array_nul<const N: usize>(&self) -> Option<[u16; N]>;
-//}
-//impl U16ToArray for U16CString {
-//	fn to_array_nul<const N: usize>(&self) -> Option<[u16; N]> {
-//		
-//		None
-//	}
-//}
-//
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use crate::test_util::temp_dir;
+	
+	/// Runs `create` with the default layout, returns the fat type of each partition.
+	fn create_default_layout(dir: &Path, args: &[&str]) -> Vec<String> {
+		let img_path = dir.join("boot.img");
+		let kernel = dir.join("kernel.elf");
+		let bootloader = dir.join("bootx64.efi");
+		fs::write(&kernel, vec![1; 5000]).unwrap();
+		fs::write(&bootloader, vec![2; 3000]).unwrap();
+		
+		let mut cli_args = vec!["makediskimg", "create", "--output", img_path.to_str().unwrap(), "--kernelelf", kernel.to_str().unwrap(), "--bootloaderefi", bootloader.to_str().unwrap()];
+		cli_args.extend_from_slice(args);
+		let matches = cli().get_matches_from(cli_args);
+		run(&matches).unwrap();
+		
+		inspect::inspect(File::open(&img_path).unwrap()).unwrap().partitions.iter()
+			.map(|p| p.filesystem.as_ref().unwrap().fat_type.clone())
+			.collect()
+	}
+	
+	#[test]
+	pub fn default_esp_is_fat32() {
+		let dir = temp_dir("default_esp");
+		for block_size in ["512", "4096"].iter() {
+			assert_eq!(create_default_layout(&dir, &["--blocksize", block_size])[0], "fat32", "{} byte blocks", block_size);
+			assert_eq!(create_default_layout(&dir, &["--blocksize", block_size, "--auto-size"]), vec!["fat32", "fat12"], "{} byte blocks", block_size);
+		}
+		fs::remove_dir_all(&dir).unwrap();
+	}
+	
+	#[test]
+	pub fn failed_build_keeps_previous_image() {
+		let dir = temp_dir("failed_build");
+		let img_path = dir.join("boot.img");
+		fs::write(&img_path, b"previous").unwrap();
+		
+		// Fails importing, after the image was laid out
+		fs::write(dir.join("big.bin"), vec![1; 2 << 20]).unwrap();
+		let manifest = "[disk]\n[[partition]]\nname = \"a\"\ntype = \"C12A7328-F81F-11D2-BA4B-00A0C93EC93B\"\nsize = \"1M\"\nfilesystem = \"fat\"\nfiles = [{source = \"big.bin\", target = \"/big.bin\"}]";
+		fs::write(dir.join("manifest.toml"), manifest).unwrap();
+		
+		let matches = cli().get_matches_from(vec!["makediskimg", "create", "--output", img_path.to_str().unwrap(), "--manifest", dir.join("manifest.toml").to_str().unwrap()]);
+		assert!(matches!(run(&matches), Err(Error::ContentTooLarge {..})));
+		assert_eq!(fs::read(&img_path).unwrap(), b"previous");
+		assert!(!temp_image_path(&img_path).exists());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+	
+	#[test]
+	pub fn rejects_unrepresentable_source_date_epoch() {
+		let create = |epoch: &str| cli().get_matches_from_safe(vec!["makediskimg", "create", "--manifest", "disk.toml", "--source-date-epoch", epoch]);
+		assert!(create("1600000000").is_ok());
+		assert!(create("18446744073709551615").is_err());
+		assert!(create("yesterday").is_err());
+	}
+	
+	/// Two fat partitions with the same name, to check they still get distinct ids.
+	const MANIFEST: &str = r#"
+		[disk]
+		[[partition]]
+		name = "ESP"
+		type = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+		filesystem = "fat"
+		files = [{source = "efi", target = "/efi"}]
+		[[partition]]
+		name = "ESP"
+		type = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+		filesystem = "fat"
+		files = [{source = "efi/boot/bootx64.efi", target = "/bootx64.efi"}]
+	"#;
+	
+	#[test]
+	pub fn reproducible_builds() {
+		let dir = temp_dir("reproducible");
+		fs::create_dir_all(dir.join("efi/boot")).unwrap();
+		fs::write(dir.join("efi/boot/bootx64.efi"), vec![1; 5000]).unwrap();
+		fs::write(dir.join("manifest.toml"), MANIFEST).unwrap();
+		let manifest = Manifest::load(&dir.join("manifest.toml")).unwrap();
+		
+		let build = |name: &str, reproducible: Option<&Reproducible>| {
+			// Different host times for each build
+			File::options().write(true).open(dir.join("efi/boot/bootx64.efi")).unwrap()
+				.set_modified(SystemTime::now()).unwrap();
+			let img_path = dir.join(name);
+			build_image(&manifest, &img_path, sparse::DEFAULT_BUFFER_SIZE, reproducible).unwrap();
+			fs::read(img_path).unwrap()
+		};
+		let reproducible = Reproducible::new("seed", UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+		let first = build("first.img", Some(&reproducible));
+		assert!(first == build("second.img", Some(&Reproducible::new("seed", UNIX_EPOCH + Duration::from_secs(1_600_000_000)))));
+		assert!(first != build("later.img", Some(&Reproducible::new("seed", UNIX_EPOCH + Duration::from_secs(1_700_000_000)))));
+		assert!(first != build("random.img", None));
+		
+		let mut img = File::open(dir.join("first.img")).unwrap();
+		let disk = GptDisk::read_from(&mut img).unwrap();
+		assert_eq!(disk.primary_header().disk_guid, reproducible.disk_guid());
+		assert_eq!(disk.partition(0).unwrap().unique_guid, reproducible.partition_guid("ESP", 0));
+		assert_eq!(disk.partition(1).unwrap().unique_guid, reproducible.partition_guid("ESP", 1));
+		
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
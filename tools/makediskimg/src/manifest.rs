@@ -0,0 +1,420 @@
+use std::{error, fmt, fs, io, str};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+use fatfs::FatType;
+use glob::Pattern;
+use serde::{Deserialize, Deserializer};
+
+use crate::gpt::{self, GptPartitionAttribs, Guid, PartitionName};
+use crate::mbr::{self, MbrOsType};
+
+/// Declarative description of a disk image, usually loaded from a toml file.
+///
+/// ```toml
+/// [disk]
+/// size = "128MiB"
+///
+/// [[partition]]
+/// name = "UEFI System"
+/// type = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+/// size = "64MiB"
+/// filesystem = "fat"
+/// files = [{source = "bootx64.efi", target = "/efi/boot/bootx64.efi"}]
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+	pub disk: DiskSpec,
+	#[serde(rename = "partition", default)]
+	pub partitions: Vec<PartitionSpec>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiskSpec {
+	#[serde(default)]
+	pub scheme: Scheme,
+	#[serde(default = "default_block_size")]
+	pub block_size: u32,
+	/// Disk size, just big enough for the partitions (plus `slack`) if not given.
+	pub size: Option<ByteSize>,
+	/// Unallocated space at the end of an automatically sized disk.
+	#[serde(default)]
+	pub slack: ByteSize,
+	/// Disk guid, random (or derived from the seed of a reproducible build) if not given.
+	pub guid: Option<Guid>,
+	/// Up to 440 bytes of legacy bios boot code for the mbr.
+	pub mbr_bootcode: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+	#[default]
+	Gpt,
+	Mbr,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartitionSpec {
+	pub name: String,
+	#[serde(rename = "type")]
+	pub type_guid: Guid,
+	/// Unique guid, random (or derived from the seed and name in a reproducible build) if not given.
+	pub guid: Option<Guid>,
+	#[serde(default, deserialize_with = "deserialize_from_str")]
+	pub attributes: GptPartitionAttribs,
+	/// Partition size, the smallest filesystem holding the files (plus `headroom`) if not given.
+	pub size: Option<ByteSize>,
+	/// Free space in an automatically sized filesystem.
+	#[serde(default)]
+	pub headroom: ByteSize,
+	/// Os type of the partition's mbr entry, derived from the type guid if not given.
+	pub mbr_type: Option<MbrOsType>,
+	/// Mirror the partition into a hybrid mbr (gpt only).
+	#[serde(default)]
+	pub mbr_mirror: bool,
+	/// Make the partition a logical one inside the extended partition (mbr only).
+	#[serde(default)]
+	pub mbr_logical: bool,
+	#[serde(default)]
+	pub filesystem: Filesystem,
+	/// Host files or directories to copy into the filesystem.
+	#[serde(default)]
+	pub files: Vec<FileSpec>,
+}
+
+impl PartitionSpec {
+	pub fn mbr_os_type(&self) -> MbrOsType {
+		self.mbr_type.unwrap_or(match self.type_guid {
+			gpt::partition_types::EFI_SYSTEM => mbr::os_types::UEFI_SYSTEM,
+			_ => mbr::os_types::FAT32_LBA,
+		})
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+	/// Left zeroed
+	#[default]
+	None,
+	/// Fat of the smallest type the content fits in (or the size allows)
+	Fat,
+	Fat12,
+	Fat16,
+	Fat32,
+}
+
+impl Filesystem {
+	pub fn is_fat(&self) -> bool {
+		*self != Filesystem::None
+	}
+	
+	/// The fat type the filesystem has to be, `None` if any will do.
+	pub fn fat_type(&self) -> Option<FatType> {
+		match self {
+			Filesystem::Fat12 => Some(FatType::Fat12),
+			Filesystem::Fat16 => Some(FatType::Fat16),
+			Filesystem::Fat32 => Some(FatType::Fat32),
+			Filesystem::None | Filesystem::Fat => None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSpec {
+	/// Host path, relative to the manifest's directory
+	pub source: PathBuf,
+	/// Absolute path inside the filesystem
+	pub target: String,
+	/// Globs of files to import from a directory, everything if empty
+	#[serde(default, deserialize_with = "deserialize_vec_from_str")]
+	pub include: Vec<Pattern>,
+	/// Globs of files and directories to skip
+	#[serde(default, deserialize_with = "deserialize_vec_from_str")]
+	pub exclude: Vec<Pattern>,
+}
+
+/// A size in bytes, either a plain number or a string with a binary unit (`K`, `M`, `G`, optionally `iB`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawByteSize")]
+pub struct ByteSize(pub u64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+	Bytes(u64),
+	String(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+	type Error = String;
+	
+	fn try_from(raw: RawByteSize) -> Result<Self, Self::Error> {
+		match raw {
+			RawByteSize::Bytes(n) => Ok(ByteSize(n)),
+			RawByteSize::String(s) => s.parse(),
+		}
+	}
+}
+
+impl str::FromStr for ByteSize {
+	type Err = String;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+		let (number, unit) = s.split_at(split);
+		
+		let number = number.parse::<u64>().map_err(|_| format!("invalid size \"{}\"", s))?;
+		let factor = match unit.trim() {
+			"" | "B" => 1,
+			"K" | "KiB" => 1 << 10,
+			"M" | "MiB" => 1 << 20,
+			"G" | "GiB" => 1 << 30,
+			unit => return Err(format!("unknown size unit \"{}\"", unit)),
+		};
+		
+		number.checked_mul(factor)
+			.map(ByteSize)
+			.ok_or_else(|| format!("size \"{}\" is too large", s))
+	}
+}
+
+fn default_block_size() -> u32 {
+	gpt::DEFAULT_BLOCK_SIZE as u32
+}
+
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+	D: Deserializer<'de>,
+	T: str::FromStr,
+	T::Err: fmt::Display,
+{
+	let s = String::deserialize(deserializer)?;
+	s.parse().map_err(serde::de::Error::custom)
+}
+
+fn deserialize_vec_from_str<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: str::FromStr,
+	T::Err: fmt::Display,
+{
+	Vec::<String>::deserialize(deserializer)?
+		.iter()
+		.map(|s| s.parse().map_err(serde::de::Error::custom))
+		.collect()
+}
+
+impl Manifest {
+	/// Loads and validates a manifest, relative host paths are resolved against the manifest's directory.
+	pub fn load(path: &Path) -> Result<Manifest, ManifestError> {
+		let text = fs::read_to_string(path)
+			.map_err(|e| ManifestError::Io {path: path.to_owned(), error: e})?;
+		let mut manifest = Self::parse(&text)?;
+		
+		let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+		manifest.resolve_paths(base_dir);
+		
+		Ok(manifest)
+	}
+	
+	/// Parses and validates a manifest, host paths are left as they are.
+	pub fn parse(text: &str) -> Result<Manifest, ManifestError> {
+		let manifest: Manifest = toml::from_str(text).map_err(ManifestError::Parse)?;
+		manifest.validate()?;
+		Ok(manifest)
+	}
+	
+	fn resolve_paths(&mut self, base_dir: &Path) {
+		if let Some(bootcode) = self.disk.mbr_bootcode.as_mut() {
+			*bootcode = base_dir.join(&bootcode);
+		}
+		for file in self.partitions.iter_mut().flat_map(|p| p.files.iter_mut()) {
+			file.source = base_dir.join(&file.source);
+		}
+	}
+	
+	fn validate(&self) -> Result<(), ManifestError> {
+		let invalid = |msg: String| Err(ManifestError::Invalid(msg));
+		
+		let disk = &self.disk;
+		if disk.block_size != 512 && disk.block_size != 4096 {
+			return invalid(format!("unsupported block size {}, must be 512 or 4096", disk.block_size));
+		}
+		match disk.size {
+			Some(ByteSize(0)) => return invalid("disk size must not be zero".to_owned()),
+			Some(_) if disk.slack.0 != 0 => return invalid("slack only applies to automatically sized disks".to_owned()),
+			_ => (),
+		}
+		if let Some(ByteSize(size)) = disk.size {
+			let min_size = self.min_disk_size_lba().saturating_mul(disk.block_size as u64);
+			if size < min_size {
+				return invalid(format!("disk.size {} is too small, the partition table and partitions need at least {} bytes", size, min_size));
+			}
+		}
+		
+		for part in self.partitions.iter() {
+			if let Err(e) = PartitionName::new(&part.name) {
+				return invalid(format!("partition \"{}\": {}", part.name, e));
+			}
+			match part.size {
+				Some(ByteSize(0)) => return invalid(format!("partition \"{}\": size must not be zero", part.name)),
+				Some(_) if part.headroom.0 != 0 => return invalid(format!("partition \"{}\": headroom only applies to automatically sized partitions", part.name)),
+				None if !part.filesystem.is_fat() => return invalid(format!("partition \"{}\": needs a size or a filesystem to size it by", part.name)),
+				_ => (),
+			}
+			if !part.filesystem.is_fat() && !part.files.is_empty() {
+				return invalid(format!("partition \"{}\": files need a filesystem", part.name));
+			}
+			if let Some(file) = part.files.iter().find(|f| !f.target.starts_with('/')) {
+				return invalid(format!("partition \"{}\": target \"{}\" must be absolute", part.name, file.target));
+			}
+			if disk.scheme == Scheme::Mbr && part.mbr_mirror {
+				return invalid(format!("partition \"{}\": mbr_mirror only applies to gpt disks", part.name));
+			}
+			if disk.scheme == Scheme::Gpt && part.mbr_logical {
+				return invalid(format!("partition \"{}\": mbr_logical only applies to mbr disks", part.name));
+			}
+		}
+		
+		Ok(())
+	}
+	
+	/// Lower bound on the disk size, ignoring alignment and automatically sized partitions.
+	fn min_disk_size_lba(&self) -> u64 {
+		let block_size = self.disk.block_size as u64;
+		let partitions_lba = self.partitions.iter()
+			.filter_map(|p| p.size)
+			.fold(0u64, |sum, ByteSize(size)| sum.saturating_add(size.div_ceil(block_size)));
+		match self.disk.scheme {
+			// Reuses the single usable lba of the gpt minimum as the first partition block
+			Scheme::Gpt => gpt::PartitionArrayLayout::STANDARD.min_disk_size_lba(self.disk.block_size).saturating_add(partitions_lba.saturating_sub(1)),
+			Scheme::Mbr => partitions_lba.saturating_add(1),
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+	Io {path: PathBuf, error: io::Error},
+	Parse(toml::de::Error),
+	Invalid(String),
+}
+
+impl fmt::Display for ManifestError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ManifestError::Io {path, error} => write!(f, "can't read manifest {}: {}", path.display(), error),
+			ManifestError::Parse(error) => write!(f, "can't parse manifest: {}", error),
+			ManifestError::Invalid(msg) => write!(f, "invalid manifest: {}", msg),
+		}
+	}
+}
+
+impl error::Error for ManifestError {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	pub fn parse_manifest() {
+		let manifest = Manifest::parse(r#"
+			[disk]
+			size = "128MiB"
+			
+			[[partition]]
+			name = "UEFI System"
+			type = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+			size = 34077184
+			filesystem = "fat"
+			files = [{source = "boot.efi", target = "/efi/boot/bootx64.efi"}]
+			
+			[[partition]]
+			name = "Nell Boot"
+			type = "77ffd558-c91d-42e0-b03d-7f1efd959111"
+			guid = "a4a4a4a4-a4a4-a4a4-a4a4-a4a4a4a4a4a4"
+			attributes = "type:12"
+			size = "32M"
+		"#).unwrap();
+		
+		assert_eq!(manifest.disk.scheme, Scheme::Gpt);
+		assert_eq!(manifest.disk.block_size, 512);
+		assert_eq!(manifest.disk.size, Some(ByteSize(128 << 20)));
+		
+		let esp = &manifest.partitions[0];
+		assert_eq!(esp.type_guid, gpt::partition_types::EFI_SYSTEM);
+		assert_eq!(esp.size, Some(ByteSize(34077184)));
+		assert_eq!(esp.mbr_os_type(), mbr::os_types::UEFI_SYSTEM);
+		assert_eq!(esp.files[0].target, "/efi/boot/bootx64.efi");
+		
+		let boot = &manifest.partitions[1];
+		assert_eq!(boot.guid, Some(Guid::from_u128(0xA4A4A4A4_A4A4_A4A4_A4A4_A4A4A4A4A4A4)));
+		assert_eq!(boot.attributes, GptPartitionAttribs::type_specific(12));
+		assert_eq!(boot.filesystem, Filesystem::None);
+		assert_eq!(boot.mbr_os_type(), mbr::os_types::FAT32_LBA);
+	}
+	
+	#[test]
+	pub fn reject_invalid_manifests() {
+		let part = |extra: &str| format!("[disk]\nsize = \"1M\"\n[[partition]]\nname = \"a\"\ntype = \"C12A7328-F81F-11D2-BA4B-00A0C93EC93B\"\nsize = 512\n{}", extra);
+		
+		assert!(Manifest::parse(&part("")).is_ok());
+		assert!(matches!(Manifest::parse(&part("colour = \"red\"")), Err(ManifestError::Parse(_))));
+		assert!(matches!(Manifest::parse(&part("attributes = \"shiny\"")), Err(ManifestError::Parse(_))));
+		assert!(matches!(Manifest::parse(&part("files = [{source = \"a\", target = \"/a\"}]")), Err(ManifestError::Invalid(_))));
+		assert!(matches!(Manifest::parse(&part("filesystem = \"fat\"\nfiles = [{source = \"a\", target = \"a\"}]")), Err(ManifestError::Invalid(_))));
+		assert!(matches!(Manifest::parse(&part("filesystem = \"fat\"\nfiles = [{source = \"a\", target = \"/a\", include = [\"[\"]}]")), Err(ManifestError::Parse(_))));
+		assert!(matches!(Manifest::parse("[disk]\nsize = \"12 parsecs\""), Err(ManifestError::Parse(_))));
+		assert!(matches!(Manifest::parse("[disk]\nsize = 1024\nblock_size = 1000"), Err(ManifestError::Invalid(_))));
+		assert!(matches!(Manifest::parse("[disk]\nsize = 1024\nslack = 1024"), Err(ManifestError::Invalid(_))));
+		assert!(matches!(Manifest::parse(&part("headroom = \"1M\"")), Err(ManifestError::Invalid(_))));
+		
+		// 68 blocks for the gpt with one usable block, which the first partition block reuses
+		let sized = |disk: u64, part: u64| format!("[disk]\nsize = {}\n[[partition]]\nname = \"a\"\ntype = \"C12A7328-F81F-11D2-BA4B-00A0C93EC93B\"\nsize = {}", disk, part);
+		assert!(Manifest::parse(&sized(69 * 512, 2 * 512)).is_ok());
+		assert!(matches!(Manifest::parse(&sized(69 * 512 - 1, 2 * 512)), Err(ManifestError::Invalid(msg)) if msg.contains("disk.size")));
+		assert!(matches!(Manifest::parse(&sized(69 * 512, 2 * 512 + 1)), Err(ManifestError::Invalid(_))));
+		assert!(matches!(Manifest::parse(&sized(69 * 512, i64::MAX as u64)), Err(ManifestError::Invalid(_))));
+		assert!(matches!(Manifest::parse("[disk]\nsize = 1024"), Err(ManifestError::Invalid(_))));
+	}
+	
+	#[test]
+	pub fn parse_auto_sized_manifest() {
+		let manifest = Manifest::parse(r#"
+			[disk]
+			slack = "1M"
+			
+			[[partition]]
+			name = "UEFI System"
+			type = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+			filesystem = "fat32"
+			
+			[[partition]]
+			name = "Nell Boot"
+			type = "77ffd558-c91d-42e0-b03d-7f1efd959111"
+			headroom = "512K"
+			filesystem = "fat"
+			files = [{source = "sysroot", target = "/", include = ["*.elf", "lib/*"], exclude = [".*"]}]
+		"#).unwrap();
+		
+		assert_eq!(manifest.disk.size, None);
+		assert_eq!(manifest.disk.slack, ByteSize(1 << 20));
+		assert_eq!(manifest.partitions[0].size, None);
+		assert_eq!(manifest.partitions[0].filesystem.fat_type(), Some(FatType::Fat32));
+		assert_eq!(manifest.partitions[1].headroom, ByteSize(512 << 10));
+		assert_eq!(manifest.partitions[1].filesystem.fat_type(), None);
+		assert_eq!(manifest.partitions[1].files[0].include, [Pattern::new("*.elf").unwrap(), Pattern::new("lib/*").unwrap()]);
+		assert_eq!(manifest.partitions[1].files[0].exclude.len(), 1);
+		assert!(manifest.partitions[0].files.is_empty());
+		
+		let unsized_raw = "[disk]\n[[partition]]\nname = \"a\"\ntype = \"C12A7328-F81F-11D2-BA4B-00A0C93EC93B\"";
+		assert!(matches!(Manifest::parse(unsized_raw), Err(ManifestError::Invalid(_))));
+	}
+}
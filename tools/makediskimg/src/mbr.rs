@@ -1,18 +1,32 @@
-use std::mem;
-use std::intrinsics::transmute;
+use std::{cmp, error, fmt, io, mem};
+use std::io::Read;
+
+use crate::Error;
+use crate::blockdev::BlockDevice;
+use crate::gpt::{self, DEFAULT_PARTITION_ALIGNMENT};
+use crate::guid::Guid;
 
 pub type MbrOsType = u8;
 
 pub mod os_types {
 	use super::MbrOsType;
 	
+	pub const FAT32_LBA: MbrOsType = 0x0C;
+	pub const EXTENDED_LBA: MbrOsType = 0x0F;
 	pub const UEFI_SYSTEM: MbrOsType = 0xEF;
 	pub const GPT_PROTECTIVE: MbrOsType = 0xEE;
 }
 
 pub const PROTECTIVE_MBR_SIGNATURE: u16 = 0xAA55;
 
+/// Size of the bootstrap code area at the start of the mbr.
+pub const BOOTSTRAP_CODE_SIZE: usize = 440;
+
+/// Maximum number of gpt partitions a hybrid mbr can mirror, one entry is taken by the protective partition.
+pub const MAX_HYBRID_PARTITIONS: usize = 3;
+
 #[repr(C)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MasterBootRecord {
 	pub bootstrap_code: [u8; 440],
 	pub unique_mbr_signature: [u8; 4],
@@ -22,6 +36,7 @@ pub struct MasterBootRecord {
 }
 
 #[repr(C)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MbrPartitionEntry {
 	pub boot_indicator: u8,
 	pub start_head: u8,
@@ -37,62 +52,833 @@ pub struct MbrPartitionEntry {
 	pub size_in_lba: [u8; 4]
 }
 
-pub fn make_gpt_protective_mbr(_clamped_max_chs: u32) -> MasterBootRecord {
-	MasterBootRecord {
-		bootstrap_code: [0; 440],
-		unique_mbr_signature: [0; 4],
-		unknown: [0; 2],
-		partitions: [
-			make_gpt_protective_partition_entry(_clamped_max_chs),
-			unsafe {transmute([0u8; mem::size_of::<MbrPartitionEntry>()])},
-			unsafe {transmute([0u8; mem::size_of::<MbrPartitionEntry>()])},
-			unsafe {transmute([0u8; mem::size_of::<MbrPartitionEntry>()])}
-		],
-		signature: PROTECTIVE_MBR_SIGNATURE
+// The on-disk layout, the structs must not contain any padding
+const _: () = assert!(mem::size_of::<MasterBootRecord>() == MasterBootRecord::SIZE);
+const _: () = assert!(mem::size_of::<MbrPartitionEntry>() == MbrPartitionEntry::SIZE);
+
+impl MasterBootRecord {
+	pub const SIZE: usize = 512;
+	
+	pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+		let mut raw = [0u8; Self::SIZE];
+		raw[..440].copy_from_slice(&self.bootstrap_code);
+		raw[440..444].copy_from_slice(&self.unique_mbr_signature);
+		raw[444..446].copy_from_slice(&self.unknown);
+		for (i, entry) in self.partitions.iter().enumerate() {
+			let offset = 446 + i * MbrPartitionEntry::SIZE;
+			raw[offset..offset + MbrPartitionEntry::SIZE].copy_from_slice(&entry.to_bytes());
+		}
+		raw[510..512].copy_from_slice(&self.signature.to_le_bytes());
+		raw
+	}
+	
+	/// Parses a boot record, doesn't validate anything (not even the signature).
+	pub fn from_bytes(raw: &[u8; Self::SIZE]) -> MasterBootRecord {
+		let entry = |i: usize| {
+			let offset = 446 + i * MbrPartitionEntry::SIZE;
+			let mut entry_raw = [0u8; MbrPartitionEntry::SIZE];
+			entry_raw.copy_from_slice(&raw[offset..offset + MbrPartitionEntry::SIZE]);
+			MbrPartitionEntry::from_bytes(&entry_raw)
+		};
+		
+		let mut bootstrap_code = [0u8; 440];
+		bootstrap_code.copy_from_slice(&raw[..440]);
+		
+		MasterBootRecord {
+			bootstrap_code,
+			unique_mbr_signature: [raw[440], raw[441], raw[442], raw[443]],
+			unknown: [raw[444], raw[445]],
+			partitions: [entry(0), entry(1), entry(2), entry(3)],
+			signature: u16::from_le_bytes([raw[510], raw[511]])
+		}
+	}
+	
+	/// Reads a boot record from the current position.
+	pub fn read_from(reader: &mut impl Read) -> io::Result<MasterBootRecord> {
+		let mut raw = [0u8; Self::SIZE];
+		reader.read_exact(&mut raw)?;
+		Ok(Self::from_bytes(&raw))
+	}
+	
+	/// A boot record with signature but no code and no partitions, as used for the extended boot records.
+	pub fn empty() -> MasterBootRecord {
+		MasterBootRecord {
+			bootstrap_code: [0; BOOTSTRAP_CODE_SIZE],
+			unique_mbr_signature: [0; 4],
+			unknown: [0; 2],
+			partitions: [MbrPartitionEntry::empty(), MbrPartitionEntry::empty(), MbrPartitionEntry::empty(), MbrPartitionEntry::empty()],
+			signature: PROTECTIVE_MBR_SIGNATURE
+		}
+	}
+}
+
+impl MbrPartitionEntry {
+	pub const SIZE: usize = 16;
+	
+	pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+		let [s0, s1, s2, s3] = self.starting_lba;
+		let [n0, n1, n2, n3] = self.size_in_lba;
+		[
+			self.boot_indicator, self.start_head, self.start_sector, self.start_track,
+			self.os_type, self.end_head, self.end_sector, self.end_track,
+			s0, s1, s2, s3,
+			n0, n1, n2, n3,
+		]
+	}
+	
+	pub fn from_bytes(raw: &[u8; Self::SIZE]) -> MbrPartitionEntry {
+		MbrPartitionEntry {
+			boot_indicator: raw[0],
+			start_head: raw[1],
+			start_sector: raw[2],
+			start_track: raw[3],
+			os_type: raw[4],
+			end_head: raw[5],
+			end_sector: raw[6],
+			end_track: raw[7],
+			starting_lba: [raw[8], raw[9], raw[10], raw[11]],
+			size_in_lba: [raw[12], raw[13], raw[14], raw[15]],
+		}
+	}
+	
+	pub fn is_empty(&self) -> bool {
+		self.os_type == 0
+	}
+	
+	pub fn starting_lba(&self) -> u32 {
+		u32::from_le_bytes(self.starting_lba)
+	}
+	
+	pub fn size_in_lba(&self) -> u32 {
+		u32::from_le_bytes(self.size_in_lba)
+	}
+	
+	/// An unused entry.
+	pub fn empty() -> MbrPartitionEntry {
+		MbrPartitionEntry::new_raw(0, 0, 0, false)
+	}
+	
+	/// Makes an entry with chs addresses translated from the lba range.
+	pub fn new(os_type: MbrOsType, starting_lba: u32, size_in_lba: u32, bootable: bool) -> MbrPartitionEntry {
+		let geo = ChsGeo::LBA_ASSIST;
+		let end_lba_incl = (starting_lba as u64 + size_in_lba as u64).saturating_sub(1);
+		
+		let mut entry = Self::new_raw(os_type, starting_lba, size_in_lba, bootable);
+		entry.set_start_chs(geo.lba_to_chs_clamped(starting_lba as u64));
+		entry.set_end_chs(geo.lba_to_chs_clamped(end_lba_incl));
+		entry
+	}
+	
+	/// Makes an entry with zeroed chs addresses.
+	fn new_raw(os_type: MbrOsType, starting_lba: u32, size_in_lba: u32, bootable: bool) -> MbrPartitionEntry {
+		MbrPartitionEntry {
+			boot_indicator: if bootable {0x80} else {0x00},
+			start_head: 0,
+			start_sector: 0,
+			start_track: 0,
+			os_type,
+			end_head: 0,
+			end_sector: 0,
+			end_track: 0,
+			starting_lba: starting_lba.to_le_bytes(),
+			size_in_lba: size_in_lba.to_le_bytes(),
+		}
+	}
+	
+	pub fn start_chs(&self) -> Chs {
+		Chs::decode([self.start_head, self.start_sector, self.start_track])
+	}
+	
+	pub fn set_start_chs(&mut self, chs: Chs) {
+		let [head, sector, track] = chs.encode();
+		self.start_head = head;
+		self.start_sector = sector;
+		self.start_track = track;
+	}
+	
+	pub fn end_chs(&self) -> Chs {
+		Chs::decode([self.end_head, self.end_sector, self.end_track])
+	}
+	
+	pub fn set_end_chs(&mut self, chs: Chs) {
+		let [head, sector, track] = chs.encode();
+		self.end_head = head;
+		self.end_sector = sector;
+		self.end_track = track;
+	}
+}
+
+/// Makes a protective mbr for a gpt disk of the given size.
+pub fn make_gpt_protective_mbr(disk_size_lba: u64) -> MasterBootRecord {
+	let mut mbr = MasterBootRecord::empty();
+	mbr.partitions[0] = make_gpt_protective_partition_entry(disk_size_lba);
+	mbr
+}
+
+/// Makes the single partition entry of a protective mbr, covering the whole disk
+/// after the mbr itself, clamped to what an mbr entry can describe (as per uefi spec 5.2.3).
+pub fn make_gpt_protective_partition_entry(disk_size_lba: u64) -> MbrPartitionEntry {
+	MbrPartitionEntry::new(os_types::GPT_PROTECTIVE, 1, protective_partition_size_lba(disk_size_lba), false)
+}
+
+/// A gpt partition mirrored into a hybrid mbr.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HybridPartition {
+	pub start_lba: u64,
+	pub size_in_lba: u64,
+	pub os_type: MbrOsType,
+	pub bootable: bool,
+}
+
+/// Makes a hybrid mbr for a gpt disk of the given size.
+/// The protective partition comes first and covers everything up to the first mirrored partition,
+/// the mirrored partitions follow in the given order. `bootstrap_code` is placed at the start of the mbr.
+/// With no partitions to mirror this is a protective mbr with bootstrap code.
+pub fn make_hybrid_mbr(disk_size_lba: u64, partitions: &[HybridPartition], bootstrap_code: &[u8]) -> Result<MasterBootRecord, HybridMbrError> {
+	if partitions.len() > MAX_HYBRID_PARTITIONS {
+		return Err(HybridMbrError::TooManyPartitions {count: partitions.len()});
+	}
+	if bootstrap_code.len() > BOOTSTRAP_CODE_SIZE {
+		return Err(HybridMbrError::BootstrapCodeTooLarge {len: bootstrap_code.len()});
+	}
+	
+	let mut mbr = make_gpt_protective_mbr(disk_size_lba);
+	mbr.bootstrap_code[..bootstrap_code.len()].copy_from_slice(bootstrap_code);
+	
+	for (i, part) in partitions.iter().enumerate() {
+		if part.start_lba < 2 || part.start_lba + part.size_in_lba > disk_size_lba {
+			return Err(HybridMbrError::OutsideDisk {index: i});
+		}
+		if part.start_lba + part.size_in_lba > u32::MAX as u64 + 1 {
+			return Err(HybridMbrError::BeyondMbrRange {index: i});
+		}
+		
+		mbr.partitions[i + 1] = MbrPartitionEntry::new(part.os_type, part.start_lba as u32, part.size_in_lba as u32, part.bootable);
+	}
+	
+	// Shrink the protective partition so it doesn't overlap the mirrored ones
+	if let Some(first_start) = partitions.iter().map(|p| p.start_lba).min() {
+		let size_in_lba = cmp::min(protective_partition_size_lba(disk_size_lba) as u64, first_start - 1);
+		mbr.partitions[0] = MbrPartitionEntry::new(os_types::GPT_PROTECTIVE, 1, size_in_lba as u32, false);
+	}
+	
+	Ok(mbr)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HybridMbrError {
+	TooManyPartitions {count: usize},
+	BootstrapCodeTooLarge {len: usize},
+	OutsideDisk {index: usize},
+	BeyondMbrRange {index: usize},
+}
+
+impl fmt::Display for HybridMbrError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			HybridMbrError::TooManyPartitions {count} => write!(f, "hybrid mbr can mirror at most {} partitions, got {}", MAX_HYBRID_PARTITIONS, count),
+			HybridMbrError::BootstrapCodeTooLarge {len} => write!(f, "bootstrap code is {} bytes, at most {} fit into the mbr", len, BOOTSTRAP_CODE_SIZE),
+			HybridMbrError::OutsideDisk {index} => write!(f, "mirrored partition {} lies outside the disk", index),
+			HybridMbrError::BeyondMbrRange {index} => write!(f, "mirrored partition {} extends beyond what an mbr entry can address", index),
+		}
+	}
+}
+
+impl error::Error for HybridMbrError {}
+
+/// A classic mbr partitioned disk.
+///
+/// Partitions are laid out back to back in the order they are added, each aligned to the disk's alignment.
+/// Logical partitions live in a single extended partition, each preceded by its extended boot record (ebr).
+pub struct MbrDisk {
+	block_size: u32,
+	disk_size_lba: u64,
+	alignment_lba: u64,
+	disk_signature: u32,
+	bootstrap_code: [u8; BOOTSTRAP_CODE_SIZE],
+	primary_partitions: Vec<MbrPartition>,
+	logical_partitions: Vec<MbrPartition>,
+	/// Lba of the ebr in front of each logical partition
+	logical_ebr_lbas: Vec<u64>,
+}
+
+impl MbrDisk {
+	/// Create a new, empty disk. Uses a random disk signature if `disk_signature` is `None`.
+	pub fn new_empty(block_size: u32, disk_size_lba: u64, disk_signature: Option<u32>) -> MbrDisk {
+		let disk_signature = disk_signature.unwrap_or_else(|| Guid::new_v4().first_u32());
+		
+		MbrDisk {
+			block_size,
+			disk_size_lba,
+			alignment_lba: cmp::max(1, DEFAULT_PARTITION_ALIGNMENT / block_size as u64),
+			disk_signature,
+			bootstrap_code: [0; BOOTSTRAP_CODE_SIZE],
+			primary_partitions: Vec::new(),
+			logical_partitions: Vec::new(),
+			logical_ebr_lbas: Vec::new(),
+		}
+	}
+	
+	/// Sets the alignment of partitions (and ebrs) added afterwards, rounded up to whole blocks.
+	pub fn set_alignment(&mut self, alignment_bytes: u64) {
+		self.alignment_lba = cmp::max(1, alignment_bytes.div_ceil(self.block_size as u64));
+	}
+	
+	pub fn set_bootstrap_code(&mut self, code: &[u8]) -> Result<(), MbrError> {
+		if code.len() > BOOTSTRAP_CODE_SIZE {
+			return Err(MbrError::BootstrapCodeTooLarge {len: code.len()});
+		}
+		
+		self.bootstrap_code = [0; BOOTSTRAP_CODE_SIZE];
+		self.bootstrap_code[..code.len()].copy_from_slice(code);
+		Ok(())
+	}
+	
+	/// Adds a primary partition after all existing partitions.
+	pub fn add_primary_partition(&mut self, os_type: MbrOsType, size_in_lba: u64, bootable: bool) -> Result<&MbrPartition, MbrError> {
+		// The extended partition takes up a slot as well
+		let used_slots = self.primary_partitions.len() + if self.logical_partitions.is_empty() {0} else {1};
+		if used_slots >= 4 {
+			return Err(MbrError::TooManyPrimaryPartitions);
+		}
+		self.check_bootable(bootable)?;
+		
+		let start_lba = self.align_up(self.next_free_lba());
+		let partition = self.make_partition(os_type, start_lba, size_in_lba, bootable)?;
+		
+		self.primary_partitions.push(partition);
+		Ok(self.primary_partitions.last().unwrap())
+	}
+	
+	/// Adds a logical partition after all existing partitions, growing the extended partition.
+	pub fn add_logical_partition(&mut self, os_type: MbrOsType, size_in_lba: u64, bootable: bool) -> Result<&MbrPartition, MbrError> {
+		if self.logical_partitions.is_empty() && self.primary_partitions.len() >= 4 {
+			return Err(MbrError::TooManyPrimaryPartitions);
+		}
+		// The extended partition must stay contiguous
+		if let Some(last_logical) = self.logical_partitions.last() {
+			if self.primary_partitions.iter().any(|p| p.start_lba > last_logical.end_lba_incl) {
+				return Err(MbrError::ExtendedNotContiguous);
+			}
+		}
+		self.check_bootable(bootable)?;
+		
+		let ebr_lba = self.align_up(self.next_free_lba());
+		let start_lba = self.align_up(ebr_lba + 1);
+		let partition = self.make_partition(os_type, start_lba, size_in_lba, bootable)?;
+		
+		self.logical_ebr_lbas.push(ebr_lba);
+		self.logical_partitions.push(partition);
+		Ok(self.logical_partitions.last().unwrap())
+	}
+	
+	fn make_partition(&self, os_type: MbrOsType, start_lba: u64, size_in_lba: u64, bootable: bool) -> Result<MbrPartition, MbrError> {
+		if size_in_lba == 0 {
+			return Err(MbrError::ZeroSize);
+		}
+		
+		let end_lba_incl = start_lba + size_in_lba - 1;
+		if end_lba_incl >= self.disk_size_lba {
+			return Err(MbrError::NoSpace {size_in_lba});
+		}
+		if end_lba_incl > u32::MAX as u64 {
+			return Err(MbrError::BeyondMbrRange {start_lba, end_lba_incl});
+		}
+		
+		Ok(MbrPartition {
+			os_type,
+			bootable,
+			start_lba,
+			size_in_lba,
+			end_lba_incl,
+		})
+	}
+	
+	fn check_bootable(&self, bootable: bool) -> Result<(), MbrError> {
+		match bootable && self.partitions().any(|p| p.bootable) {
+			true => Err(MbrError::MultipleBootable),
+			false => Ok(()),
+		}
+	}
+	
+	fn next_free_lba(&self) -> u64 {
+		self.partitions()
+			.map(|p| p.end_lba_incl + 1)
+			.max()
+			.unwrap_or(1)
+	}
+	
+	fn align_up(&self, lba: u64) -> u64 {
+		lba.div_ceil(self.alignment_lba) * self.alignment_lba
+	}
+	
+	pub fn block_size(&self) -> u32 {
+		self.block_size
+	}
+	
+	pub fn disk_size_lba(&self) -> u64 {
+		self.disk_size_lba
+	}
+	
+	/// Smallest disk size that still holds all partitions.
+	pub fn min_disk_size_lba(&self) -> u64 {
+		self.next_free_lba()
+	}
+	
+	/// Moves the end of the disk, the partitions stay where they are.
+	pub fn resize_disk(&mut self, disk_size_lba: u64) -> Result<(), MbrError> {
+		let min_size_lba = self.min_disk_size_lba();
+		if disk_size_lba < min_size_lba {
+			return Err(MbrError::DiskTooSmall {min_size_lba});
+		}
+		
+		self.disk_size_lba = disk_size_lba;
+		Ok(())
+	}
+	
+	pub fn disk_signature(&self) -> u32 {
+		self.disk_signature
+	}
+	
+	pub fn primary_partitions(&self) -> &[MbrPartition] {
+		&self.primary_partitions
+	}
+	
+	pub fn logical_partitions(&self) -> &[MbrPartition] {
+		&self.logical_partitions
+	}
+	
+	/// All partitions, primary ones first.
+	pub fn partitions(&self) -> impl Iterator<Item = &MbrPartition> {
+		self.primary_partitions.iter().chain(self.logical_partitions.iter())
+	}
+	
+	/// The lba range of the extended partition, `None` if there are no logical partitions.
+	pub fn extended_partition(&self) -> Option<(u64, u64)> {
+		let start = *self.logical_ebr_lbas.first()?;
+		let end_incl = self.logical_partitions.last()?.end_lba_incl;
+		Some((start, end_incl))
+	}
+	
+	/// Builds the master boot record: the primary partitions followed by the extended partition.
+	pub fn master_boot_record(&self) -> MasterBootRecord {
+		let mut record = MasterBootRecord::empty();
+		record.bootstrap_code = self.bootstrap_code;
+		record.unique_mbr_signature = self.disk_signature.to_le_bytes();
+		
+		for (i, p) in self.primary_partitions.iter().enumerate() {
+			record.partitions[i] = MbrPartitionEntry::new(p.os_type, p.start_lba as u32, p.size_in_lba as u32, p.bootable);
+		}
+		if let Some((start, end_incl)) = self.extended_partition() {
+			record.partitions[self.primary_partitions.len()] = MbrPartitionEntry::new(os_types::EXTENDED_LBA, start as u32, (end_incl - start + 1) as u32, false);
+		}
+		
+		record
+	}
+	
+	/// Builds the chain of extended boot records with their lbas.
+	/// Each ebr describes its logical partition relative to itself and links the next ebr relative to the extended partition.
+	pub fn extended_boot_records(&self) -> Vec<(u64, MasterBootRecord)> {
+		let (extended_start, _) = match self.extended_partition() {
+			Some(a) => a,
+			None => return Vec::new(),
+		};
+		let geo = ChsGeo::LBA_ASSIST;
+		
+		let mut records = Vec::with_capacity(self.logical_partitions.len());
+		for (i, (p, &ebr_lba)) in self.logical_partitions.iter().zip(self.logical_ebr_lbas.iter()).enumerate() {
+			let mut record = MasterBootRecord::empty();
+			
+			// Chs addresses stay absolute
+			let mut entry = MbrPartitionEntry::new_raw(p.os_type, (p.start_lba - ebr_lba) as u32, p.size_in_lba as u32, p.bootable);
+			entry.set_start_chs(geo.lba_to_chs_clamped(p.start_lba));
+			entry.set_end_chs(geo.lba_to_chs_clamped(p.end_lba_incl));
+			record.partitions[0] = entry;
+			
+			if let (Some(next), Some(&next_ebr_lba)) = (self.logical_partitions.get(i + 1), self.logical_ebr_lbas.get(i + 1)) {
+				let mut link = MbrPartitionEntry::new_raw(os_types::EXTENDED_LBA, (next_ebr_lba - extended_start) as u32, (next.end_lba_incl - next_ebr_lba + 1) as u32, false);
+				link.set_start_chs(geo.lba_to_chs_clamped(next_ebr_lba));
+				link.set_end_chs(geo.lba_to_chs_clamped(next.end_lba_incl));
+				record.partitions[1] = link;
+			}
+			
+			records.push((ebr_lba, record));
+		}
+		records
+	}
+	
+	/// Writes the disk to a device with the same block size and at least as many blocks.
+	pub fn writer<D: BlockDevice>(&self, device: D) -> MbrDiskWriter<'_, D> {
+		MbrDiskWriter {
+			disk: self,
+			device,
+			initialized: false,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MbrPartition {
+	pub os_type: MbrOsType,
+	pub bootable: bool,
+	pub start_lba: u64,
+	pub size_in_lba: u64,
+	pub end_lba_incl: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MbrError {
+	ZeroSize,
+	/// All four mbr slots are in use (the extended partition counts as one).
+	TooManyPrimaryPartitions,
+	/// A logical partition can't be added after a primary partition that follows the extended partition.
+	ExtendedNotContiguous,
+	MultipleBootable,
+	NoSpace {size_in_lba: u64},
+	BeyondMbrRange {start_lba: u64, end_lba_incl: u64},
+	BootstrapCodeTooLarge {len: usize},
+	DiskTooSmall {min_size_lba: u64},
+}
+
+impl fmt::Display for MbrError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MbrError::ZeroSize => write!(f, "partition size must not be zero"),
+			MbrError::TooManyPrimaryPartitions => write!(f, "all 4 mbr partition slots are in use"),
+			MbrError::ExtendedNotContiguous => write!(f, "logical partitions must be contiguous, but a primary partition follows the extended partition"),
+			MbrError::MultipleBootable => write!(f, "only one partition can be bootable"),
+			MbrError::NoSpace {size_in_lba} => write!(f, "no free space for a partition of {} lba", size_in_lba),
+			MbrError::BeyondMbrRange {start_lba, end_lba_incl} => write!(f, "lba range {}..={} extends beyond what an mbr entry can address", start_lba, end_lba_incl),
+			MbrError::BootstrapCodeTooLarge {len} => write!(f, "bootstrap code is {} bytes, at most {} fit into the mbr", len, BOOTSTRAP_CODE_SIZE),
+			MbrError::DiskTooSmall {min_size_lba} => write!(f, "disk must be at least {} lba to hold all partitions", min_size_lba),
+		}
 	}
 }
 
-pub fn make_gpt_protective_partition_entry(_clamped_max_chs: u32) -> MbrPartitionEntry {
-	MbrPartitionEntry {
-		boot_indicator: 0,
-		start_head: 0x00,
-		start_sector: 0x02,
-		start_track: 0x00,
-		os_type: os_types::GPT_PROTECTIVE,
-		end_head: 0xFF, // TODO: Properly handle end chs
-		end_sector: 0xFF,
-		end_track: 0xFF,
-		starting_lba: [0x01, 0x00, 0x00, 0x00],
-		size_in_lba: [0xFF, 0xFF, 0xFF, 0xFF] // TODO: Properly handle size in lba
+impl error::Error for MbrError {}
+
+pub struct MbrDiskWriter<'a, D> {
+	disk: &'a MbrDisk,
+	device: D,
+	initialized: bool,
+}
+
+impl<'a, D: BlockDevice> MbrDiskWriter<'a, D> {
+	fn ensure_init(&mut self) -> Result<(), Error> {
+		if !self.initialized {
+			gpt::check_device(&self.device, self.disk.block_size, self.disk.disk_size_lba)?;
+			self.initialized = true;
+		}
+		
+		Ok(())
+	}
+	
+	/// Writes the mbr and all ebrs.
+	pub fn write_boot_records(&mut self) -> Result<(), Error> {
+		self.write_record(0, &self.disk.master_boot_record())?;
+		for (lba, record) in self.disk.extended_boot_records() {
+			self.write_record(lba, &record)?;
+		}
+		
+		Ok(())
+	}
+	
+	fn write_record(&mut self, lba: u64, record: &MasterBootRecord) -> Result<(), Error> {
+		// Init
+		self.ensure_init()?;
+		
+		// The rest of a bigger block is kept
+		let mut block = vec![0u8; self.disk.block_size as usize];
+		self.device.read_blocks(lba, &mut block)
+			.and_then(|_| {
+				block[..MasterBootRecord::SIZE].copy_from_slice(&record.to_bytes());
+				self.device.write_blocks(lba, &block)
+			})
+			.map_err(|e| Error::io(format!("can't write the boot record at lba {}", lba), e))
+	}
+	
+	pub fn write_partition_content(&mut self, partition: &MbrPartition, stream: &mut dyn Read) -> Result<(), Error> {
+		// Init
+		self.ensure_init()?;
+		
+		gpt::write_content(&mut self.device, partition.start_lba, partition.start_lba + partition.size_in_lba, stream)
+			.map_err(|e| Error::io("can't write partition content", e))
+	}
+	
+	/// Flushes and returns the device.
+	pub fn flush(mut self) -> Result<D, Error> {
+		self.device.flush().map_err(|e| Error::io("can't flush the disk", e))?;
+		Ok(self.device)
 	}
 }
 
-/// Converts the given LBA to a CHS address clamped to 0xFFFFFF.
-#[deprecated]
-pub fn lba_to_chs_clamped(_lba: u32) -> u32 {
-	// TODO: This is stupid because we would actually need the god damn head and cylinder counts from the actual drive (which also literally don't exist) to calculate the chs
-	// TODO: Just put 0xFFFFFF as the end chs of the protective partition entry and be done with it (eventhough *in theory* that's not completely correct as the chs must not lay outside the disk as per uefi spec)
-	// TODO: (That won't happen anyway because we don't use 10 MB drives anymore so the chs will literally always be clamped anyway)
-	0
+/// Size of the protective partition of a disk with the given size: `min(disk_size_lba - 1, 0xFFFFFFFF)`.
+pub fn protective_partition_size_lba(disk_size_lba: u64) -> u32 {
+	cmp::min(disk_size_lba.saturating_sub(1), u32::MAX as u64) as u32
 }
 
 /// A cylinder-head-sector address.
+/// Sectors count from 1, cylinders and heads from 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Chs {
-	
+	pub cylinder: u16,
+	pub head: u8,
+	pub sector: u8,
 }
 
 impl Chs {
-	pub fn max() {
-		
+	/// The largest encodable address (`0xFFFFFF` encoded),
+	/// also used for any address that lies beyond what chs can describe.
+	pub const MAX: Chs = Chs {
+		cylinder: 1023,
+		head: 255,
+		sector: 63,
+	};
+	
+	/// Encodes into the 3 byte mbr format: head, sector plus the cylinder's high bits, cylinder low byte.
+	pub fn encode(&self) -> [u8; 3] {
+		[
+			self.head,
+			(self.sector & 0x3F) | ((self.cylinder >> 2) as u8 & 0xC0),
+			self.cylinder as u8,
+		]
+	}
+	
+	pub fn decode(raw: [u8; 3]) -> Chs {
+		Chs {
+			cylinder: ((raw[1] as u16 & 0xC0) << 2) | raw[2] as u16,
+			head: raw[0],
+			sector: raw[1] & 0x3F,
+		}
 	}
 }
 
 /// Geometry of a [cylinder-head-sector](self::Chs) addressed disk.
-/// Represents the number
+/// Represents the number of heads per cylinder and sectors per track used for lba translation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct ChsGeo {
-	
+	pub heads: u16,
+	pub sectors_per_track: u8,
 }
 
 impl ChsGeo {
+	/// The usual lba assist translation geometry of 255 heads and 63 sectors per track.
+	/// Real disks don't have a geometry anymore, so this is what tools and firmware assume.
+	pub const LBA_ASSIST: ChsGeo = ChsGeo {
+		heads: 255,
+		sectors_per_track: 63,
+	};
 	
-}
\ No newline at end of file
+	/// Converts the given lba to a chs address, `None` if it lies beyond cylinder 1023.
+	pub fn lba_to_chs(&self, lba: u64) -> Option<Chs> {
+		let spt = self.sectors_per_track as u64;
+		let heads = self.heads as u64;
+		
+		let cylinder = lba / (heads * spt);
+		if cylinder > Chs::MAX.cylinder as u64 {
+			return None;
+		}
+		
+		Some(Chs {
+			cylinder: cylinder as u16,
+			head: ((lba / spt) % heads) as u8,
+			sector: (lba % spt + 1) as u8,
+		})
+	}
+	
+	/// Converts the given lba to a chs address, clamped to [`Chs::MAX`].
+	pub fn lba_to_chs_clamped(&self, lba: u64) -> Chs {
+		self.lba_to_chs(lba).unwrap_or(Chs::MAX)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	pub fn chs_encoding_round_trip() {
+		assert_eq!(Chs::MAX.encode(), [0xFF, 0xFF, 0xFF]);
+		assert_eq!(Chs::decode([0xFF, 0xFF, 0xFF]), Chs::MAX);
+		
+		let chs = Chs {cylinder: 0x2A5, head: 17, sector: 9};
+		assert_eq!(chs.encode(), [17, 0x89, 0xA5]);
+		assert_eq!(Chs::decode(chs.encode()), chs);
+	}
+	
+	#[test]
+	pub fn lba_to_chs() {
+		let geo = ChsGeo::LBA_ASSIST;
+		assert_eq!(geo.lba_to_chs(0), Some(Chs {cylinder: 0, head: 0, sector: 1}));
+		assert_eq!(geo.lba_to_chs(1), Some(Chs {cylinder: 0, head: 0, sector: 2}));
+		assert_eq!(geo.lba_to_chs(63), Some(Chs {cylinder: 0, head: 1, sector: 1}));
+		assert_eq!(geo.lba_to_chs(255 * 63), Some(Chs {cylinder: 1, head: 0, sector: 1}));
+		assert_eq!(geo.lba_to_chs(1024 * 255 * 63 - 1), Some(Chs {cylinder: 1023, head: 254, sector: 63}));
+		assert_eq!(geo.lba_to_chs(1024 * 255 * 63), None);
+		assert_eq!(geo.lba_to_chs_clamped(1024 * 255 * 63), Chs::MAX);
+	}
+	
+	#[test]
+	pub fn protective_entry_size() {
+		// Small disk, size and end chs cover exactly the disk
+		let entry = make_gpt_protective_partition_entry(262100);
+		assert_eq!(u32::from_le_bytes(entry.starting_lba), 1);
+		assert_eq!(u32::from_le_bytes(entry.size_in_lba), 262099);
+		assert_eq!(entry.start_chs(), Chs {cylinder: 0, head: 0, sector: 2});
+		assert_eq!(entry.end_chs(), ChsGeo::LBA_ASSIST.lba_to_chs(262099).unwrap());
+		
+		// Huge disk, everything clamped
+		let entry = make_gpt_protective_partition_entry(0x1_0000_0000 + 100);
+		assert_eq!(u32::from_le_bytes(entry.size_in_lba), 0xFFFFFFFF);
+		assert_eq!(entry.end_chs(), Chs::MAX);
+	}
+	
+	#[test]
+	pub fn hybrid_mbr_layout() {
+		let esp = HybridPartition {start_lba: 2048, size_in_lba: 4096, os_type: os_types::UEFI_SYSTEM, bootable: true};
+		let data = HybridPartition {start_lba: 8192, size_in_lba: 1024, os_type: os_types::FAT32_LBA, bootable: false};
+		let mbr = make_hybrid_mbr(100_000, &[esp, data], &[0xEB, 0x63, 0x90]).unwrap();
+		
+		assert_eq!(&mbr.bootstrap_code[..4], &[0xEB, 0x63, 0x90, 0x00]);
+		assert_eq!(mbr.signature, PROTECTIVE_MBR_SIGNATURE);
+		
+		let protective = &mbr.partitions[0];
+		assert_eq!(protective.os_type, os_types::GPT_PROTECTIVE);
+		assert_eq!(u32::from_le_bytes(protective.starting_lba), 1);
+		assert_eq!(u32::from_le_bytes(protective.size_in_lba), 2047);
+		
+		let mirrored = &mbr.partitions[1];
+		assert_eq!(mirrored.os_type, os_types::UEFI_SYSTEM);
+		assert_eq!(mirrored.boot_indicator, 0x80);
+		assert_eq!(u32::from_le_bytes(mirrored.starting_lba), 2048);
+		assert_eq!(u32::from_le_bytes(mirrored.size_in_lba), 4096);
+		assert_eq!(mirrored.end_chs(), ChsGeo::LBA_ASSIST.lba_to_chs(2048 + 4096 - 1).unwrap());
+		
+		assert_eq!(mbr.partitions[2].os_type, os_types::FAT32_LBA);
+		assert_eq!(mbr.partitions[2].boot_indicator, 0x00);
+		assert_eq!(mbr.partitions[3].os_type, 0);
+	}
+	
+	#[test]
+	pub fn hybrid_mbr_errors() {
+		let part = HybridPartition {start_lba: 2048, size_in_lba: 16, os_type: os_types::FAT32_LBA, bootable: false};
+		assert_eq!(make_hybrid_mbr(100_000, &[part; 4], &[]).err(), Some(HybridMbrError::TooManyPartitions {count: 4}));
+		assert_eq!(make_hybrid_mbr(100_000, &[part], &[0; 441]).err(), Some(HybridMbrError::BootstrapCodeTooLarge {len: 441}));
+		assert_eq!(make_hybrid_mbr(2050, &[part], &[]).err(), Some(HybridMbrError::OutsideDisk {index: 0}));
+		
+		let huge = HybridPartition {start_lba: 0xFFFF_0000, size_in_lba: 0x1_0001, ..part};
+		assert_eq!(make_hybrid_mbr(0x2_0000_0000, &[huge], &[]).err(), Some(HybridMbrError::BeyondMbrRange {index: 0}));
+	}
+	
+	#[test]
+	pub fn mbr_disk_layout() {
+		let mut disk = MbrDisk::new_empty(512, 100_000, Some(0xDEADBEEF));
+		disk.add_primary_partition(os_types::UEFI_SYSTEM, 4096, true).unwrap();
+		disk.add_logical_partition(os_types::FAT32_LBA, 1000, false).unwrap();
+		disk.add_logical_partition(os_types::FAT32_LBA, 2000, false).unwrap();
+		disk.add_primary_partition(os_types::FAT32_LBA, 100, false).unwrap();
+		
+		let logical = disk.logical_partitions();
+		assert_eq!(disk.primary_partitions()[0].start_lba, 2048);
+		assert_eq!((logical[0].start_lba, logical[0].end_lba_incl), (8192, 9191));
+		assert_eq!((logical[1].start_lba, logical[1].end_lba_incl), (12288, 14287));
+		assert_eq!(disk.primary_partitions()[1].start_lba, 14336);
+		assert_eq!(disk.extended_partition(), Some((6144, 14287)));
+		
+		let mbr = disk.master_boot_record();
+		assert_eq!(mbr.unique_mbr_signature, [0xEF, 0xBE, 0xAD, 0xDE]);
+		assert_eq!(mbr.partitions[0].boot_indicator, 0x80);
+		assert_eq!(mbr.partitions[1].os_type, os_types::FAT32_LBA);
+		assert_eq!(u32::from_le_bytes(mbr.partitions[1].starting_lba), 14336);
+		assert_eq!(mbr.partitions[2].os_type, os_types::EXTENDED_LBA);
+		assert_eq!(u32::from_le_bytes(mbr.partitions[2].starting_lba), 6144);
+		assert_eq!(u32::from_le_bytes(mbr.partitions[2].size_in_lba), 14287 - 6144 + 1);
+		assert_eq!(mbr.partitions[3].os_type, 0);
+		
+		// Logicals are relative to their ebr, links relative to the extended partition
+		let ebrs = disk.extended_boot_records();
+		assert_eq!(ebrs.len(), 2);
+		assert_eq!(ebrs[0].0, 6144);
+		assert_eq!(u32::from_le_bytes(ebrs[0].1.partitions[0].starting_lba), 2048);
+		assert_eq!(u32::from_le_bytes(ebrs[0].1.partitions[1].starting_lba), 10240 - 6144);
+		assert_eq!(u32::from_le_bytes(ebrs[0].1.partitions[1].size_in_lba), 14287 - 10240 + 1);
+		assert_eq!(ebrs[1].0, 10240);
+		assert_eq!(ebrs[1].1.partitions[1].os_type, 0);
+		assert_eq!(ebrs[1].1.signature, PROTECTIVE_MBR_SIGNATURE);
+	}
+	
+	#[test]
+	pub fn write_to_block_device() {
+		use crate::blockdev::{BlockDevice, IoDevice};
+		use crate::memdisk::MemDisk;
+		
+		let mut disk = MbrDisk::new_empty(4096, 2048, Some(0xDEADBEEF));
+		disk.add_primary_partition(os_types::UEFI_SYSTEM, 256, true).unwrap();
+		let logical = disk.add_logical_partition(os_types::FAT32_LBA, 100, false).unwrap().clone();
+		
+		// Too small for the disk, or the wrong block size
+		assert!(disk.writer(IoDevice::new(MemDisk::new_sparse(2047 * 4096), 4096).unwrap()).write_boot_records().is_err());
+		assert!(disk.writer(IoDevice::new(MemDisk::new_sparse(2048 * 4096), 512).unwrap()).write_boot_records().is_err());
+		
+		let mut writer = disk.writer(IoDevice::new(MemDisk::new_sparse(2048 * 4096), 4096).unwrap());
+		writer.write_boot_records().unwrap();
+		writer.write_partition_content(&logical, &mut &[0xAB; 5000][..]).unwrap();
+		let mut device = writer.flush().unwrap();
+		
+		let mut block = vec![0; 4096];
+		device.read_blocks(0, &mut block).unwrap();
+		assert_eq!(MasterBootRecord::read_from(&mut &block[..]).unwrap(), disk.master_boot_record());
+		
+		let (ebr_lba, ebr) = &disk.extended_boot_records()[0];
+		device.read_blocks(*ebr_lba, &mut block).unwrap();
+		assert_eq!(&MasterBootRecord::read_from(&mut &block[..]).unwrap(), ebr);
+		
+		// Content is padded to whole blocks
+		let mut content = vec![0; 3 * 4096];
+		device.read_blocks(logical.start_lba, &mut content).unwrap();
+		assert!(content[..5000].iter().all(|&b| b == 0xAB));
+		assert!(content[5000..].iter().all(|&b| b == 0));
+	}
+	
+	#[test]
+	pub fn mbr_disk_errors() {
+		let mut disk = MbrDisk::new_empty(512, 100_000, None);
+		disk.set_alignment(512);
+		assert_eq!(disk.add_primary_partition(os_types::FAT32_LBA, 0, false).err(), Some(MbrError::ZeroSize));
+		assert_eq!(disk.add_primary_partition(os_types::FAT32_LBA, 100_000, false).err(), Some(MbrError::NoSpace {size_in_lba: 100_000}));
+		assert_eq!(disk.set_bootstrap_code(&[0; 441]), Err(MbrError::BootstrapCodeTooLarge {len: 441}));
+		
+		disk.add_primary_partition(os_types::FAT32_LBA, 10, true).unwrap();
+		assert_eq!(disk.add_primary_partition(os_types::FAT32_LBA, 10, true).err(), Some(MbrError::MultipleBootable));
+		
+		disk.add_logical_partition(os_types::FAT32_LBA, 10, false).unwrap();
+		disk.add_primary_partition(os_types::FAT32_LBA, 10, false).unwrap();
+		assert_eq!(disk.add_logical_partition(os_types::FAT32_LBA, 10, false).err(), Some(MbrError::ExtendedNotContiguous));
+		
+		disk.add_primary_partition(os_types::FAT32_LBA, 10, false).unwrap();
+		assert_eq!(disk.add_primary_partition(os_types::FAT32_LBA, 10, false).err(), Some(MbrError::TooManyPrimaryPartitions));
+		
+		assert_eq!(disk.min_disk_size_lba(), 42);
+		assert_eq!(disk.resize_disk(41), Err(MbrError::DiskTooSmall {min_size_lba: 42}));
+		disk.resize_disk(42).unwrap();
+	}
+	
+	#[test]
+	pub fn boot_record_bytes_round_trip() {
+		let mut disk = MbrDisk::new_empty(512, 100_000, Some(0x12345678));
+		disk.set_bootstrap_code(&[0xEB, 0xFE]).unwrap();
+		disk.add_primary_partition(os_types::UEFI_SYSTEM, 4096, true).unwrap();
+		let mbr = disk.master_boot_record();
+		
+		let raw = mbr.to_bytes();
+		assert_eq!(&raw[..2], &[0xEB, 0xFE]);
+		assert_eq!(&raw[440..444], &[0x78, 0x56, 0x34, 0x12]);
+		assert_eq!(&raw[446..462], &[0x80, 0x20, 0x21, 0x00, 0xEF, 0x61, 0x21, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00]);
+		assert_eq!(&raw[462..510], &[0; 48][..]);
+		assert_eq!(&raw[510..], &[0x55, 0xAA]);
+		
+		assert_eq!(MasterBootRecord::from_bytes(&raw), mbr);
+		assert_eq!(MasterBootRecord::read_from(&mut &raw[..]).unwrap(), mbr);
+		assert_eq!(MbrPartitionEntry::from_bytes(&mbr.partitions[0].to_bytes()), mbr.partitions[0]);
+	}
+}
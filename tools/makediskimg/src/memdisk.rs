@@ -1,46 +1,177 @@
 use std::cmp;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::error;
 use std::fmt;
 use std::io::{self, SeekFrom};
 
+/// Size of the pages of a sparse mem disk
+pub const PAGE_SIZE: usize = 4096;
+
+/// An in-memory disk, for building volumes and as a block device in tests.
+///
+/// Fixed size disks fail writes at the end with [`io::ErrorKind::StorageFull`],
+/// growable ones extend instead (like a file). Sparse disks only allocate the pages that were written to.
 pub struct MemDisk {
-	data: Vec<u8>,
-	cursor: usize,
+	storage: Storage,
+	size: u64,
+	growable: bool,
+	cursor: u64,
+}
+
+enum Storage {
+	Contiguous(Vec<u8>),
+	/// Allocated pages by index, the rest reads as zero
+	Sparse(BTreeMap<u64, Box<[u8; PAGE_SIZE]>>),
 }
 
 impl MemDisk {
 	pub fn new_fixed_size(size: usize) -> Self {
 		// Allocate buffer
-		let mut data = Vec::with_capacity(size);
-		data.resize(data.capacity(), 0);
+		let data = vec![0; size];
 		
-		// Make object
+		Self::from_vec(data)
+	}
+	
+	/// An empty disk growing with writes past its end.
+	pub fn new_growable() -> Self {
+		MemDisk {
+			storage: Storage::Contiguous(Vec::new()),
+			size: 0,
+			growable: true,
+			cursor: 0,
+		}
+	}
+	
+	/// A fixed size disk only allocating the pages written to, for large volumes with little content.
+	pub fn new_sparse(size: u64) -> Self {
 		MemDisk {
-			data,
+			storage: Storage::Sparse(BTreeMap::new()),
+			size,
+			growable: false,
 			cursor: 0,
 		}
 	}
 	
-	pub fn size(&self) -> usize {
-		self.data.len()
+	/// A fixed size disk with the given contents.
+	pub fn from_vec(data: Vec<u8>) -> Self {
+		MemDisk {
+			size: data.len() as u64,
+			storage: Storage::Contiguous(data),
+			growable: false,
+			cursor: 0,
+		}
+	}
+	
+	/// The contents of the disk, unallocated pages of sparse disks are filled in with zeroes.
+	pub fn into_inner(self) -> Vec<u8> {
+		match self.storage {
+			Storage::Contiguous(data) => data,
+			Storage::Sparse(pages) => {
+				let mut data = vec![0; self.size as usize];
+				for (index, page) in pages.iter() {
+					let start = *index as usize * PAGE_SIZE;
+					let len = cmp::min(PAGE_SIZE, data.len() - start);
+					data[start..start + len].copy_from_slice(&page[..len]);
+				}
+				data
+			}
+		}
+	}
+	
+	pub fn size(&self) -> u64 {
+		self.size
 	}
 	
-	pub fn cursor(&self) -> usize {
+	pub fn cursor(&self) -> u64 {
 		self.cursor
 	}
+	
+	pub fn is_growable(&self) -> bool {
+		self.growable
+	}
+	
+	/// Bytes of memory holding the contents, less than the size for sparse disks.
+	pub fn allocated_bytes(&self) -> u64 {
+		match &self.storage {
+			Storage::Contiguous(data) => data.len() as u64,
+			Storage::Sparse(pages) => (pages.len() * PAGE_SIZE) as u64,
+		}
+	}
+	
+	/// Copies from the disk at `offset`, which has to be within the disk.
+	fn read_at(&self, offset: u64, buf: &mut [u8]) {
+		match &self.storage {
+			Storage::Contiguous(data) => {
+				let start = offset as usize;
+				buf.copy_from_slice(&data[start..start + buf.len()]);
+			}
+			Storage::Sparse(pages) => {
+				let mut done = 0;
+				while done < buf.len() {
+					let pos = offset + done as u64;
+					let (index, page_offset) = (pos / PAGE_SIZE as u64, (pos % PAGE_SIZE as u64) as usize);
+					let len = cmp::min(buf.len() - done, PAGE_SIZE - page_offset);
+					
+					match pages.get(&index) {
+						Some(page) => buf[done..done + len].copy_from_slice(&page[page_offset..page_offset + len]),
+						None => buf[done..done + len].fill(0),
+					}
+					done += len;
+				}
+			}
+		}
+	}
+	
+	/// Copies to the disk at `offset`, the disk has to be big enough.
+	fn write_at(&mut self, offset: u64, buf: &[u8]) {
+		match &mut self.storage {
+			Storage::Contiguous(data) => {
+				let start = offset as usize;
+				data[start..start + buf.len()].copy_from_slice(buf);
+			}
+			Storage::Sparse(pages) => {
+				let mut done = 0;
+				while done < buf.len() {
+					let pos = offset + done as u64;
+					let (index, page_offset) = (pos / PAGE_SIZE as u64, (pos % PAGE_SIZE as u64) as usize);
+					let len = cmp::min(buf.len() - done, PAGE_SIZE - page_offset);
+					
+					// Writing zeroes to a missing page changes nothing
+					let chunk = &buf[done..done + len];
+					if let Some(page) = pages.get_mut(&index) {
+						page[page_offset..page_offset + len].copy_from_slice(chunk);
+					} else if chunk.iter().any(|&b| b != 0) {
+						let mut page = Box::new([0; PAGE_SIZE]);
+						page[page_offset..page_offset + len].copy_from_slice(chunk);
+						pages.insert(index, page);
+					}
+					done += len;
+				}
+			}
+		}
+	}
+	
+	/// Grows the disk to at least `size` bytes.
+	fn grow(&mut self, size: u64) -> Result<(), MemDiskError> {
+		if let Storage::Contiguous(data) = &mut self.storage {
+			let size = usize::try_from(size).map_err(|_| MemDiskError::StorageFull)?;
+			data.resize(size, 0);
+		}
+		self.size = cmp::max(self.size, size);
+		Ok(())
+	}
 }
 
 impl io::Read for MemDisk {
 	fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-		let real_read_size = cmp::min(buf.len(), self.data.len() - self.cursor);
+		let real_read_size = cmp::min(buf.len() as u64, self.size.saturating_sub(self.cursor)) as usize;
 		
 		// Copy data into buffer
-		unsafe {
-			std::ptr::copy_nonoverlapping(self.data.as_ptr().offset(self.cursor as isize), buf.as_mut_ptr(), real_read_size);
-		}
+		self.read_at(self.cursor, &mut buf[..real_read_size]);
 		
 		// Advance cursor
-		self.cursor += real_read_size;
+		self.cursor += real_read_size as u64;
 		
 		Ok(real_read_size)
 	}
@@ -48,15 +179,26 @@ impl io::Read for MemDisk {
 
 impl io::Write for MemDisk {
 	fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
-		let real_write_size = cmp::min(buf.len(), self.data.len() - self.cursor);
+		if buf.is_empty() {
+			return Ok(0);
+		}
 		
-		// Copy data into our buffer
-		unsafe {
-			std::ptr::copy_nonoverlapping(buf.as_ptr(), self.data.as_mut_ptr().offset(self.cursor as isize), real_write_size);
+		let end = self.cursor.checked_add(buf.len() as u64).ok_or(MemDiskError::StorageFull)?;
+		if self.growable && end > self.size {
+			self.grow(end)?;
+		}
+		
+		// Writes crossing the end are cut short, ones at the end fail
+		let real_write_size = cmp::min(buf.len() as u64, self.size.saturating_sub(self.cursor)) as usize;
+		if real_write_size == 0 {
+			return Err(MemDiskError::StorageFull.into());
 		}
 		
+		// Copy data into our buffer
+		self.write_at(self.cursor, &buf[..real_write_size]);
+		
 		// Advance cursor
-		self.cursor += real_write_size;
+		self.cursor += real_write_size as u64;
 		
 		Ok(real_write_size)
 	}
@@ -70,45 +212,139 @@ impl io::Write for MemDisk {
 impl io::Seek for MemDisk {
 	fn seek(&mut self, pos: SeekFrom) -> Result<u64, io::Error> {
 		// Calc new cursor pos
-		let mut new_cursor_pos = match pos {
-			SeekFrom::Start(n) => n as usize,
-			SeekFrom::Current(n) => {
-				if n < 0 && (-n as usize) > self.cursor {
-					return Err(io::Error::new(io::ErrorKind::InvalidInput, SimpleError));
-				}
-				(self.cursor as i64 + n) as usize
-			}
-			SeekFrom::End(n) => {
-				if n < 0 && (-n as usize) > self.data.len() {
-					return Err(io::Error::new(io::ErrorKind::InvalidInput, SimpleError));
-				}
-				(self.data.len() as i64 + n) as usize
-			}
-		};
+		let new_cursor_pos = match pos {
+			SeekFrom::Start(n) => Some(n),
+			SeekFrom::Current(n) => self.cursor.checked_add_signed(n),
+			SeekFrom::End(n) => self.size.checked_add_signed(n),
+		}.ok_or(MemDiskError::SeekBeforeStart)?;
 		
-		// Clamp cursor
-		if new_cursor_pos > self.data.len() {
-			new_cursor_pos = self.data.len();
+		// Only growable disks can be positioned past their end, like files
+		if new_cursor_pos > self.size && !self.growable {
+			return Err(MemDiskError::SeekPastEnd {pos: new_cursor_pos, size: self.size}.into());
 		}
 		
 		// Update cursor
 		self.cursor = new_cursor_pos;
 		
-		Ok(self.cursor as u64)
+		Ok(self.cursor)
 	}
 }
 
-#[derive(Debug)]
-pub struct SimpleError;
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemDiskError {
+	/// Write at the end of a fixed size disk
+	StorageFull,
+	SeekBeforeStart,
+	SeekPastEnd {pos: u64, size: u64},
+}
 
-impl fmt::Display for SimpleError {
+impl fmt::Display for MemDiskError {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "Some error")
+		match self {
+			MemDiskError::StorageFull => write!(f, "mem disk is full"),
+			MemDiskError::SeekBeforeStart => write!(f, "seek before the start of the mem disk"),
+			MemDiskError::SeekPastEnd {pos, size} => write!(f, "seek to {} past the end of the mem disk ({} bytes)", pos, size),
+		}
+	}
+}
+
+impl error::Error for MemDiskError {}
+
+impl From<MemDiskError> for io::Error {
+	fn from(e: MemDiskError) -> Self {
+		let kind = match e {
+			MemDiskError::StorageFull => io::ErrorKind::StorageFull,
+			MemDiskError::SeekBeforeStart | MemDiskError::SeekPastEnd {..} => io::ErrorKind::InvalidInput,
+		};
+		io::Error::new(kind, e)
 	}
 }
 
-impl error::Error for SimpleError {
-	fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-		None
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use std::io::{Read, Seek, Write};
+	
+	#[test]
+	pub fn fixed_size_errors() {
+		let mut disk = MemDisk::new_fixed_size(1000);
+		
+		// Cut short at the end, then full
+		disk.seek(SeekFrom::Start(990)).unwrap();
+		assert_eq!(disk.write(&[1; 20]).unwrap(), 10);
+		let err = disk.write(&[1; 20]).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+		
+		let err = disk.seek(SeekFrom::Start(1001)).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		let err = disk.seek(SeekFrom::Current(-1001)).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+		
+		// Failed seeks don't move the cursor
+		assert_eq!(disk.cursor(), 1000);
+		assert_eq!(disk.read(&mut [0; 10]).unwrap(), 0);
+		
+		let data = disk.into_inner();
+		assert_eq!(data.len(), 1000);
+		assert_eq!(&data[985..], &[0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1]);
+	}
+	
+	#[test]
+	pub fn growable() {
+		let mut disk = MemDisk::new_growable();
+		disk.write_all(b"hello").unwrap();
+		
+		// Seeking past the end leaves a zeroed gap once written
+		disk.seek(SeekFrom::End(3)).unwrap();
+		disk.write_all(b"world").unwrap();
+		assert_eq!(disk.size(), 13);
+		
+		disk.seek(SeekFrom::Start(0)).unwrap();
+		let mut data = Vec::new();
+		disk.read_to_end(&mut data).unwrap();
+		assert_eq!(data, b"hello\0\0\0world");
+		assert_eq!(disk.into_inner(), data);
+	}
+	
+	#[test]
+	pub fn sparse() {
+		let size = 1 << 40;
+		let mut disk = MemDisk::new_sparse(size);
+		
+		// Crossing a page boundary
+		disk.seek(SeekFrom::Start(3 * PAGE_SIZE as u64 - 2)).unwrap();
+		disk.write_all(&[7; 4]).unwrap();
+		disk.seek(SeekFrom::End(-1)).unwrap();
+		disk.write_all(&[9]).unwrap();
+		
+		// Zeroes don't allocate
+		disk.seek(SeekFrom::Start(0)).unwrap();
+		disk.write_all(&[0; 2 * PAGE_SIZE]).unwrap();
+		assert_eq!(disk.allocated_bytes(), 3 * PAGE_SIZE as u64);
+		
+		let mut buf = [0xFF; 8];
+		disk.seek(SeekFrom::Start(3 * PAGE_SIZE as u64 - 4)).unwrap();
+		disk.read_exact(&mut buf).unwrap();
+		assert_eq!(buf, [0, 0, 7, 7, 7, 7, 0, 0]);
+		
+		disk.seek(SeekFrom::End(-2)).unwrap();
+		let mut tail = Vec::new();
+		disk.read_to_end(&mut tail).unwrap();
+		assert_eq!(tail, [0, 9]);
+		
+		assert_eq!(disk.write(&[1]).unwrap_err().kind(), io::ErrorKind::StorageFull);
+		assert_eq!(disk.size(), size);
+	}
+	
+	#[test]
+	pub fn sparse_into_inner() {
+		let mut disk = MemDisk::new_sparse(PAGE_SIZE as u64 + 10);
+		disk.seek(SeekFrom::Start(PAGE_SIZE as u64 + 5)).unwrap();
+		disk.write_all(&[1; 5]).unwrap();
+		
+		let mut expected = vec![0; PAGE_SIZE + 10];
+		expected[PAGE_SIZE + 5..].fill(1);
+		assert_eq!(disk.into_inner(), expected);
 	}
 }
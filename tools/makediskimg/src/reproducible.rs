@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::fat::{self, ImportEntry};
+use crate::guid::Guid;
+
+/// Namespace of the seeds, each seed names the namespace of the ids it derives.
+const SEED_NAMESPACE: Guid = Guid::from_u128(0xA5A1DFD4_20A4_40C6_B535_7ACA11D4F857);
+
+/// Fatfs wants static time providers, each timestamp's is leaked once and reused
+static TIME_PROVIDERS: Mutex<BTreeMap<SystemTime, &'static FixedTime>> = Mutex::new(BTreeMap::new());
+
+/// Settings of a reproducible build, where the same inputs always give a byte-identical image.
+///
+/// Guids, the mbr disk signature and fat volume serials are derived from the seed and the names of what they identify,
+/// all fat timestamps are set to one fixed time.
+#[derive(Clone, Debug)]
+pub struct Reproducible {
+	namespace: Guid,
+	timestamp: SystemTime,
+	time_provider: &'static FixedTime,
+}
+
+impl Reproducible {
+	/// Timestamps before 1980 (e.g. `UNIX_EPOCH`) end up as 1980-01-01, the earliest time fat can store.
+	pub fn new(seed: &str, timestamp: SystemTime) -> Reproducible {
+		Reproducible {
+			namespace: Guid::new_v5(&SEED_NAMESPACE, seed.as_bytes()),
+			timestamp,
+			time_provider: time_provider(timestamp),
+		}
+	}
+	
+	pub fn disk_guid(&self) -> Guid {
+		self.guid("disk")
+	}
+	
+	pub fn mbr_disk_signature(&self) -> u32 {
+		self.guid("mbr").first_u32()
+	}
+	
+	/// Unique guid of a partition. `repetition` tells apart partitions sharing a name, 0 for the first one.
+	pub fn partition_guid(&self, name: &str, repetition: usize) -> Guid {
+		self.guid(&format!("partition/{}", partition_key(name, repetition)))
+	}
+	
+	/// Serial of the fat volume in a partition, see [`Reproducible::partition_guid`].
+	pub fn volume_id(&self, name: &str, repetition: usize) -> u32 {
+		self.guid(&format!("volume/{}", partition_key(name, repetition))).first_u32()
+	}
+	
+	/// Gives all imported files the fixed timestamp instead of their host times.
+	pub fn fix_timestamps(&self, imports: &mut [ImportEntry]) {
+		for entry in imports.iter_mut() {
+			if let ImportEntry::File {created, modified, ..} = entry {
+				*created = Some(self.timestamp);
+				*modified = Some(self.timestamp);
+			}
+		}
+	}
+	
+	/// Time provider for fatfs, which stamps the directories it creates.
+	pub fn time_provider(&self) -> &'static dyn fatfs::TimeProvider {
+		self.time_provider
+	}
+	
+	fn guid(&self, name: &str) -> Guid {
+		Guid::new_v5(&self.namespace, name.as_bytes())
+	}
+}
+
+fn time_provider(timestamp: SystemTime) -> &'static FixedTime {
+	let mut providers = TIME_PROVIDERS.lock().unwrap_or_else(|e| e.into_inner());
+	providers.entry(timestamp)
+		.or_insert_with(|| Box::leak(Box::new(FixedTime(fat::fat_date_time(timestamp)))))
+}
+
+fn partition_key(name: &str, repetition: usize) -> String {
+	match repetition {
+		0 => name.to_owned(),
+		n => format!("{}#{}", name, n),
+	}
+}
+
+#[derive(Debug)]
+struct FixedTime(fatfs::DateTime);
+
+impl fatfs::TimeProvider for FixedTime {
+	fn get_current_date(&self) -> fatfs::Date {
+		self.0.date
+	}
+	
+	fn get_current_date_time(&self) -> fatfs::DateTime {
+		self.0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use std::time::{Duration, UNIX_EPOCH};
+	
+	#[test]
+	pub fn derived_ids() {
+		let a = Reproducible::new("seed", UNIX_EPOCH);
+		let b = Reproducible::new("seed", UNIX_EPOCH);
+		let other = Reproducible::new("other seed", UNIX_EPOCH);
+		
+		assert_eq!(a.disk_guid(), b.disk_guid());
+		assert_eq!(a.partition_guid("ESP", 0), b.partition_guid("ESP", 0));
+		assert_eq!(a.volume_id("ESP", 0), b.volume_id("ESP", 0));
+		assert_eq!(a.mbr_disk_signature(), b.mbr_disk_signature());
+		
+		assert_ne!(a.disk_guid(), other.disk_guid());
+		assert_ne!(a.partition_guid("ESP", 0), a.partition_guid("Nell Boot", 0));
+		assert_ne!(a.partition_guid("ESP", 0), a.partition_guid("ESP", 1));
+		assert_ne!(a.volume_id("ESP", 0), a.volume_id("Nell Boot", 0));
+		
+		// One time provider per timestamp
+		assert!(std::ptr::eq(a.time_provider, other.time_provider));
+		assert!(!std::ptr::eq(a.time_provider, Reproducible::new("seed", UNIX_EPOCH + Duration::from_secs(1)).time_provider));
+	}
+}
@@ -0,0 +1,239 @@
+use std::{cmp, io};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::blockdev::read_up_to;
+
+/// Granularity of the holes, the page size of most filesystems
+pub const SPARSE_BLOCK_SIZE: u64 = 4096;
+
+/// Default size of the write buffer
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A file that leaves holes instead of writing all-zero blocks, with a write buffer in front.
+///
+/// Zero blocks are only skipped if the file already reads as zero there,
+/// so overwriting existing content with zeroes still works.
+/// Buffered writes are flushed by [`Write::flush`] (and, ignoring errors, on drop).
+pub struct SparseFile {
+	file: File,
+	buffer: Vec<u8>,
+	buffer_size: usize,
+	/// File offset of the first buffered byte
+	buffer_start: u64,
+	/// Position of the next read or write
+	cursor: u64,
+	/// Length of the file including buffered writes
+	len: u64,
+}
+
+impl SparseFile {
+	pub fn new(file: File, buffer_size: usize) -> io::Result<Self> {
+		let len = file.metadata()?.len();
+		
+		Ok(SparseFile {
+			file,
+			buffer: Vec::with_capacity(buffer_size),
+			buffer_size: cmp::max(buffer_size, 1),
+			buffer_start: 0,
+			cursor: 0,
+			len,
+		})
+	}
+	
+	pub fn get_ref(&self) -> &File {
+		&self.file
+	}
+	
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+	
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+	
+	/// Truncates or extends the file, new space is a hole.
+	pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+		self.flush_buffer()?;
+		self.file.set_len(len)?;
+		self.len = len;
+		Ok(())
+	}
+	
+	/// Writes out the buffer, skipping zero blocks that are already zero in the file.
+	fn flush_buffer(&mut self) -> io::Result<()> {
+		let mut offset = self.buffer_start;
+		let mut rest = &self.buffer[..];
+		let mut file_cursor = None;
+		let mut existing = [0u8; SPARSE_BLOCK_SIZE as usize];
+		
+		while !rest.is_empty() {
+			// Chunks end at block boundaries of the file
+			let chunk_len = cmp::min(rest.len() as u64, SPARSE_BLOCK_SIZE - offset % SPARSE_BLOCK_SIZE) as usize;
+			let (chunk, next) = rest.split_at(chunk_len);
+			
+			let skip = chunk.iter().all(|&b| b == 0) && {
+				self.file.seek(SeekFrom::Start(offset))?;
+				let existing = &mut existing[..chunk_len];
+				let read = read_up_to(&mut self.file, existing)?;
+				file_cursor = None;
+				existing[..read].iter().all(|&b| b == 0)
+			};
+			
+			if !skip {
+				if file_cursor != Some(offset) {
+					self.file.seek(SeekFrom::Start(offset))?;
+				}
+				self.file.write_all(chunk)?;
+				file_cursor = Some(offset + chunk_len as u64);
+			}
+			
+			offset += chunk_len as u64;
+			rest = next;
+		}
+		
+		self.buffer.clear();
+		
+		// Skipped zeroes at the end still have to extend the file
+		if self.file.metadata()?.len() < self.len {
+			self.file.set_len(self.len)?;
+		}
+		Ok(())
+	}
+}
+
+impl Read for SparseFile {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		self.flush_buffer()?;
+		
+		self.file.seek(SeekFrom::Start(self.cursor))?;
+		let bytes_read = self.file.read(buf)?;
+		self.cursor += bytes_read as u64;
+		
+		Ok(bytes_read)
+	}
+}
+
+impl Write for SparseFile {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		// Only contiguous writes are buffered
+		let buffer_end = self.buffer_start + self.buffer.len() as u64;
+		if !self.buffer.is_empty() && (self.cursor != buffer_end || self.buffer.len() == self.buffer_size) {
+			self.flush_buffer()?;
+		}
+		if self.buffer.is_empty() {
+			self.buffer_start = self.cursor;
+		}
+		
+		let write_size = cmp::min(buf.len(), self.buffer_size - self.buffer.len());
+		self.buffer.extend_from_slice(&buf[..write_size]);
+		
+		self.cursor += write_size as u64;
+		self.len = cmp::max(self.len, self.cursor);
+		
+		Ok(write_size)
+	}
+	
+	fn flush(&mut self) -> io::Result<()> {
+		self.flush_buffer()?;
+		self.file.flush()
+	}
+}
+
+impl Seek for SparseFile {
+	fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+		let new_cursor = match pos {
+			SeekFrom::Start(n) => Some(n),
+			SeekFrom::Current(n) => self.cursor.checked_add_signed(n),
+			SeekFrom::End(n) => self.len.checked_add_signed(n),
+		};
+		
+		self.cursor = new_cursor
+			.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))?;
+		Ok(self.cursor)
+	}
+}
+
+impl Drop for SparseFile {
+	fn drop(&mut self) {
+		let _ = self.flush_buffer();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	use std::fs::{self, OpenOptions};
+	use std::path::PathBuf;
+	
+	use crate::test_util::temp_dir;
+	
+	fn temp_file(name: &str) -> (PathBuf, File) {
+		let path = temp_dir(name).join("image");
+		let file = OpenOptions::new()
+			.create(true).write(true).read(true).truncate(true)
+			.open(&path)
+			.unwrap();
+		(path, file)
+	}
+	
+	#[test]
+	pub fn contents_round_trip() {
+		let (path, file) = temp_file("sparse_round_trip");
+		let mut sparse = SparseFile::new(file, 10_000).unwrap();
+		
+		// Unaligned, partly zero writes crossing block and buffer boundaries
+		let mut expected = vec![0u8; 64 * 1024];
+		for (i, b) in expected.iter_mut().enumerate().skip(3000).take(20_000) {
+			*b = if i / 5000 % 2 == 0 {(i % 251) as u8} else {0};
+		}
+		sparse.seek(SeekFrom::Start(3000)).unwrap();
+		for chunk in expected[3000..23_000].chunks(777) {
+			sparse.write_all(chunk).unwrap();
+		}
+		
+		// Zeroes have to overwrite existing data
+		sparse.seek(SeekFrom::Start(3500)).unwrap();
+		sparse.write_all(&[0; 100]).unwrap();
+		expected[3500..3600].fill(0);
+		
+		// Reads see buffered writes
+		sparse.seek(SeekFrom::Start(3000)).unwrap();
+		let mut start = [0u8; 600];
+		sparse.read_exact(&mut start).unwrap();
+		assert_eq!(&start[..], &expected[3000..3600]);
+		
+		// Trailing zeroes extend the file
+		sparse.seek(SeekFrom::End(0)).unwrap();
+		sparse.write_all(&expected[23_000..]).unwrap();
+		assert_eq!(sparse.len(), expected.len() as u64);
+		drop(sparse);
+		
+		assert_eq!(fs::read(&path).unwrap(), expected);
+		fs::remove_file(&path).unwrap();
+	}
+	
+	#[cfg(unix)]
+	#[test]
+	pub fn leaves_holes() {
+		use std::os::unix::fs::MetadataExt;
+		
+		let (path, file) = temp_file("sparse_holes");
+		let mut sparse = SparseFile::new(file, DEFAULT_BUFFER_SIZE).unwrap();
+		
+		sparse.write_all(&[0xAB; 512]).unwrap();
+		sparse.write_all(&vec![0; 16 * 1024 * 1024]).unwrap();
+		sparse.write_all(&[0xCD; 512]).unwrap();
+		sparse.flush().unwrap();
+		
+		// Blocks are 512 bytes, leave room for filesystems allocating more than a page
+		let metadata = fs::metadata(&path).unwrap();
+		assert_eq!(metadata.len(), 16 * 1024 * 1024 + 1024);
+		assert!(metadata.blocks() * 512 <= 1024 * 1024, "{} blocks allocated", metadata.blocks());
+		
+		drop(sparse);
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,66 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use fatfs::FsOptions;
+
+use crate::blockdev::{BlockIo, IoDevice, PartitionDevice};
+use crate::fat;
+use crate::gpt::{CreatePartitionOptions, GptDisk, GptPartition};
+use crate::memdisk::MemDisk;
+
+/// Blocks of the disks made by [`gpt_image`], 512 bytes each.
+pub const TEST_DISK_SIZE_LBA: u64 = 16384;
+
+/// An empty directory for the files of a test, unique to the test process.
+pub fn temp_dir(name: &str) -> PathBuf {
+	let dir = std::env::temp_dir().join(format!("makediskimg_test_{}_{}", std::process::id(), name));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	dir
+}
+
+/// Creates an in-memory device big enough for the disk.
+pub fn mem_image(disk: &GptDisk) -> IoDevice<MemDisk> {
+	let size_bytes = disk.disk_size_lba() * disk.block_size() as u64;
+	IoDevice::new(MemDisk::new_sparse(size_bytes), disk.block_size()).unwrap()
+}
+
+/// Writes the protective mbr and both gpt headers of the disk to a new in-memory image.
+pub fn write_gpt(disk: &GptDisk) -> MemDisk {
+	let mut writer = disk.writer(mem_image(disk));
+	writer.write_protective_mbr().unwrap();
+	writer.write_gpt_header(true).unwrap();
+	writer.write_gpt_header(false).unwrap();
+	writer.flush().unwrap().into_inner()
+}
+
+/// A gpt disk of [`TEST_DISK_SIZE_LBA`] blocks with the given partitions, and its image.
+/// The partitions are left zeroed, see [`format_fat`].
+pub fn gpt_image(partitions: Vec<CreatePartitionOptions>) -> (GptDisk, MemDisk) {
+	let mut disk = GptDisk::new_empty(512, TEST_DISK_SIZE_LBA, None).unwrap();
+	for options in partitions {
+		disk.create_partition(options).unwrap();
+	}
+	let image = write_gpt(&disk);
+	(disk, image)
+}
+
+/// Formats a partition of a [`gpt_image`] and writes the given files, with their parent directories, to it.
+pub fn format_fat(image: &mut MemDisk, partition: &GptPartition, files: &[(&str, &[u8])]) {
+	let mut device = IoDevice::new(image, 512).unwrap();
+	let mut storage = BlockIo::new(PartitionDevice::new(&mut device, partition.start_lba, partition.size_in_lba).unwrap());
+	fat::format_volume(&mut storage, partition.size_in_lba * 512, 512, 0).unwrap();
+	{
+		let vfs = fatfs::FileSystem::new(&mut storage, FsOptions::new()).unwrap();
+		for (path, content) in files.iter() {
+			let segs = path.split('/').collect::<Vec<_>>();
+			for end in 1..segs.len() {
+				vfs.root_dir().create_dir(&segs[..end].join("/")).unwrap();
+			}
+			vfs.root_dir().create_file(path).unwrap().write_all(content).unwrap();
+		}
+		vfs.unmount().unwrap();
+	}
+	storage.flush().unwrap();
+}